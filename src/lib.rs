@@ -1,8 +1,9 @@
 //! A crate for controlling NetMD and Hi-MD devices.
 //!
-//! This crate is entirely `async` (a necessity because of USB in WASM), but
-//! it can be used in programs which are not async by using a crate like
-//! [futures_lite](https://docs.rs/futures-lite/) with the `block_on` function.
+//! This crate is `async` (a necessity because of USB in WASM), but programs
+//! which are not async can enable the `blocking` feature and use
+//! [`netmd::blocking::BlockingNetMDContext`] instead, which runs its own
+//! executor.
 //!
 //! To use this library, first you need to get a device from [`cross_usb`] and
 //! then open a [`netmd::NetMDContext`].
@@ -10,11 +11,11 @@
 //! ```no_run
 //! # tokio_test::block_on(async {
 //! use cross_usb::get_device;
-//! use minidisc::netmd::base::DEVICE_IDS_CROSSUSB;
+//! use minidisc::netmd::device_filters;
 //! use minidisc::netmd::NetMDContext;
 //!
-//! // Get a device using the built-in list of descriptors for minidisc devices
-//! let dev_descriptor = cross_usb::get_device(DEVICE_IDS_CROSSUSB.to_vec()).await
+//! // Get a device using the list of descriptors for known minidisc devices
+//! let dev_descriptor = cross_usb::get_device(device_filters()).await
 //!     .expect("Failed to find device");
 //!
 //! // Open a NetMD Context with the device
@@ -26,5 +27,29 @@
 //!     .expect("Could not list disc contents");
 //! # })
 //! ```
+//!
+//! When more than one device may be connected, use
+//! [`netmd::discovery::list_devices`] to choose which one to open.
+//!
+//! # Features
+//!
+//! - `native-usb` (default): open USB devices with [`cross_usb`], which uses
+//!   WebUSB on WASM. Without it, devices are reached through your own
+//!   [`netmd::Transport`]
+//! - `fs` (default): read and write captures and device configs as files
+//! - `threaded-encryption` (default): encrypt tracks on their own thread while
+//!   they are downloaded
+//! - `wasm`: the browser timers, which must be enabled when building for
+//!   WASM
+//! - `blocking`: a synchronous API, see [`netmd::blocking`]
+//! - `serde`: serialize the disc contents, device status and player events
+//! - `cli`: the command line tool
+//!
+//! The features which only work on native targets are ignored when building
+//! for WASM. Only `tokio::sync` is used by default, so no tokio runtime is pulled in
+//! unless `blocking` or `cli` is enabled.
+
+#[cfg(all(target_family = "wasm", not(feature = "wasm")))]
+compile_error!("the `wasm` feature must be enabled to build for WASM");
 
 pub mod netmd;
@@ -2,7 +2,7 @@ use std::{process::exit, time::Duration};
 
 #[tokio::main]
 async fn main() {
-    let Ok(player) = cross_usb::get_device(minidisc::netmd::DEVICE_IDS_CROSSUSB.to_vec()).await else {
+    let Ok(player) = cross_usb::get_device(minidisc::netmd::device_filters()).await else {
         eprintln!("Could not find a MiniDisc device");
         exit(1);
     };
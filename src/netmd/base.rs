@@ -1,74 +1,107 @@
 #![cfg_attr(debug_assertions, allow(dead_code))]
+use std::borrow::Cow;
 use std::time::Duration;
 
+#[cfg(feature = "native-usb")]
 use once_cell::sync::Lazy;
+
 use thiserror::Error;
 
-// USB stuff
-use cross_usb::prelude::*;
-use cross_usb::usb::{ControlIn, ControlOut, ControlType, Recipient, Error};
-use cross_usb::{DeviceInfo, Interface};
+#[cfg(feature = "native-usb")]
+use cross_usb::usb::Error;
 
+use super::protocol::{Machine, ReadReply, Request, Response, SendCommand, Step};
+use super::registry;
+use super::transport::Transport;
+#[cfg(feature = "native-usb")]
+use super::transport::UsbTransport;
 use super::utils::cross_sleep;
 
-const BULK_WRITE_ENDPOINT: u8 = 0x02;
-const BULK_READ_ENDPOINT: u8 = 0x81;
+/// Sharp players write disc titles differently, report capacity in the
+/// current recording mode and are slow to start downloads
+const SHARP_PORTABLE: Capabilities = Capabilities {
+    utoc1_disc_title: true,
+    mode_scaled_capacity: true,
+    ..Capabilities::PORTABLE
+};
+const SHARP_DECK: Capabilities = Capabilities {
+    utoc1_disc_title: true,
+    mode_scaled_capacity: true,
+    ..Capabilities::DECK
+};
+
+/// Panasonic players send a different byte in the disc capacity reply
+const PANASONIC_PORTABLE: Capabilities = Capabilities {
+    capacity_flag: 0x08,
+    ..Capabilities::PORTABLE
+};
 
 nofmt::pls! { // Skip formatting the following info
 /// Device IDs for use in matching existing devices
 pub static DEVICE_IDS: &[DeviceId] = &[
-    DeviceId { vendor_id: 0x04dd, product_id: 0x7202, name: Some("Sharp IM-MT899H") },
-    DeviceId { vendor_id: 0x04dd, product_id: 0x9013, name: Some("Sharp IM-DR400") },
-    DeviceId { vendor_id: 0x04dd, product_id: 0x9014, name: Some("Sharp IM-DR80") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0034, name: Some("Sony PCLK-XX") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0036, name: Some("Sony") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0075, name: Some("Sony MZ-N1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x007c, name: Some("Sony") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0080, name: Some("Sony LAM-1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0081, name: Some("Sony MDS-JB980/MDS-NT1/MDS-JE780") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0084, name: Some("Sony MZ-N505") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0085, name: Some("Sony MZ-S1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0086, name: Some("Sony MZ-N707") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x008e, name: Some("Sony CMT-C7NT") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0097, name: Some("Sony PCGA-MDN1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00ad, name: Some("Sony CMT-L7HD") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00c6, name: Some("Sony MZ-N10") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00c7, name: Some("Sony MZ-N910") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00c8, name: Some("Sony MZ-N710/NF810") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00c9, name: Some("Sony MZ-N510/N610") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00ca, name: Some("Sony MZ-NE410/NF520D") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00e7, name: Some("Sony CMT-M333NT/M373NT") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x00eb, name: Some("Sony MZ-NE810/NE910") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0101, name: Some("Sony LAM") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0113, name: Some("Aiwa AM-NX1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x013f, name: Some("Sony MDS-S500") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x014c, name: Some("Aiwa AM-NX9") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x017e, name: Some("Sony MZ-NH1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0180, name: Some("Sony MZ-NH3D") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0182, name: Some("Sony MZ-NH900") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0184, name: Some("Sony MZ-NH700/NH800") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0186, name: Some("Sony MZ-NH600") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0187, name: Some("Sony MZ-NH600D") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0188, name: Some("Sony MZ-N920") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x018a, name: Some("Sony LAM-3") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x01e9, name: Some("Sony MZ-DH10P") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0219, name: Some("Sony MZ-RH10") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x021b, name: Some("Sony MZ-RH710/MZ-RH910") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x021d, name: Some("Sony CMT-AH10") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x022c, name: Some("Sony CMT-AH10") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x023c, name: Some("Sony DS-HMD1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0286, name: Some("Sony MZ-RH1") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x011a, name: Some("Sony CMT-SE7") },
-    DeviceId { vendor_id: 0x054c, product_id: 0x0148, name: Some("Sony MDS-A1") },
-    DeviceId { vendor_id: 0x0b28, product_id: 0x1004, name: Some("Kenwood MDX-J9") },
-    DeviceId { vendor_id: 0x04da, product_id: 0x23b3, name: Some("Panasonic SJ-MR250") },
-    DeviceId { vendor_id: 0x04da, product_id: 0x23b6, name: Some("Panasonic SJ-MR270") },
+    DeviceId { vendor_id: 0x04dd, product_id: 0x7202, name: Some(Cow::Borrowed("Sharp IM-MT899H")), capabilities: SHARP_PORTABLE },
+    DeviceId { vendor_id: 0x04dd, product_id: 0x9013, name: Some(Cow::Borrowed("Sharp IM-DR400")), capabilities: SHARP_DECK },
+    DeviceId { vendor_id: 0x04dd, product_id: 0x9014, name: Some(Cow::Borrowed("Sharp IM-DR80")), capabilities: SHARP_DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0034, name: Some(Cow::Borrowed("Sony PCLK-XX")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0036, name: Some(Cow::Borrowed("Sony")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0075, name: Some(Cow::Borrowed("Sony MZ-N1")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x007c, name: Some(Cow::Borrowed("Sony")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0080, name: Some(Cow::Borrowed("Sony LAM-1")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0081, name: Some(Cow::Borrowed("Sony MDS-JB980/MDS-NT1/MDS-JE780")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0084, name: Some(Cow::Borrowed("Sony MZ-N505")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0085, name: Some(Cow::Borrowed("Sony MZ-S1")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0086, name: Some(Cow::Borrowed("Sony MZ-N707")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x008e, name: Some(Cow::Borrowed("Sony CMT-C7NT")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0097, name: Some(Cow::Borrowed("Sony PCGA-MDN1")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00ad, name: Some(Cow::Borrowed("Sony CMT-L7HD")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00c6, name: Some(Cow::Borrowed("Sony MZ-N10")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00c7, name: Some(Cow::Borrowed("Sony MZ-N910")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00c8, name: Some(Cow::Borrowed("Sony MZ-N710/NF810")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00c9, name: Some(Cow::Borrowed("Sony MZ-N510/N610")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00ca, name: Some(Cow::Borrowed("Sony MZ-NE410/NF520D")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00e7, name: Some(Cow::Borrowed("Sony CMT-M333NT/M373NT")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x00eb, name: Some(Cow::Borrowed("Sony MZ-NE810/NE910")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0101, name: Some(Cow::Borrowed("Sony LAM")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0113, name: Some(Cow::Borrowed("Aiwa AM-NX1")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x013f, name: Some(Cow::Borrowed("Sony MDS-S500")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x014c, name: Some(Cow::Borrowed("Aiwa AM-NX9")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x017e, name: Some(Cow::Borrowed("Sony MZ-NH1")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0180, name: Some(Cow::Borrowed("Sony MZ-NH3D")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0182, name: Some(Cow::Borrowed("Sony MZ-NH900")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0184, name: Some(Cow::Borrowed("Sony MZ-NH700/NH800")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0186, name: Some(Cow::Borrowed("Sony MZ-NH600")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0187, name: Some(Cow::Borrowed("Sony MZ-NH600D")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0188, name: Some(Cow::Borrowed("Sony MZ-N920")), capabilities: Capabilities::PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x018a, name: Some(Cow::Borrowed("Sony LAM-3")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x01e9, name: Some(Cow::Borrowed("Sony MZ-DH10P")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0219, name: Some(Cow::Borrowed("Sony MZ-RH10")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x021b, name: Some(Cow::Borrowed("Sony MZ-RH710/MZ-RH910")), capabilities: Capabilities::HIMD_PORTABLE },
+    DeviceId { vendor_id: 0x054c, product_id: 0x021d, name: Some(Cow::Borrowed("Sony CMT-AH10")), capabilities: Capabilities::HIMD_DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x022c, name: Some(Cow::Borrowed("Sony CMT-AH10")), capabilities: Capabilities::HIMD_DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x023c, name: Some(Cow::Borrowed("Sony DS-HMD1")), capabilities: Capabilities::HIMD_DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0286, name: Some(Cow::Borrowed("Sony MZ-RH1")), capabilities: Capabilities::RH1 },
+    DeviceId { vendor_id: 0x054c, product_id: 0x011a, name: Some(Cow::Borrowed("Sony CMT-SE7")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x054c, product_id: 0x0148, name: Some(Cow::Borrowed("Sony MDS-A1")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x0b28, product_id: 0x1004, name: Some(Cow::Borrowed("Kenwood MDX-J9")), capabilities: Capabilities::DECK },
+    DeviceId { vendor_id: 0x04da, product_id: 0x23b3, name: Some(Cow::Borrowed("Panasonic SJ-MR250")), capabilities: PANASONIC_PORTABLE },
+    DeviceId { vendor_id: 0x04da, product_id: 0x23b6, name: Some(Cow::Borrowed("Panasonic SJ-MR270")), capabilities: PANASONIC_PORTABLE },
 ];
 }
 
 /// Device IDs for use with [cross_usb]
-pub static DEVICE_IDS_CROSSUSB: Lazy<Box<[cross_usb::DeviceFilter]>> = Lazy::new(|| {
-    DEVICE_IDS
+///
+/// This includes [`DEVICE_IDS`] and the devices added with
+/// [`super::registry::register_device`] before it is first used.
+#[cfg(feature = "native-usb")]
+#[deprecated(note = "use `device_filters()`, which includes devices registered later")]
+pub static DEVICE_IDS_CROSSUSB: Lazy<Box<[cross_usb::DeviceFilter]>> =
+    Lazy::new(|| device_filters().into_boxed_slice());
+
+/// The [cross_usb] filters matching every known device, including the
+/// devices registered at runtime
+#[cfg(feature = "native-usb")]
+pub fn device_filters() -> Vec<cross_usb::DeviceFilter> {
+    known_devices()
         .iter()
         .map(|d| {
             cross_usb::device_filter! {
@@ -77,7 +110,39 @@ pub static DEVICE_IDS_CROSSUSB: Lazy<Box<[cross_usb::DeviceFilter]>> = Lazy::new
             }
         })
         .collect()
-});
+}
+
+/// Every known device, with devices registered at runtime first
+pub fn known_devices() -> Vec<DeviceId> {
+    let mut devices = registry::registered_devices();
+    let built_in: Vec<DeviceId> = DEVICE_IDS
+        .iter()
+        .filter(|d| {
+            !devices
+                .iter()
+                .any(|r| r.vendor_id == d.vendor_id && r.product_id == d.product_id)
+        })
+        .cloned()
+        .collect();
+    devices.extend(built_in);
+
+    devices
+}
+
+/// Look up a device, in the registry first and then in [`DEVICE_IDS`]
+fn find_device(vendor_id: u16, product_id: u16) -> Option<DeviceId> {
+    registry::find(vendor_id, product_id).or_else(|| {
+        DEVICE_IDS
+            .iter()
+            .find(|d| d.vendor_id == vendor_id && d.product_id == product_id)
+            .cloned()
+    })
+}
+
+/// Look up the name of a known device
+pub fn device_name(vendor_id: u16, product_id: u16) -> Option<Cow<'static, str>> {
+    find_device(vendor_id, product_id).and_then(|d| d.name)
+}
 
 /// The current status of the Minidisc device
 pub enum Status {
@@ -91,12 +156,144 @@ pub enum Status {
     DiscBlank,
 }
 
+/// What a model of device is capable of, and how it differs from others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Capabilities {
+    /// Tracks can be uploaded from the device to the host
+    pub upload: bool,
+
+    /// The device can eject a disc by itself
+    pub eject: bool,
+
+    /// The device supports Hi-MD discs. This is not used by the library,
+    /// and is only here for applications to check.
+    pub himd: bool,
+
+    /// The device is a deck or stereo system, rather than a portable. This
+    /// is not used by the library, and is only here for applications to
+    /// check.
+    pub deck: bool,
+
+    /// The disc title has to be written through the UTOC1 descriptor
+    pub utoc1_disc_title: bool,
+
+    /// The disc capacity is reported scaled to the current recording mode,
+    /// so a capacity longer than any disc is scaled back to SP
+    pub mode_scaled_capacity: bool,
+
+    /// The byte the device sends before the times in the disc capacity
+    /// reply, which is `0x80` for most devices. Its meaning is unknown, and
+    /// a reply with any other byte is refused.
+    pub capacity_flag: u8,
+
+    /// The time to wait after starting a download before sending data
+    pub download_delay: Duration,
+}
+
+impl Capabilities {
+    /// A portable NetMD player
+    pub const PORTABLE: Self = Self {
+        upload: false,
+        eject: false,
+        himd: false,
+        deck: false,
+        utoc1_disc_title: false,
+        mode_scaled_capacity: false,
+        capacity_flag: 0x80,
+        download_delay: Duration::from_millis(200),
+    };
+
+    /// A NetMD deck or stereo system
+    pub const DECK: Self = Self {
+        eject: true,
+        deck: true,
+        ..Self::PORTABLE
+    };
+
+    /// A portable Hi-MD player
+    pub const HIMD_PORTABLE: Self = Self {
+        himd: true,
+        ..Self::PORTABLE
+    };
+
+    /// A Hi-MD deck or stereo system
+    pub const HIMD_DECK: Self = Self {
+        himd: true,
+        ..Self::DECK
+    };
+
+    /// The MZ-RH1, the only player which can upload tracks
+    pub const RH1: Self = Self {
+        upload: true,
+        ..Self::HIMD_PORTABLE
+    };
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::PORTABLE
+    }
+}
+
 /// The ID of a device, including the name
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DeviceId {
     vendor_id: u16,
     product_id: u16,
-    name: Option<&'static str>,
+    name: Option<Cow<'static, str>>,
+    capabilities: Capabilities,
+}
+
+impl DeviceId {
+    /// Create the ID of a device which is not in [`DEVICE_IDS`], to add it
+    /// with [`super::registry::register_device`]
+    pub fn new<N: Into<Cow<'static, str>>>(
+        vendor_id: u16,
+        product_id: u16,
+        name: N,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            name: Some(name.into()),
+            capabilities,
+        }
+    }
+
+    /// Find the ID of a device, looking up its name and capabilities in
+    /// the known devices
+    pub fn lookup(vendor_id: u16, product_id: u16) -> Self {
+        match find_device(vendor_id, product_id) {
+            Some(device) => device,
+            None => Self {
+                vendor_id,
+                product_id,
+                name: None,
+                capabilities: Capabilities::default(),
+            },
+        }
+    }
+
+    /// Gets the vendor id
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    /// Gets the product id
+    pub fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    /// Gets the device name, this is limited to the known devices
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Gets the capabilities and quirks of the device
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
 }
 
 #[derive(Error, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -113,58 +310,128 @@ pub enum NetMDError {
     #[error("could not find device")]
     UnknownDevice(DeviceId),
 
+    #[cfg(feature = "native-usb")]
     #[error("usb connection error")]
     UsbError(#[from] Error),
+
+    #[error("the device was disconnected")]
+    Disconnected,
+
+    #[error("could not write capture: {0}")]
+    CaptureError(String),
+
+    #[error("replay does not match capture: {0}")]
+    ReplayMismatch(String),
+}
+
+/// How long to wait for a device before giving up.
+///
+/// Between attempts the delay is backed off exponentially, starting at zero,
+/// then `base_delay`, `3 * base_delay`, `7 * base_delay` and so on, limited to
+/// `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of times to poll the device for a reply
+    pub max_attempts: u32,
+
+    /// The unit of the back-off between polls
+    pub base_delay: Duration,
+
+    /// The longest time to wait between two polls
+    pub max_delay: Duration,
+
+    /// The longest total time to spend sleeping between polls for one reply.
+    ///
+    /// Only the sleeps are counted, not the time the polls themselves take,
+    /// so this is not a deadline on the wall clock. Timers can't be read
+    /// the same way on every platform, so a limit on the wall clock is left
+    /// to the caller.
+    pub max_total_delay: Option<Duration>,
+
+    /// The maximum number of times to read a reply while the device only
+    /// replies with an interim status
+    pub max_interim_attempts: u32,
+
+    /// The unit of the back-off after an interim reply
+    pub interim_base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 39,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            max_total_delay: None,
+            max_interim_attempts: 4,
+            interim_base_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The time to wait after failed poll number `attempt`, starting at 0
+    pub fn poll_delay(&self, attempt: u32) -> Duration {
+        Self::backoff(self.base_delay, attempt).min(self.max_delay)
+    }
+
+    /// The time to wait after interim reply number `attempt`, starting at 0
+    pub fn interim_delay(&self, attempt: u32) -> Duration {
+        Self::backoff(self.interim_base_delay, attempt).min(self.max_delay)
+    }
+
+    fn backoff(unit: Duration, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt)
+            .unwrap_or(u32::MAX)
+            .saturating_sub(1);
+
+        unit.saturating_mul(factor)
+    }
 }
 
-/// A low-level USB connection to a NetMD device.
+/// A low-level connection to a NetMD device.
 ///
 /// With this you can send raw commands to the device and recieve raw data.
+/// The connection is carried over a [`Transport`], which is a real USB
+/// device by default.
 ///
 /// For simple communication with a NetMD device, you most likely want the
 /// higher level [`super::NetMDInterface`] or [`super::NetMDContext`] interfaces
-pub struct NetMD {
-    usb_interface: Interface,
+pub struct NetMD<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    transport: T,
     model: DeviceId,
+    retry_policy: RetryPolicy,
 }
 
-impl NetMD {
-    const READ_REPLY_RETRY_INTERVAL: u32 = 10;
-
-    /// Creates a new interface to a NetMD device
-    pub async fn new(usb_descriptor: DeviceInfo) -> Result<Self, NetMDError> {
-        let mut model = DeviceId {
-            vendor_id: usb_descriptor.vendor_id().await,
-            product_id: usb_descriptor.product_id().await,
-            name: None,
-        };
-
-        for device_type in DEVICE_IDS.iter() {
-            if device_type.vendor_id == model.vendor_id
-                && device_type.product_id == model.product_id
-            {
-                model.name = device_type.name;
-                break;
-            }
-        }
+impl<T: Transport> NetMD<T> {
+    /// Creates a new interface to a NetMD device over a [`Transport`]
+    pub fn new(transport: T) -> Result<Self, NetMDError> {
+        let model = DeviceId::lookup(transport.vendor_id(), transport.product_id());
 
         match model.name {
             None => return Err(NetMDError::UnknownDevice(model)),
             Some(_) => (),
         }
 
-        let usb_device = usb_descriptor.open().await?;
-        let usb_interface = usb_device.open_interface(0).await?;
-
         Ok(Self {
-            usb_interface,
+            transport,
             model,
+            retry_policy: RetryPolicy::default(),
         })
     }
 
-    /// Gets the device name, this is limited to the devices in the list
+    /// Gets the device name, this is limited to the known devices
     pub fn device_name(&self) -> Option<&str> {
-        self.model.name
+        self.model.name()
+    }
+
+    /// Gets the capabilities and quirks of the device
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.model.capabilities
     }
 
     /// Gets the vendor id
@@ -177,33 +444,33 @@ impl NetMD {
         self.model.product_id
     }
 
+    /// Gets the policy used when waiting for replies
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Sets the policy used when waiting for replies
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Get a reference to the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Get a mutable reference to the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
     /// Poll the device to get either the result
     /// of the previous command, or the status
     pub async fn poll(&mut self) -> Result<(u16, [u8; 4]), NetMDError> {
-        // Create an array to store the result of the poll
-        let poll_result = match self
-            .usb_interface
-            .control_in(ControlIn {
-                control_type: ControlType::Vendor,
-                recipient: Recipient::Interface,
-                request: 0x01,
-                value: 0,
-                index: 0,
-                length: 4,
-            })
-            .await
-        {
-            Ok(size) => size,
-            Err(error) => return Err(error.into()),
-        };
+        let poll_result = self.transport.poll().await?;
 
         let length_bytes = u16::from_le_bytes([poll_result[2], poll_result[3]]);
 
-        let poll_result: [u8; 4] = match poll_result.try_into() {
-            Ok(val) => val,
-            Err(_) => return Err(NetMDError::InvalidResult),
-        };
-
         Ok((length_bytes, poll_result))
     }
 
@@ -223,34 +490,9 @@ impl NetMD {
         command: Vec<u8>,
         use_factory_command: bool,
     ) -> Result<(), NetMDError> {
-        // First poll to ensure the device is ready
-        match self.poll().await {
-            Ok(buffer) => match buffer.1[2] {
-                0 => 0,
-                _ => return Err(NetMDError::NotReady),
-            },
-            Err(error) => return Err(error),
-        };
-
-        let request = match use_factory_command {
-            false => 0x80,
-            true => 0xff,
-        };
-
-        match self
-            .usb_interface
-            .control_out(ControlOut {
-                control_type: ControlType::Vendor,
-                recipient: Recipient::Interface,
-                request,
-                value: 0,
-                index: 0,
-                data: &command,
-            })
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(error) => Err(error.into()),
+        match use_factory_command {
+            false => self.drive(SendCommand::new(command)).await,
+            true => self.drive(SendCommand::factory(command)).await,
         }
     }
 
@@ -274,48 +516,47 @@ impl NetMD {
         use_factory_command: bool,
         override_length: Option<i32>,
     ) -> Result<Vec<u8>, NetMDError> {
-        let mut length = 0;
-
-        for attempt in 0..40 {
-            if attempt == 39 {
-                return Err(NetMDError::Timeout);
-            }
-
-            length = self.poll().await?.0;
-
-            if length > 0 {
-                break;
-            }
-
-            // Back off while trying again
-            let sleep_time = Self::READ_REPLY_RETRY_INTERVAL * (u32::pow(2, attempt) - 1);
-
-            cross_sleep(Duration::from_millis(sleep_time as u64)).await;
-        }
-
+        let mut machine = match use_factory_command {
+            false => ReadReply::new(self.retry_policy),
+            true => ReadReply::factory(self.retry_policy),
+        };
         if let Some(value) = override_length {
-            length = value as u16
+            machine = machine.with_length(value as u16);
         }
 
-        let request = match use_factory_command {
-            false => 0x81,
-            true => 0xff,
-        };
+        self.drive(machine).await
+    }
 
-        // Create a buffer to fill with the result
-        let reply = self
-            .usb_interface
-            .control_in(ControlIn {
-                control_type: ControlType::Vendor,
-                recipient: Recipient::Interface,
-                request,
-                value: 0,
-                index: 0,
-                length,
-            })
-            .await?;
+    /// Run a protocol [`Machine`] over the transport until it is done
+    pub async fn drive<M: Machine>(&mut self, mut machine: M) -> Result<M::Output, M::Error> {
+        let mut request = machine.start();
+
+        loop {
+            let response = match request {
+                Request::Poll => Response::Polled(self.transport.poll().await?),
+                Request::Send(command) => {
+                    self.transport.send_command(&command).await?;
+                    Response::Sent
+                }
+                Request::SendFactory(command) => {
+                    self.transport.send_factory_command(&command).await?;
+                    Response::Sent
+                }
+                Request::Read(length) => Response::Read(self.transport.read_reply(length).await?),
+                Request::ReadFactory(length) => {
+                    Response::Read(self.transport.read_factory_reply(length).await?)
+                }
+                Request::Wait(duration) => {
+                    cross_sleep(duration).await;
+                    Response::Waited
+                }
+            };
 
-        Ok(reply)
+            request = match machine.advance(response)? {
+                Step::Request(request) => request,
+                Step::Done(output) => return Ok(output),
+            };
+        }
     }
 
     // Default chunksize should be 0x10000
@@ -343,16 +584,9 @@ impl NetMD {
 
         while done < length {
             let to_read = std::cmp::min(chunksize, length - done);
-            done -= to_read;
-
-            let res = match self
-                .usb_interface
-                .bulk_in(BULK_READ_ENDPOINT, to_read)
-                .await
-            {
-                Ok(result) => result,
-                Err(error) => return Err(NetMDError::UsbError(error)),
-            };
+            done += to_read;
+
+            let res = self.transport.read_bulk(to_read).await?;
 
             if let Some(cb) = &progress_callback {
                 cb(length, done)
@@ -365,9 +599,6 @@ impl NetMD {
     }
 
     pub async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError> {
-        Ok(self
-            .usb_interface
-            .bulk_out(BULK_WRITE_ENDPOINT, data)
-            .await?)
+        self.transport.write_bulk(data).await
     }
 }
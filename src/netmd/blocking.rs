@@ -0,0 +1,491 @@
+//! A synchronous API, for programs which are not async.
+//!
+//! [`BlockingNetMDContext`] and [`BlockingNetMDInterface`] wrap their async
+//! counterparts and run every call to completion on their own single
+//! threaded runtime, so no executor is needed by the caller. They cannot be
+//! used from within an async runtime, as the calls block the thread.
+//!
+//! ```no_run
+//! use minidisc::netmd::blocking::BlockingNetMDContext;
+//!
+//! let mut context = BlockingNetMDContext::open_first().unwrap();
+//!
+//! let disc = context.list_content().unwrap();
+//! for track in disc.tracks() {
+//!     println!("{}: {}", track.index(), track.title());
+//! }
+//!
+//! context.rename_disc("New title", None).unwrap();
+//! context.play().unwrap();
+//! ```
+//!
+//! Anything which is not mirrored here can be run through
+//! [`BlockingNetMDContext::run`].
+#[cfg(feature = "native-usb")]
+use cross_usb::DeviceInfo;
+use tokio::runtime::Runtime;
+
+use super::base::Capabilities;
+#[cfg(feature = "native-usb")]
+use super::base::{device_filters, NetMDError};
+use super::commands::{ContextError, DeviceStatus, Disc, NetMDContext};
+#[cfg(feature = "native-usb")]
+use super::discovery::{self, DiscoveredDevice};
+use super::handle::OperationFuture;
+use super::interface::{
+    Channels, Direction, DiscFormat, Encoding, InterfaceError, MDTrack, NetMDInterface, NetMDLevel,
+    OperatingStatus, StatusBlock,
+};
+use super::protocol::GroupList;
+use super::transport::Transport;
+#[cfg(feature = "native-usb")]
+use super::transport::UsbTransport;
+use super::utils::RawTime;
+
+/// Mirror async methods as methods which block on the runtime of the
+/// wrapper. `$parts` is a method returning the runtime and the wrapped value.
+macro_rules! blocking {
+    ($parts:ident => $(
+        $(#[$meta:meta])*
+        fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty;
+    )*) => {
+        $(
+            $(#[$meta])*
+            pub fn $name(&mut self $(, $arg: $ty)*) -> $ret {
+                let (runtime, inner) = self.$parts();
+                runtime.block_on(inner.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed building the blocking runtime")
+}
+
+/// List every connected device which is known, see
+/// [`discovery::list_devices`]
+#[cfg(feature = "native-usb")]
+pub fn list_devices() -> Result<Vec<DiscoveredDevice>, NetMDError> {
+    runtime().block_on(discovery::list_devices())
+}
+
+/// A synchronous [`NetMDContext`].
+///
+/// Besides the methods of the context, the playback and title methods of the
+/// interface are available directly; the rest of the interface can be used
+/// through [`BlockingNetMDContext::run`].
+pub struct BlockingNetMDContext<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    context: NetMDContext<T>,
+    runtime: Runtime,
+}
+
+#[cfg(feature = "native-usb")]
+impl BlockingNetMDContext<UsbTransport> {
+    /// Create a new context to control a NetMD device
+    pub fn new(device: DeviceInfo) -> Result<Self, InterfaceError> {
+        let runtime = runtime();
+        let context = runtime.block_on(NetMDContext::new(device))?;
+
+        Ok(Self { context, runtime })
+    }
+
+    /// Open the first known device which is connected
+    pub fn open_first() -> Result<Self, InterfaceError> {
+        let runtime = runtime();
+        let context = runtime.block_on(async {
+            let device = cross_usb::get_device(device_filters())
+                .await
+                .map_err(NetMDError::from)?;
+
+            NetMDContext::new(device).await
+        })?;
+
+        Ok(Self { context, runtime })
+    }
+
+    /// Open a device found by [`list_devices`]
+    pub fn open(device: DiscoveredDevice) -> Result<Self, InterfaceError> {
+        let runtime = runtime();
+        let context = runtime.block_on(device.open())?;
+
+        Ok(Self { context, runtime })
+    }
+}
+
+impl<T: Transport> BlockingNetMDContext<T> {
+    /// Wrap an already opened context
+    pub fn from_context(context: NetMDContext<T>) -> Self {
+        Self {
+            context,
+            runtime: runtime(),
+        }
+    }
+
+    /// Get back the async context
+    pub fn into_inner(self) -> NetMDContext<T> {
+        self.context
+    }
+
+    /// Gets the capabilities and quirks of the device, see
+    /// [`NetMDContext::capabilities`]
+    pub fn capabilities(&self) -> &Capabilities {
+        self.context.capabilities()
+    }
+
+    /// Get a reference to the async context
+    pub fn context(&self) -> &NetMDContext<T> {
+        &self.context
+    }
+
+    /// Get a mutable reference to the async context
+    pub fn context_mut(&mut self) -> &mut NetMDContext<T> {
+        &mut self.context
+    }
+
+    /// Run an async operation on the context to completion.
+    ///
+    /// ```no_run
+    /// # use minidisc::netmd::blocking::BlockingNetMDContext;
+    /// # let mut context = BlockingNetMDContext::open_first().unwrap();
+    /// let flags = context
+    ///     .run(|context| Box::pin(context.interface_mut().disc_flags()))
+    ///     .unwrap();
+    /// ```
+    pub fn run<R, F>(&mut self, operation: F) -> R
+    where
+        F: for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, R>,
+    {
+        self.runtime.block_on(operation(&mut self.context))
+    }
+
+    /// Get the track as AEA or WAV data, see [`NetMDContext::upload`]
+    pub fn upload<F: Fn(usize, usize)>(
+        &mut self,
+        track: u16,
+        progress_callback: Option<F>,
+    ) -> Result<(DiscFormat, Vec<u8>), ContextError> {
+        self.runtime
+            .block_on(self.context.upload(track, progress_callback))
+    }
+
+    /// Download a track to the device, see [`NetMDContext::download`]
+    pub fn download<F: Fn(usize, usize)>(
+        &mut self,
+        track: MDTrack,
+        progress_callback: F,
+    ) -> Result<(u16, Vec<u8>, Vec<u8>), ContextError> {
+        self.runtime
+            .block_on(self.context.download(track, progress_callback))
+    }
+
+    fn parts(&mut self) -> (&Runtime, &mut NetMDContext<T>) {
+        (&self.runtime, &mut self.context)
+    }
+
+    fn interface_parts(&mut self) -> (&Runtime, &mut NetMDInterface<T>) {
+        (&self.runtime, self.context.interface_mut())
+    }
+
+    blocking! { parts =>
+        /// See [`NetMDContext::net_md_level`]
+        fn net_md_level(&mut self) -> Result<NetMDLevel, InterfaceError>;
+        /// See [`NetMDContext::next_track`]
+        fn next_track(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDContext::previous_track`]
+        fn previous_track(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDContext::restart_track`]
+        fn restart_track(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDContext::device_status`]
+        fn device_status(&mut self) -> Result<DeviceStatus, ContextError>;
+        /// See [`NetMDContext::list_content`]
+        fn list_content(&mut self) -> Result<Disc, ContextError>;
+        /// See [`NetMDContext::rewrite_disc_groups`]
+        fn rewrite_disc_groups(&mut self, disc: Disc) -> Result<(), ContextError>;
+        /// See [`NetMDContext::rename_disc`]
+        fn rename_disc(
+            &mut self,
+            new_name: &str,
+            new_fw_name: Option<&str>
+        ) -> Result<(), ContextError>;
+    }
+
+    blocking! { interface_parts =>
+        /// See [`NetMDInterface::play`]
+        fn play(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::pause`]
+        fn pause(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::stop`]
+        fn stop(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::go_to_track`]
+        fn go_to_track(&mut self, track_number: u16) -> Result<u16, InterfaceError>;
+        /// See [`NetMDInterface::eject_disc`]
+        fn eject_disc(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::track_count`]
+        fn track_count(&mut self) -> Result<u16, InterfaceError>;
+        /// See [`NetMDInterface::disc_title`]
+        fn disc_title(&mut self, wchar: bool) -> Result<String, InterfaceError>;
+        /// See [`NetMDInterface::set_track_title`]
+        fn set_track_title(
+            &mut self,
+            track: u16,
+            title: &str,
+            wchar: bool
+        ) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::erase_track`]
+        fn erase_track(&mut self, track: u16) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::move_track`]
+        fn move_track(&mut self, source: u16, dest: u16) -> Result<(), InterfaceError>;
+    }
+}
+
+impl<T: Transport> From<NetMDContext<T>> for BlockingNetMDContext<T> {
+    fn from(value: NetMDContext<T>) -> Self {
+        Self::from_context(value)
+    }
+}
+
+/// A synchronous [`NetMDInterface`].
+pub struct BlockingNetMDInterface<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    interface: NetMDInterface<T>,
+    runtime: Runtime,
+}
+
+#[cfg(feature = "native-usb")]
+impl BlockingNetMDInterface<UsbTransport> {
+    /// Get a new interface to a NetMD device
+    pub fn new(device: DeviceInfo) -> Result<Self, InterfaceError> {
+        let runtime = runtime();
+        let interface = runtime.block_on(NetMDInterface::new(device))?;
+
+        Ok(Self { interface, runtime })
+    }
+}
+
+impl<T: Transport> BlockingNetMDInterface<T> {
+    /// Wrap an already opened interface
+    pub fn from_interface(interface: NetMDInterface<T>) -> Self {
+        Self {
+            interface,
+            runtime: runtime(),
+        }
+    }
+
+    /// Get back the async interface
+    pub fn into_inner(self) -> NetMDInterface<T> {
+        self.interface
+    }
+
+    /// Turn the interface into a [`BlockingNetMDContext`], keeping the
+    /// runtime
+    pub fn into_context(self) -> BlockingNetMDContext<T> {
+        BlockingNetMDContext {
+            context: NetMDContext::from(self.interface),
+            runtime: self.runtime,
+        }
+    }
+
+    /// Run an async operation on the interface to completion
+    pub fn run<R, F>(&mut self, operation: F) -> R
+    where
+        F: for<'a> FnOnce(&'a mut NetMDInterface<T>) -> OperationFuture<'a, R>,
+    {
+        self.runtime.block_on(operation(&mut self.interface))
+    }
+
+    /// Read a track from the device, see
+    /// [`NetMDInterface::save_track_to_array`]
+    pub fn save_track_to_array<F: Fn(usize, usize)>(
+        &mut self,
+        track: u16,
+        progress_callback: Option<F>,
+    ) -> Result<(DiscFormat, u16, Vec<u8>), InterfaceError> {
+        self.runtime
+            .block_on(self.interface.save_track_to_array(track, progress_callback))
+    }
+
+    fn parts(&mut self) -> (&Runtime, &mut NetMDInterface<T>) {
+        (&self.runtime, &mut self.interface)
+    }
+
+    blocking! { parts =>
+        /// See [`NetMDInterface::net_md_level`]
+        fn net_md_level(&mut self) -> Result<NetMDLevel, InterfaceError>;
+        /// See [`NetMDInterface::play`]
+        fn play(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::fast_forward`]
+        fn fast_forward(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::rewind`]
+        fn rewind(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::pause`]
+        fn pause(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::stop`]
+        fn stop(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::acquire`]
+        fn acquire(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::release`]
+        fn release(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::status`]
+        fn status(&mut self) -> Result<StatusBlock, InterfaceError>;
+        /// See [`NetMDInterface::full_status`]
+        fn full_status(&mut self) -> Result<StatusBlock, InterfaceError>;
+        /// See [`NetMDInterface::disc_present`]
+        fn disc_present(&mut self) -> Result<bool, InterfaceError>;
+        /// See [`NetMDInterface::operating_status`]
+        fn operating_status(&mut self) -> Result<OperatingStatus, InterfaceError>;
+        /// See [`NetMDInterface::position`]
+        fn position(&mut self) -> Result<[u16; 5], InterfaceError>;
+        /// See [`NetMDInterface::eject_disc`]
+        fn eject_disc(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::go_to_track`]
+        fn go_to_track(&mut self, track_number: u16) -> Result<u16, InterfaceError>;
+        /// See [`NetMDInterface::go_to_time`]
+        fn go_to_time(
+            &mut self,
+            track_number: u16,
+            hour: u8,
+            minute: u8,
+            second: u8,
+            frame: u8
+        ) -> Result<u16, InterfaceError>;
+        /// See [`NetMDInterface::track_change`]
+        fn track_change(&mut self, direction: Direction) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::erase_disc`]
+        fn erase_disc(&mut self) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::disc_flags`]
+        fn disc_flags(&mut self) -> Result<u8, InterfaceError>;
+        /// See [`NetMDInterface::track_count`]
+        fn track_count(&mut self) -> Result<u16, InterfaceError>;
+        /// See [`NetMDInterface::raw_disc_title`]
+        fn raw_disc_title(&mut self, wchar: bool) -> Result<String, InterfaceError>;
+        /// See [`NetMDInterface::disc_title`]
+        fn disc_title(&mut self, wchar: bool) -> Result<String, InterfaceError>;
+        /// See [`NetMDInterface::track_group_list`]
+        fn track_group_list(&mut self) -> Result<GroupList, InterfaceError>;
+        /// See [`NetMDInterface::track_titles`]
+        fn track_titles(
+            &mut self,
+            tracks: Vec<u16>,
+            wchar: bool
+        ) -> Result<Vec<String>, InterfaceError>;
+        /// See [`NetMDInterface::track_title`]
+        fn track_title(&mut self, track: u16, wchar: bool) -> Result<String, InterfaceError>;
+        /// See [`NetMDInterface::set_disc_title`]
+        fn set_disc_title(&mut self, title: &str, wchar: bool) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::set_track_title`]
+        fn set_track_title(
+            &mut self,
+            track: u16,
+            title: &str,
+            wchar: bool
+        ) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::erase_track`]
+        fn erase_track(&mut self, track: u16) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::move_track`]
+        fn move_track(&mut self, source: u16, dest: u16) -> Result<(), InterfaceError>;
+        /// See [`NetMDInterface::track_lengths`]
+        fn track_lengths(&mut self, tracks: Vec<u16>) -> Result<Vec<RawTime>, InterfaceError>;
+        /// See [`NetMDInterface::track_length`]
+        fn track_length(&mut self, track: u16) -> Result<RawTime, InterfaceError>;
+        /// See [`NetMDInterface::track_encoding`]
+        fn track_encoding(
+            &mut self,
+            track_number: u16
+        ) -> Result<(Encoding, Channels), InterfaceError>;
+        /// See [`NetMDInterface::track_flags`]
+        fn track_flags(&mut self, track: u16) -> Result<u8, InterfaceError>;
+        /// See [`NetMDInterface::disc_capacity`]
+        fn disc_capacity(&mut self) -> Result<[RawTime; 3], InterfaceError>;
+    }
+}
+
+impl<T: Transport> From<NetMDInterface<T>> for BlockingNetMDInterface<T> {
+    fn from(value: NetMDInterface<T>) -> Self {
+        Self::from_interface(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::netmd::emulator::{VirtualDisc, VirtualPlayer, VirtualTrack};
+    use crate::netmd::interface::WireFormat;
+    use crate::netmd::NetMD;
+
+    fn context() -> BlockingNetMDContext<VirtualPlayer> {
+        let disc = VirtualDisc {
+            title: String::from("Mix"),
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+        let device = NetMD::new(VirtualPlayer::new(disc)).unwrap();
+
+        BlockingNetMDContext::from_context(NetMDContext::from(NetMDInterface::from(device)))
+    }
+
+    fn player_disc(context: &BlockingNetMDContext<VirtualPlayer>) -> &VirtualDisc {
+        context
+            .context()
+            .interface()
+            .device
+            .transport()
+            .disc()
+            .unwrap()
+    }
+
+    #[test]
+    fn list_content() {
+        let mut context = context();
+
+        let content = context.list_content().unwrap();
+
+        assert_eq!(content.track_count(), 1);
+        assert_eq!(content.tracks()[0].title(), "First");
+    }
+
+    #[test]
+    fn rename_disc() {
+        let mut context = context();
+
+        context.rename_disc("Renamed", None).unwrap();
+
+        assert_eq!(player_disc(&context).title, "Renamed");
+        assert_eq!(context.disc_title(false).unwrap(), "Renamed");
+    }
+
+    #[test]
+    fn download() {
+        let data: Vec<u8> = (0..2048 * 2).map(|i| (i % 251) as u8).collect();
+        let track = MDTrack {
+            title: String::from("Downloaded"),
+            format: WireFormat::Pcm,
+            data: data.clone(),
+            chunk_size: 0x400,
+            full_width_title: None,
+        };
+        let mut context = context();
+
+        let (index, _, _) = context.download(track, |_, _| {}).unwrap();
+
+        let disc = player_disc(&context);
+        assert_eq!(index, 1);
+        assert_eq!(disc.tracks.len(), 2);
+        assert_eq!(disc.tracks[1].title, "Downloaded");
+        assert_eq!(disc.tracks[1].data, data);
+    }
+}
@@ -0,0 +1,535 @@
+//! Recording and replaying of the traffic between the host and a device.
+//!
+//! [`RecordingTransport`] wraps another [`Transport`] and writes everything
+//! which passes through it to a capture file. [`ReplayTransport`] reads a
+//! capture back and serves the recorded bytes, so a problem seen on one
+//! particular device can be reproduced without having that device.
+//!
+//! Captures are plain text, with the device IDs on the first line followed by
+//! one event per line, in milliseconds since the recording started:
+//!
+//! ```text
+//! device 054c 0286
+//! 0 poll 00000000
+//! 1 command 00180801101801000000
+//! 3 poll 00000a00
+//! 4 reply 0918080110180100000000
+//! ```
+//!
+//! A capture can then be replayed against the higher level interfaces:
+//!
+//! ```no_run
+//! use minidisc::netmd::capture::ReplayTransport;
+//! use minidisc::netmd::{NetMD, NetMDInterface};
+//!
+//! # async fn replay() -> Result<(), Box<dyn std::error::Error>> {
+//! let transport = ReplayTransport::open("capture.txt")?;
+//! let mut interface = NetMDInterface::from(NetMD::new(transport)?);
+//!
+//! interface.disc_title(false).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::base::NetMDError;
+use super::dissector::SECURE_PREFIX;
+use super::transport::Transport;
+
+/// The kind of a [`CaptureEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Poll,
+    Command,
+    FactoryCommand,
+    Reply,
+    FactoryReply,
+    BulkIn,
+    BulkOut,
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EventKind::Poll => "poll",
+            EventKind::Command => "command",
+            EventKind::FactoryCommand => "factory_command",
+            EventKind::Reply => "reply",
+            EventKind::FactoryReply => "factory_reply",
+            EventKind::BulkIn => "bulk_in",
+            EventKind::BulkOut => "bulk_out",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "poll" => EventKind::Poll,
+            "command" => EventKind::Command,
+            "factory_command" => EventKind::FactoryCommand,
+            "reply" => EventKind::Reply,
+            "factory_reply" => EventKind::FactoryReply,
+            "bulk_in" => EventKind::BulkIn,
+            "bulk_out" => EventKind::BulkOut,
+            _ => return Err(invalid_data(format!("unknown event kind `{}`", s))),
+        })
+    }
+}
+
+/// A single transfer between the host and the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureEvent {
+    /// The time since the start of the recording
+    pub time: Duration,
+    pub kind: EventKind,
+    pub data: Vec<u8>,
+}
+
+/// A complete recording of the traffic to a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub events: Vec<CaptureEvent>,
+}
+
+impl Capture {
+    /// Read a capture from a file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a capture from anything which can be read line by line
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut lines = reader.lines();
+
+        let header = lines
+            .next()
+            .ok_or_else(|| invalid_data("capture is empty".into()))??;
+        let (vendor_id, product_id) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+            ["device", vendor_id, product_id] => (parse_id(vendor_id)?, parse_id(product_id)?),
+            _ => return Err(invalid_data(format!("invalid capture header `{}`", header))),
+        };
+
+        let mut events = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(time), Some(kind)) = (fields.next(), fields.next()) else {
+                return Err(invalid_data(format!("invalid capture line `{}`", line)));
+            };
+
+            events.push(CaptureEvent {
+                time: Duration::from_millis(
+                    time.parse()
+                        .map_err(|_| invalid_data(format!("invalid time `{}`", time)))?,
+                ),
+                kind: kind.parse()?,
+                data: from_hex(fields.next().unwrap_or_default())?,
+            });
+        }
+
+        Ok(Self {
+            vendor_id,
+            product_id,
+            events,
+        })
+    }
+}
+
+/// A [`Transport`] which records all traffic passing through it.
+pub struct RecordingTransport<T, W = BufWriter<File>> {
+    inner: T,
+    writer: W,
+    start: Instant,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Record the traffic of a transport to a file at `path`
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> io::Result<Self> {
+        Self::new(inner, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<T: Transport, W: Write> RecordingTransport<T, W> {
+    /// Record the traffic of a transport to any writer
+    pub fn new(inner: T, mut writer: W) -> io::Result<Self> {
+        writeln!(
+            writer,
+            "device {:04x} {:04x}",
+            inner.vendor_id(),
+            inner.product_id()
+        )?;
+        writer.flush()?;
+
+        Ok(Self {
+            inner,
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Stop recording, returning the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, kind: EventKind, data: &[u8]) -> Result<(), NetMDError> {
+        let time = self.start.elapsed().as_millis();
+
+        // Flush every event, so a capture is still useful if the program crashes
+        writeln!(self.writer, "{} {} {}", time, kind, to_hex(data))
+            .and_then(|_| self.writer.flush())
+            .map_err(|error| NetMDError::CaptureError(error.to_string()))
+    }
+}
+
+impl<T: Transport, W: Write> Transport for RecordingTransport<T, W> {
+    fn vendor_id(&self) -> u16 {
+        self.inner.vendor_id()
+    }
+
+    fn product_id(&self) -> u16 {
+        self.inner.product_id()
+    }
+
+    async fn poll(&mut self) -> Result<[u8; 4], NetMDError> {
+        let result = self.inner.poll().await?;
+        self.record(EventKind::Poll, &result)?;
+
+        Ok(result)
+    }
+
+    async fn send_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.inner.send_command(command).await?;
+        self.record(EventKind::Command, command)
+    }
+
+    async fn send_factory_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.inner.send_factory_command(command).await?;
+        self.record(EventKind::FactoryCommand, command)
+    }
+
+    async fn read_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        let reply = self.inner.read_reply(length).await?;
+        self.record(EventKind::Reply, &reply)?;
+
+        Ok(reply)
+    }
+
+    async fn read_factory_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        let reply = self.inner.read_factory_reply(length).await?;
+        self.record(EventKind::FactoryReply, &reply)?;
+
+        Ok(reply)
+    }
+
+    async fn read_bulk(&mut self, length: usize) -> Result<Vec<u8>, NetMDError> {
+        let data = self.inner.read_bulk(length).await?;
+        self.record(EventKind::BulkIn, &data)?;
+
+        Ok(data)
+    }
+
+    async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError> {
+        let written = self.inner.write_bulk(data).await?;
+        self.record(EventKind::BulkOut, data)?;
+
+        Ok(written)
+    }
+}
+
+/// A [`Transport`] which plays back a [`Capture`].
+///
+/// Everything the host sends is checked against the capture, and the
+/// recorded replies are returned in order. Any difference results in a
+/// [`NetMDError::ReplayMismatch`].
+///
+/// Parts of the secure session handshake are random, so the secure commands
+/// and track data sent while downloading will not match the capture when
+/// replaying it. Use [`ReplayTransport::set_strict`] to only check their
+/// lengths in that case.
+pub struct ReplayTransport {
+    vendor_id: u16,
+    product_id: u16,
+    events: VecDeque<CaptureEvent>,
+    strict: bool,
+}
+
+impl ReplayTransport {
+    /// Replay a capture file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Capture::open(path)?.into())
+    }
+
+    /// Set whether the data sent by the host must match the capture exactly.
+    /// This is on by default.
+    ///
+    /// When it is off, secure session commands only have to match up to the
+    /// secure command byte, and bulk transfers to the device only have to
+    /// have the same length. Every other command must still match exactly.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// The number of events which have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+
+    fn next_event(&mut self, kind: EventKind) -> Result<CaptureEvent, NetMDError> {
+        match self.events.pop_front() {
+            Some(event) if event.kind == kind => Ok(event),
+            Some(event) => Err(NetMDError::ReplayMismatch(format!(
+                "expected {} but the capture has {} at {}ms",
+                kind,
+                event.kind,
+                event.time.as_millis()
+            ))),
+            None => Err(NetMDError::ReplayMismatch(format!(
+                "expected {} but the capture has ended",
+                kind
+            ))),
+        }
+    }
+
+    fn expect(&mut self, kind: EventKind, data: &[u8]) -> Result<(), NetMDError> {
+        let event = self.next_event(kind)?;
+        if !self.matches(kind, &event.data, data) {
+            return Err(NetMDError::ReplayMismatch(format!(
+                "{} at {}ms was {} in the capture, but {} was sent",
+                kind,
+                event.time.as_millis(),
+                to_hex(&event.data),
+                to_hex(data)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Whether data sent by the host matches the data in the capture
+    fn matches(&self, kind: EventKind, recorded: &[u8], sent: &[u8]) -> bool {
+        if self.strict || recorded == sent {
+            return recorded == sent;
+        }
+
+        // The status byte, the secure prefix and the secure command
+        let header = SECURE_PREFIX.len() + 2;
+        match kind {
+            // Track data is encrypted with random keys
+            EventKind::BulkOut => recorded.len() == sent.len(),
+            // Secure commands hold random nonces and values derived from them
+            EventKind::Command if sent.get(1..header - 1) == Some(&SECURE_PREFIX[..]) => {
+                recorded.len() == sent.len() && recorded.get(..header) == sent.get(..header)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<Capture> for ReplayTransport {
+    fn from(value: Capture) -> Self {
+        Self {
+            vendor_id: value.vendor_id,
+            product_id: value.product_id,
+            events: value.events.into(),
+            strict: true,
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    async fn poll(&mut self) -> Result<[u8; 4], NetMDError> {
+        let event = self.next_event(EventKind::Poll)?;
+
+        event.data.try_into().map_err(|_| NetMDError::InvalidResult)
+    }
+
+    async fn send_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.expect(EventKind::Command, command)
+    }
+
+    async fn send_factory_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.expect(EventKind::FactoryCommand, command)
+    }
+
+    async fn read_reply(&mut self, _length: u16) -> Result<Vec<u8>, NetMDError> {
+        Ok(self.next_event(EventKind::Reply)?.data)
+    }
+
+    async fn read_factory_reply(&mut self, _length: u16) -> Result<Vec<u8>, NetMDError> {
+        Ok(self.next_event(EventKind::FactoryReply)?.data)
+    }
+
+    async fn read_bulk(&mut self, _length: usize) -> Result<Vec<u8>, NetMDError> {
+        Ok(self.next_event(EventKind::BulkIn)?.data)
+    }
+
+    async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError> {
+        self.expect(EventKind::BulkOut, data)?;
+
+        Ok(data.len())
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_id(id: &str) -> io::Result<u16> {
+    u16::from_str_radix(id, 16).map_err(|_| invalid_data(format!("invalid device ID `{}`", id)))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> io::Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(invalid_data(format!("odd length hex data `{}`", hex)));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| invalid_data(format!("invalid hex data `{}`", hex)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::emulator::{VirtualDisc, VirtualPlayer, VirtualTrack};
+    use crate::netmd::interface::Encoding;
+    use crate::netmd::{NetMD, NetMDContext, NetMDInterface};
+
+    const EJECT: [u8; 6] = [0x00, 0x18, 0xc1, 0xff, 0x60, 0x00];
+
+    fn secure(nonce: u8) -> Vec<u8> {
+        [
+            &[0x00][..],
+            &SECURE_PREFIX[..],
+            &[0x20, 0xff, 0x00, 0x00, nonce],
+        ]
+        .concat()
+    }
+
+    fn replay(capture: &str) -> ReplayTransport {
+        Capture::from_reader(capture.as_bytes()).unwrap().into()
+    }
+
+    #[test]
+    fn round_trip() {
+        tokio_test::block_on(async {
+            let disc = VirtualDisc {
+                title: "Capture".into(),
+                tracks: vec![VirtualTrack::new(
+                    "One",
+                    Encoding::SP,
+                    Duration::from_secs(60),
+                )],
+                ..VirtualDisc::default()
+            };
+
+            let mut recorded = Vec::new();
+            let transport =
+                RecordingTransport::new(VirtualPlayer::new(disc), &mut recorded).unwrap();
+            let mut context =
+                NetMDContext::from(NetMDInterface::from(NetMD::new(transport).unwrap()));
+            context.rename_disc("Renamed", None).await.unwrap();
+            let listed = context.list_content().await.unwrap();
+            drop(context);
+
+            let transport = replay(std::str::from_utf8(&recorded).unwrap());
+            let mut context =
+                NetMDContext::from(NetMDInterface::from(NetMD::new(transport).unwrap()));
+            context.rename_disc("Renamed", None).await.unwrap();
+            let replayed = context.list_content().await.unwrap();
+
+            assert_eq!(format!("{:?}", replayed), format!("{:?}", listed));
+            assert_eq!(context.interface().device.transport().remaining(), 0);
+        });
+    }
+
+    #[test]
+    fn failed_commands_are_not_recorded() {
+        let mut recorded = Vec::new();
+        let mut transport =
+            RecordingTransport::new(VirtualPlayer::new(VirtualDisc::default()), &mut recorded)
+                .unwrap();
+
+        assert!(tokio_test::block_on(transport.send_command(&[])).is_err());
+        drop(transport);
+
+        let capture = Capture::from_reader(&recorded[..]).unwrap();
+        assert!(capture.events.is_empty());
+    }
+
+    #[test]
+    fn strict_replay() {
+        let mut transport = replay(&format!(
+            "device 054c 0286\n0 command {}\n",
+            to_hex(&secure(1))
+        ));
+
+        let result = tokio_test::block_on(transport.send_command(&secure(2)));
+        assert!(matches!(result, Err(NetMDError::ReplayMismatch(_))));
+    }
+
+    #[test]
+    fn relaxed_replay() {
+        let mut transport = replay(&format!(
+            "device 054c 0286\n0 command {}\n1 bulk_out 0102\n",
+            to_hex(&secure(1))
+        ));
+        transport.set_strict(false);
+
+        tokio_test::block_on(async {
+            transport.send_command(&secure(2)).await.unwrap();
+            assert_eq!(transport.write_bulk(&[0x03, 0x04]).await.unwrap(), 2);
+        });
+        assert_eq!(transport.remaining(), 0);
+    }
+
+    #[test]
+    fn relaxed_replay_checks_other_commands() {
+        let mut transport = replay(&format!("device 054c 0286\n0 command {}\n", to_hex(&EJECT)));
+        transport.set_strict(false);
+
+        let mut command = EJECT;
+        command[5] = 0x01;
+        let result = tokio_test::block_on(transport.send_command(&command));
+        assert!(matches!(result, Err(NetMDError::ReplayMismatch(_))));
+    }
+}
@@ -1,39 +1,88 @@
 #![cfg_attr(debug_assertions, allow(dead_code))]
+#[cfg(feature = "native-usb")]
 use cross_usb::DeviceInfo;
-use num_derive::FromPrimitive;
+use log::warn;
 use num_traits::FromPrimitive;
 use regex::Regex;
-use std::error::Error;
 use std::time::Duration;
+use thiserror::Error;
 
 use crate::netmd::interface::DiscFlag;
 use crate::netmd::utils::{create_aea_header, create_wav_header, AeaOptions, RawTime};
 
+use super::base::{Capabilities, NetMDError};
 use super::interface::{
-    Channels, Direction, DiscFormat, Encoding, InterfaceError, MDSession, MDTrack, NetMDInterface,
-    TrackFlag,
+    Channels, CommandSupport, Direction, DiscFormat, Encoding, InterfaceError, MDSession, MDTrack,
+    NetMDInterface, NetMDLevel, SubunitIdentifier, TrackFlag, WireFormat,
 };
+use super::transport::Transport;
+#[cfg(feature = "native-usb")]
+use super::transport::UsbTransport;
 use super::utils::{
     cross_sleep, half_width_title_length, half_width_to_full_width_range,
     sanitize_full_width_title, sanitize_half_width_title,
 };
+use super::watcher::StatusWatcher;
 
-/// The current reported status from the device.
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
-pub enum OperatingStatus {
-    Ready = 50687,
-    Playing = 50037,
-    Paused = 50045,
-    FastForward = 49983,
-    Rewind = 49999,
-    ReadingTOC = 65315,
-    NoDisc = 65296,
-    DiscBlank = 65535,
-    ReadyForTransfer = 65319,
+#[doc(inline)]
+pub use super::interface::OperatingStatus;
+
+/// An error for any action in a [`NetMDContext`]
+#[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum ContextError {
+    #[error("there is no disc in the device")]
+    NoDisc,
+
+    #[error("the disc is write protected")]
+    WriteProtected,
+
+    #[error("the disc does not have enough space left")]
+    DiscFull,
+
+    #[error("the device is busy")]
+    DeviceBusy,
+
+    #[error("the device rejected the secure session: {0}")]
+    SessionRejected(InterfaceError),
+
+    #[cfg(feature = "native-usb")]
+    #[error("the USB connection to the device failed")]
+    Usb(cross_usb::usb::Error),
+
+    #[error("the device was disconnected")]
+    Disconnected,
+
+    #[error(transparent)]
+    Interface(InterfaceError),
+
+    #[error(transparent)]
+    Device(NetMDError),
+}
+
+impl From<InterfaceError> for ContextError {
+    fn from(error: InterfaceError) -> Self {
+        match error {
+            InterfaceError::CommunicationError(error) => error.into(),
+            error => ContextError::Interface(error),
+        }
+    }
+}
+
+impl From<NetMDError> for ContextError {
+    fn from(error: NetMDError) -> Self {
+        match error {
+            NetMDError::NotReady => ContextError::DeviceBusy,
+            #[cfg(feature = "native-usb")]
+            NetMDError::UsbError(error) => ContextError::Usb(error),
+            NetMDError::Disconnected => ContextError::Disconnected,
+            error => ContextError::Device(error),
+        }
+    }
 }
 
 /// A representation of time in the way a NetMD device uses internally.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Time {
     pub minute: u16,
     pub second: u16,
@@ -42,15 +91,17 @@ pub struct Time {
 
 /// A representation of the current status of the device.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceStatus {
     pub disc_present: bool,
-    pub state: Option<OperatingStatus>,
+    pub state: OperatingStatus,
     pub track: u8,
     pub time: Time,
 }
 
 /// Information about a single track
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Track {
     index: u16,
     title: String,
@@ -109,6 +160,7 @@ impl Track {
 
 /// Information about a single group on the disc, containing [`Track`]s
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Group {
     index: u16,
     title: Option<String>,
@@ -118,6 +170,7 @@ pub struct Group {
 
 /// Information about a MiniDisc complete with [`Track`]s, [`Group`]s, and metadata.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Disc {
     title: String,
     full_width_title: String,
@@ -146,6 +199,21 @@ impl Disc {
         tracks
     }
 
+    /// The space used on the disc, in frames
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// The space left on the disc, in frames
+    pub fn left(&self) -> u64 {
+        self.left
+    }
+
+    /// The total space on the disc, in frames
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
     fn remaining_characters_for_titles(
         &self,
         ignore_disc_titles: bool,
@@ -300,17 +368,44 @@ impl Disc {
 /// This struct wraps a [`NetMDInterface`] and allows for some higher level
 /// functions, but it is still necessary to interact with the [`NetMDInterface`]
 /// when performing many operations.
-pub struct NetMDContext {
-    interface: NetMDInterface,
+pub struct NetMDContext<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    interface: NetMDInterface<T>,
 }
 
-impl NetMDContext {
+#[cfg(feature = "native-usb")]
+impl NetMDContext<UsbTransport> {
     /// Create a new context to control a NetMD device
     pub async fn new(device: DeviceInfo) -> Result<Self, InterfaceError> {
         let interface = NetMDInterface::new(device).await?;
 
         Ok(Self { interface })
     }
+}
+
+impl<T: Transport> NetMDContext<T> {
+    /// Gets the capabilities and quirks of the device, to check which
+    /// actions it supports
+    pub fn capabilities(&self) -> &Capabilities {
+        self.interface.capabilities()
+    }
+
+    /// Get the [`NetMDLevel`] of the device
+    pub async fn net_md_level(&mut self) -> Result<NetMDLevel, InterfaceError> {
+        self.interface.net_md_level().await
+    }
+
+    /// Get the media the device supports, along with its level
+    pub async fn disc_subunit_identifier(&mut self) -> Result<SubunitIdentifier, InterfaceError> {
+        self.interface.disc_subunit_identifier().await
+    }
+
+    /// Ask the device which commands it supports, without running them
+    pub async fn probe_commands(&mut self) -> Result<CommandSupport, InterfaceError> {
+        self.interface.probe_commands().await
+    }
 
     /// Change to the next track (skip forward)
     pub async fn next_track(&mut self) -> Result<(), InterfaceError> {
@@ -328,20 +423,21 @@ impl NetMDContext {
     }
 
     /// Get the current status of the device
-    pub async fn device_status(&mut self) -> Result<DeviceStatus, Box<dyn Error>> {
+    pub async fn device_status(&mut self) -> Result<DeviceStatus, ContextError> {
         let status = self.interface.status().await?;
-        let playback_status = self.interface.playback_status2().await?;
-        let b1: u16 = playback_status[4] as u16;
-        let b2: u16 = playback_status[5] as u16;
+        let mut state = self
+            .interface
+            .playback_status2()
+            .await?
+            .operating_status()
+            .ok_or(InterfaceError::MissingField("the operating status"))?;
         let position = self.interface.position().await?;
-        let operating_status = b1 << 8 | b2;
 
         let track = position[0] as u8;
-        let disc_present = status[4] != 0x80;
-        let mut state: Option<OperatingStatus> = FromPrimitive::from_u16(operating_status);
+        let disc_present = status.disc_present();
 
-        if state == Some(OperatingStatus::Playing) && !disc_present {
-            state = Some(OperatingStatus::Ready);
+        if state == OperatingStatus::Playing && !disc_present {
+            state = OperatingStatus::Ready;
         }
 
         let time = Time {
@@ -359,31 +455,22 @@ impl NetMDContext {
     }
 
     /// Get a representation of the current disc inserted in the device.
-    pub async fn list_content(&mut self) -> Result<Disc, Box<dyn Error>> {
+    pub async fn list_content(&mut self) -> Result<Disc, ContextError> {
+        self.require_disc().await?;
+
         let flags = self.interface.disc_flags().await?;
         let title = self.interface.disc_title(false).await?;
         let full_width_title = self.interface.disc_title(true).await?;
-        let disc_capacity: [RawTime; 3] = self.interface.disc_capacity().await?;
+        let (frames_used, frames_total, frames_left) = self.capacity().await?;
         let track_count = self.interface.track_count().await?;
 
-        let mut frames_used = disc_capacity[0].as_frames();
-        let mut frames_total = disc_capacity[1].as_frames();
-        let mut frames_left = disc_capacity[2].as_frames();
-
-        // Some devices report the time remaining of the currently selected recording mode. (Sharps)
-        while frames_total > 512 * 60 * 82 {
-            frames_used /= 2;
-            frames_total /= 2;
-            frames_left /= 2;
-        }
-
         let track_group_list = self.interface.track_group_list().await?;
 
         let mut groups = vec![];
         for (index, group) in track_group_list.iter().enumerate() {
             let mut tracks = vec![];
             for track in &group.2 {
-                let (encoding, channel) = self.interface.track_encoding(*track).await.unwrap();
+                let (encoding, channel) = self.interface.track_encoding(*track).await?;
                 let duration = self.interface.track_length(*track).await?;
                 let flags = self.interface.track_flags(*track).await?;
                 let title = self.interface.track_title(*track, false).await?;
@@ -396,7 +483,8 @@ impl NetMDContext {
                     duration,
                     channel,
                     encoding,
-                    protected: TrackFlag::from_u8(flags).unwrap(),
+                    protected: TrackFlag::from_u8(flags)
+                        .ok_or(InterfaceError::InvalidTrackFlag(flags))?,
                 })
             }
 
@@ -423,7 +511,50 @@ impl NetMDContext {
         Ok(disc)
     }
 
-    pub async fn rewrite_disc_groups(&mut self, disc: Disc) -> Result<(), Box<dyn Error>> {
+    /// Get the used, total and remaining space on the disc, in frames
+    async fn capacity(&mut self) -> Result<(u64, u64, u64), ContextError> {
+        let disc_capacity: [RawTime; 3] = self.interface.disc_capacity().await?;
+
+        let mut frames_used = disc_capacity[0].as_frames();
+        let mut frames_total = disc_capacity[1].as_frames();
+        let mut frames_left = disc_capacity[2].as_frames();
+
+        // Some devices report the time remaining of the currently selected recording mode. (Sharps)
+        // No disc is longer than 82 minutes, so this is scaled back to SP
+        if self.interface.capabilities().mode_scaled_capacity {
+            while frames_total > 512 * 60 * 82 {
+                frames_used /= 2;
+                frames_total /= 2;
+                frames_left /= 2;
+            }
+        } else if frames_total > 512 * 60 * 82 {
+            warn!(
+                "The device reported a disc capacity of {} frames, which is longer than any disc",
+                frames_total
+            );
+        }
+
+        Ok((frames_used, frames_total, frames_left))
+    }
+
+    async fn require_disc(&mut self) -> Result<(), ContextError> {
+        match self.interface.status().await?.disc_present() {
+            true => Ok(()),
+            false => Err(ContextError::NoDisc),
+        }
+    }
+
+    async fn require_writable_disc(&mut self) -> Result<(), ContextError> {
+        self.require_disc().await?;
+
+        let flags = self.interface.disc_flags().await?;
+        match flags & DiscFlag::WriteProtected as u8 {
+            0 => Ok(()),
+            _ => Err(ContextError::WriteProtected),
+        }
+    }
+
+    pub async fn rewrite_disc_groups(&mut self, disc: Disc) -> Result<(), ContextError> {
         let (new_raw_title, new_raw_full_width_title) = disc.compile_disc_titles();
 
         self.interface.set_disc_title(&new_raw_title, false).await?;
@@ -439,7 +570,9 @@ impl NetMDContext {
         &mut self,
         new_name: &str,
         new_fw_name: Option<&str>,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), ContextError> {
+        self.require_writable_disc().await?;
+
         let new_name = sanitize_half_width_title(new_name);
         let new_fw_name = new_fw_name.map(sanitize_full_width_title);
 
@@ -519,7 +652,9 @@ impl NetMDContext {
         &mut self,
         track: u16,
         progress_callback: Option<F>,
-    ) -> Result<(DiscFormat, Vec<u8>), Box<dyn Error>> {
+    ) -> Result<(DiscFormat, Vec<u8>), ContextError> {
+        self.require_disc().await?;
+
         let mut output_vec = Vec::new();
         let (format, _frames, result) = self
             .interface
@@ -548,17 +683,35 @@ impl NetMDContext {
         Ok((format, header))
     }
 
-    async fn prepare_download(&mut self) -> Result<(), Box<dyn Error>> {
-        while ![OperatingStatus::DiscBlank, OperatingStatus::Ready].contains(
-            &self
-                .device_status()
-                .await?
-                .state
-                .unwrap_or(OperatingStatus::NoDisc),
-        ) {
+    /// Check the disc can hold `track` and acquire the device, before a
+    /// track is sent by [`NetMDContext::send_prepared_track`]
+    pub(super) async fn prepare_download(&mut self, track: &MDTrack) -> Result<(), ContextError> {
+        // Wait for up to 10 seconds, f. ex. while the TOC is read
+        let mut attempts = 0;
+        loop {
+            match self.device_status().await?.state {
+                OperatingStatus::DiscBlank | OperatingStatus::Ready => break,
+                OperatingStatus::NoDisc => return Err(ContextError::NoDisc),
+                _ if attempts >= 50 => return Err(ContextError::DeviceBusy),
+                _ => attempts += 1,
+            }
             cross_sleep(Duration::from_millis(200)).await;
         }
 
+        self.require_writable_disc().await?;
+
+        // Every frame holds 512 samples at 44.1kHz, and the LP modes take
+        // less space on the disc for the same time
+        let divisor = match track.data_format() {
+            WireFormat::Pcm => 1,
+            WireFormat::LP2 | WireFormat::L105kbps => 2,
+            WireFormat::LP4 => 4,
+        };
+        let frames_needed = track.frame_count() as u64 * 512 * 512 / 44100 / divisor;
+        if frames_needed > self.capacity().await?.2 {
+            return Err(ContextError::DiscFull);
+        }
+
         let _ = self.interface.session_key_forget().await;
         let _ = self.interface.leave_secure_session().await;
 
@@ -575,12 +728,12 @@ impl NetMDContext {
     /// # Downloading a track:
     /// ```no_run
     /// # tokio_test::block_on(async {
-    /// use minidisc::netmd::DEVICE_IDS_CROSSUSB;
+    /// use minidisc::netmd::device_filters;
     /// use minidisc::netmd::NetMDContext;
     /// use minidisc::netmd::interface::{MDTrack, NetMDInterface};
     ///
     /// // Get the minidisc device from cross_usb
-    /// let device = cross_usb::get_device(DEVICE_IDS_CROSSUSB.to_vec()).await.unwrap();
+    /// let device = cross_usb::get_device(device_filters()).await.unwrap();
     ///
     /// // Obtain a NetMDContext and acquire it
     /// let mut context = NetMDContext::new(device).await.unwrap();
@@ -611,9 +764,19 @@ impl NetMDContext {
         &mut self,
         track: MDTrack,
         progress_callback: F,
-    ) -> Result<(u16, Vec<u8>, Vec<u8>), Box<dyn Error>>
+    ) -> Result<(u16, Vec<u8>, Vec<u8>), ContextError>
     {
-        self.prepare_download().await?;
+        self.prepare_download(&track).await?;
+        self.send_prepared_track(track, progress_callback).await
+    }
+
+    /// Send a track once [`NetMDContext::prepare_download`] has succeeded,
+    /// in one secure session, and release the device
+    pub(super) async fn send_prepared_track<F: Fn(usize, usize)>(
+        &mut self,
+        track: MDTrack,
+        progress_callback: F,
+    ) -> Result<(u16, Vec<u8>, Vec<u8>), ContextError> {
         // Lock the interface by providing it to the session
         let mut session = MDSession::new(&mut self.interface);
         session.init().await?;
@@ -626,23 +789,30 @@ impl NetMDContext {
         Ok(result)
     }
 
+    /// Watch the device for changes, polling its status every `interval`.
+    ///
+    /// See [`StatusWatcher`] for the events which are reported.
+    pub fn watch_status(&mut self, interval: Duration) -> StatusWatcher<'_, T> {
+        StatusWatcher::new(self, interval)
+    }
+
     /// Get a reference to the underlying interface.
     ///
     /// [`NetMDContext::interface_mut()`] is almost certainly more useful
     /// in most cases.
-    pub fn interface(&self) -> &NetMDInterface {
+    pub fn interface(&self) -> &NetMDInterface<T> {
         &self.interface
     }
 
     /// Get a mutable reference to the underlying interface.
-    pub fn interface_mut(&mut self) -> &mut NetMDInterface {
+    pub fn interface_mut(&mut self) -> &mut NetMDInterface<T> {
         &mut self.interface
     }
 }
 
-impl From<NetMDInterface> for NetMDContext {
+impl<T: Transport> From<NetMDInterface<T>> for NetMDContext<T> {
     /// Create a context from an already opened interface.
-    fn from(value: NetMDInterface) -> Self {
+    fn from(value: NetMDInterface<T>) -> Self {
         Self { interface: value }
     }
 }
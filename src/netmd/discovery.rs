@@ -0,0 +1,350 @@
+//! Finding every connected NetMD device.
+//!
+//! [`cross_usb::get_device`] only returns a single device, so when several
+//! players are connected at once use [`list_devices`] and open the ones
+//! needed.
+//!
+//! Devices can't be told apart by their bus and port, or by a serial
+//! number, as [`cross_usb`] exposes neither. Two players of the same model
+//! only differ by their position in the list, which changes whenever a
+//! device is connected or disconnected, so keep the [`DiscoveredDevice`] or
+//! the opened context rather than its index.
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use minidisc::netmd::discovery::list_devices;
+//!
+//! for device in list_devices().await.unwrap() {
+//!     println!("{}: {:?}", device.index(), device.name());
+//!
+//!     let mut context = device.open().await.unwrap();
+//!     context.list_content().await.unwrap();
+//! }
+//! # })
+//! ```
+//!
+//! To find out when devices are connected and disconnected, use a
+//! [`DeviceWatcher`].
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use cross_usb::prelude::*;
+use cross_usb::DeviceInfo;
+use futures_core::Stream;
+
+use super::base::{device_filters, Capabilities, DeviceId, NetMDError};
+use super::commands::NetMDContext;
+use super::interface::{InterfaceError, NetMDInterface};
+use super::utils::cross_sleep;
+
+/// A connected NetMD device which has not been opened yet.
+///
+/// There is no bus, port or serial number to identify a device by, as
+/// [`cross_usb`] does not expose them. The only identity is its position in
+/// the list returned by [`list_devices`], which is only stable as long as no
+/// device is connected or disconnected.
+pub struct DiscoveredDevice {
+    id: DeviceId,
+    index: usize,
+    info: DeviceInfo,
+}
+
+impl DiscoveredDevice {
+    /// The USB vendor ID of the device
+    pub fn vendor_id(&self) -> u16 {
+        self.id.vendor_id()
+    }
+
+    /// The USB product ID of the device
+    pub fn product_id(&self) -> u16 {
+        self.id.product_id()
+    }
+
+    /// The model name of the device
+    pub fn name(&self) -> Option<&str> {
+        self.id.name()
+    }
+
+    /// The capabilities and quirks of the device
+    pub fn capabilities(&self) -> &Capabilities {
+        self.id.capabilities()
+    }
+
+    /// The [`DeviceId`] of the device
+    pub fn id(&self) -> &DeviceId {
+        &self.id
+    }
+
+    /// The position of the device in the list it was discovered in
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The underlying [`cross_usb`] device
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Open the device as a [`NetMDInterface`]
+    pub async fn open_interface(self) -> Result<NetMDInterface, InterfaceError> {
+        NetMDInterface::new(self.info).await
+    }
+
+    /// Open the device as a [`NetMDContext`]
+    pub async fn open(self) -> Result<NetMDContext, InterfaceError> {
+        NetMDContext::new(self.info).await
+    }
+}
+
+/// List every connected device which is known, see
+/// [`super::base::known_devices`]
+pub async fn list_devices() -> Result<Vec<DiscoveredDevice>, NetMDError> {
+    let devices = cross_usb::get_device_list(device_filters()).await?;
+
+    let mut discovered = Vec::new();
+    for (index, info) in devices.into_iter().enumerate() {
+        let id = DeviceId::lookup(info.vendor_id().await, info.product_id().await);
+
+        discovered.push(DiscoveredDevice { id, index, info });
+    }
+
+    Ok(discovered)
+}
+
+/// A change in the devices connected to the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Connected(DeviceId),
+    Disconnected(DeviceId),
+}
+
+/// Watches for NetMD devices being connected or disconnected.
+///
+/// [`cross_usb`] has no hot-plug notifications, so the connected devices are
+/// scanned periodically. Every device which is already connected is reported
+/// as [`DiscoveryEvent::Connected`] by the first scan.
+///
+/// Events only hold the [`DeviceId`], so when two players of the same model
+/// are connected it can't be told which of them was disconnected.
+///
+/// A context for a device which has been disconnected should be dropped.
+/// Commands sent to it fail with [`NetMDError::Disconnected`], or
+/// [`super::commands::ContextError::Disconnected`] from a context.
+///
+/// The watcher can also be used as a [`Stream`] with
+/// [`DeviceWatcher::into_stream`].
+///
+/// ```no_run
+/// # tokio_test::block_on(async {
+/// use std::time::Duration;
+/// use minidisc::netmd::discovery::{DeviceWatcher, DiscoveryEvent};
+///
+/// let mut watcher = DeviceWatcher::new(Duration::from_secs(1));
+/// loop {
+///     match watcher.next().await.unwrap() {
+///         DiscoveryEvent::Connected(id) => println!("connected {:?}", id.name()),
+///         DiscoveryEvent::Disconnected(id) => println!("disconnected {:?}", id.name()),
+///     }
+/// }
+/// # })
+/// ```
+pub struct DeviceWatcher {
+    interval: Duration,
+    connected: Vec<DeviceId>,
+    events: VecDeque<DiscoveryEvent>,
+    scanned: bool,
+}
+
+impl DeviceWatcher {
+    /// Create a watcher which scans for devices every `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            connected: Vec::new(),
+            events: VecDeque::new(),
+            scanned: false,
+        }
+    }
+
+    /// The devices which were connected at the last scan
+    pub fn connected(&self) -> &[DeviceId] {
+        &self.connected
+    }
+
+    /// Wait for the next device to be connected or disconnected
+    pub async fn next(&mut self) -> Result<DiscoveryEvent, NetMDError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            if self.scanned {
+                cross_sleep(self.interval).await;
+            }
+            self.scan().await?;
+        }
+    }
+
+    /// Turn the watcher into a [`Stream`] of events
+    pub fn into_stream(self) -> DeviceEvents {
+        DeviceEvents {
+            watcher: Some(self),
+            next: None,
+        }
+    }
+
+    async fn scan(&mut self) -> Result<(), NetMDError> {
+        let current = list_devices()
+            .await?
+            .into_iter()
+            .map(|device| device.id)
+            .collect();
+        self.update(current);
+
+        Ok(())
+    }
+
+    /// Queue the events for the devices found by a scan
+    fn update(&mut self, mut current: Vec<DeviceId>) {
+        current.sort();
+
+        // Several devices can have the same ID, so match them up one by one
+        let mut added = current.clone();
+        for id in &self.connected {
+            match added.iter().position(|new| new == id) {
+                Some(position) => {
+                    added.remove(position);
+                }
+                None => self
+                    .events
+                    .push_back(DiscoveryEvent::Disconnected(id.clone())),
+            }
+        }
+        self.events
+            .extend(added.into_iter().map(DiscoveryEvent::Connected));
+
+        self.connected = current;
+        self.scanned = true;
+    }
+}
+
+type NextEvent = Pin<Box<dyn Future<Output = (DeviceWatcher, Result<DiscoveryEvent, NetMDError>)>>>;
+
+/// A [`Stream`] of the events of a [`DeviceWatcher`], see
+/// [`DeviceWatcher::into_stream`].
+///
+/// The stream never ends, a failed scan is returned as an error and the
+/// next scan is tried when it is polled again.
+///
+/// The stream is not `Send`, as listing devices through WebUSB is not. The
+/// [`DeviceWatcher`] itself is, so move it to the task or thread which
+/// polls the stream and turn it into a stream there.
+pub struct DeviceEvents {
+    watcher: Option<DeviceWatcher>,
+    next: Option<NextEvent>,
+}
+
+impl DeviceEvents {
+    /// Get the watcher back, if it is not waiting for an event
+    pub fn into_inner(self) -> Option<DeviceWatcher> {
+        self.watcher
+    }
+}
+
+impl Stream for DeviceEvents {
+    type Item = Result<DiscoveryEvent, NetMDError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(mut watcher) = self.watcher.take() {
+            self.next = Some(Box::pin(async move {
+                let event = watcher.next().await;
+                (watcher, event)
+            }));
+        }
+
+        let Some(next) = self.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match next.as_mut().poll(cx) {
+            Poll::Ready((watcher, event)) => {
+                self.next = None;
+                self.watcher = Some(watcher);
+                Poll::Ready(Some(event))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(product_id: u16) -> DeviceId {
+        DeviceId::lookup(0xfff0, product_id)
+    }
+
+    fn events(watcher: &mut DeviceWatcher) -> Vec<DiscoveryEvent> {
+        watcher.events.drain(..).collect()
+    }
+
+    #[test]
+    fn first_scan_connects_everything() {
+        let mut watcher = DeviceWatcher::new(Duration::from_secs(1));
+        watcher.update(vec![id(2), id(1)]);
+
+        assert_eq!(
+            events(&mut watcher),
+            vec![
+                DiscoveryEvent::Connected(id(1)),
+                DiscoveryEvent::Connected(id(2)),
+            ]
+        );
+        assert_eq!(watcher.connected(), [id(1), id(2)]);
+    }
+
+    #[test]
+    fn connected_and_disconnected() {
+        let mut watcher = DeviceWatcher::new(Duration::from_secs(1));
+        watcher.update(vec![id(1), id(2)]);
+        events(&mut watcher);
+
+        watcher.update(vec![id(2), id(3)]);
+        assert_eq!(
+            events(&mut watcher),
+            vec![
+                DiscoveryEvent::Disconnected(id(1)),
+                DiscoveryEvent::Connected(id(3)),
+            ]
+        );
+
+        watcher.update(vec![id(3), id(2)]);
+        assert_eq!(events(&mut watcher), vec![]);
+    }
+
+    #[test]
+    fn same_model_twice() {
+        let mut watcher = DeviceWatcher::new(Duration::from_secs(1));
+        watcher.update(vec![id(1)]);
+        events(&mut watcher);
+
+        watcher.update(vec![id(1), id(1)]);
+        assert_eq!(events(&mut watcher), vec![DiscoveryEvent::Connected(id(1))]);
+
+        watcher.update(vec![id(1)]);
+        assert_eq!(
+            events(&mut watcher),
+            vec![DiscoveryEvent::Disconnected(id(1))]
+        );
+        assert_eq!(watcher.connected(), [id(1)]);
+    }
+
+    #[test]
+    fn watcher_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<DeviceWatcher>();
+    }
+}
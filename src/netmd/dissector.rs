@@ -0,0 +1,353 @@
+//! A decoder which names raw NetMD commands and replies.
+//!
+//! This works on complete messages, including the leading status byte, as
+//! they are sent to or received from a device. It is useful when reading
+//! trace logs, or the commands and replies in a [`super::capture`].
+//!
+//! ```
+//! use minidisc::netmd::dissector::dissect;
+//!
+//! let command = [0x00, 0x18, 0x08, 0x10, 0x18, 0x01, 0x01, 0x00];
+//!
+//! assert_eq!(
+//!     dissect(&command).to_string(),
+//!     "Control: descriptor (descriptor=DiscTitleTD, action=OpenRead)",
+//! );
+//! ```
+use std::fmt;
+
+use num_traits::FromPrimitive;
+
+use super::interface::{Action, Descriptor, DescriptorAction, NetmdStatus};
+
+const DESCRIPTORS: [Descriptor; 8] = [
+    Descriptor::DiscTitleTD,
+    Descriptor::AudioUTOC1TD,
+    Descriptor::AudioUTOC4TD,
+    Descriptor::Dstid,
+    Descriptor::AudioContentsTD,
+    Descriptor::RootTD,
+    Descriptor::DiscSubunitIdentifier,
+    Descriptor::OperatingStatusBlock,
+];
+
+/// The prefix of every secure session command
+pub(super) const SECURE_PREFIX: [u8; 9] = [0x18, 0x00, 0x08, 0x00, 0x46, 0xf0, 0x03, 0x01, 0x03];
+
+/// A decoded NetMD command or reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dissection {
+    /// The status byte, if it is a known status
+    pub status: Option<NetmdStatus>,
+    /// A short name for the command
+    pub name: &'static str,
+    /// The fields which could be decoded from the command
+    pub fields: Vec<(&'static str, String)>,
+    /// The raw bytes of the message
+    pub raw: Vec<u8>,
+}
+
+impl Dissection {
+    fn field<V: ToString>(&mut self, name: &'static str, value: V) {
+        self.fields.push((name, value.to_string()));
+    }
+
+    fn word_field(&mut self, name: &'static str, body: &[u8], index: usize) {
+        if let Some(value) = word(body, index) {
+            self.field(name, value);
+        }
+    }
+}
+
+impl fmt::Display for Dissection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.status, self.raw.first()) {
+            (Some(status), _) => write!(f, "{:?}: {}", status, self.name)?,
+            (None, Some(byte)) => write!(f, "status {:#04x}: {}", byte, self.name)?,
+            (None, None) => write!(f, "{}", self.name)?,
+        }
+
+        if !self.fields.is_empty() {
+            let fields: Vec<String> = self
+                .fields
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            write!(f, " ({})", fields.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a raw NetMD command or reply.
+///
+/// Anything which is not recognized is named `unknown`, so this can be used
+/// on any data without failing.
+pub fn dissect(bytes: &[u8]) -> Dissection {
+    let mut dissection = Dissection {
+        status: None,
+        name: "empty",
+        fields: Vec::new(),
+        raw: bytes.to_vec(),
+    };
+
+    let Some((&status, body)) = bytes.split_first() else {
+        return dissection;
+    };
+    dissection.status = NetmdStatus::try_from(status).ok();
+    dissection.name = "unknown";
+
+    match body {
+        [0x18, 0x08, rest @ ..] => dissect_descriptor(rest, &mut dissection),
+        [0x18, 0xc3, _, action, ..] => {
+            dissection.name = "playback control";
+            match Action::from_u8(*action) {
+                Some(action) => dissection.field("action", format!("{:?}", action)),
+                None => dissection.field("action", format!("{:#04x}", action)),
+            }
+        }
+        [0x18, 0xc5, ..] => dissection.name = "stop",
+        [0x18, 0xc1, ..] => dissection.name = "eject",
+        [0x18, 0x50, _, 0x01, ..] => {
+            dissection.name = "go to track";
+            dissection.word_field("track", body, 8);
+        }
+        [0x18, 0x50, _, 0x00, ..] => {
+            dissection.name = "go to time";
+            dissection.word_field("track", body, 8);
+        }
+        [0x18, 0x50, _, 0x10, ..] => {
+            dissection.name = "track change";
+            if let Some(direction) = word(body, 8) {
+                dissection.field("direction", format!("{:#06x}", direction));
+            }
+        }
+        [0x18, 0x09, 0x80, 0x01, ..] => dissection.name = "status block read",
+        [0x18, 0x09, 0x00, ..] => dissection.name = "subunit identifier read",
+        [0x18, 0x06, rest @ ..] => dissect_read(rest, &mut dissection),
+        [0x18, 0x07, rest @ ..] => dissect_write(rest, &mut dissection),
+        [0x18, 0x40, _, 0x01, ..] => {
+            dissection.name = "erase track";
+            dissection.word_field("track", body, 8);
+        }
+        [0x18, 0x40, ..] => dissection.name = "erase disc",
+        [0x18, 0x43, ..] => {
+            dissection.name = "move track";
+            dissection.word_field("source", body, 8);
+            dissection.word_field("destination", body, 13);
+        }
+        body if body.starts_with(&SECURE_PREFIX) => {
+            dissect_secure(&body[SECURE_PREFIX.len()..], &mut dissection)
+        }
+        [0xff, 0x01, 0x0c, ..] => dissection.name = "acquire",
+        [0xff, 0x01, 0x00, ..] => dissection.name = "release",
+        _ => (),
+    }
+
+    dissection
+}
+
+fn dissect_descriptor(rest: &[u8], dissection: &mut Dissection) {
+    dissection.name = "descriptor";
+
+    let Some(descriptor) = DESCRIPTORS
+        .iter()
+        .find(|descriptor| rest.starts_with(&descriptor.get_array()))
+    else {
+        return;
+    };
+    dissection.field("descriptor", format!("{:?}", descriptor));
+
+    let action = rest.get(descriptor.get_array().len());
+    match action.and_then(|action| DescriptorAction::from_u8(*action)) {
+        Some(action) => dissection.field("action", format!("{:?}", action)),
+        None => {
+            if let Some(action) = action {
+                dissection.field("action", format!("{:#04x}", action))
+            }
+        }
+    }
+}
+
+fn dissect_read(rest: &[u8], dissection: &mut Dissection) {
+    dissection.name = "read";
+
+    match rest {
+        [0x02, 0x20, 0x18, 0x01, _, kind, ..] => {
+            dissection.name = "read disc title";
+            dissection.field("full_width", *kind == 0x01);
+        }
+        [0x02, 0x20, 0x18, kind, ..] => {
+            dissection.name = "read track title";
+            dissection.field("full_width", *kind == 0x03);
+            dissection.word_field("track", rest, 4);
+        }
+        [0x02, 0x20, 0x10, 0x01, ..] => {
+            dissection.name = "read track info";
+            dissection.word_field("track", rest, 4);
+        }
+        [0x01, 0x10, 0x10, 0x00, ..] => dissection.name = "read disc flags",
+        [0x02, 0x10, 0x10, 0x01, ..] => dissection.name = "read track count",
+        [0x02, 0x10, 0x10, 0x00, ..] => dissection.name = "read disc capacity",
+        [0x01, 0x20, 0x10, 0x01, ..] => {
+            dissection.name = "read track flags";
+            dissection.word_field("track", rest, 4);
+        }
+        _ => (),
+    }
+}
+
+fn dissect_write(rest: &[u8], dissection: &mut Dissection) {
+    dissection.name = "write";
+
+    match rest {
+        [0x02, 0x20, 0x18, 0x01, _, kind, ..] => {
+            dissection.name = "write disc title";
+            dissection.field("full_width", *kind == 0x01);
+        }
+        [0x02, 0x20, 0x18, kind, ..] => {
+            dissection.name = "write track title";
+            dissection.field("full_width", *kind == 0x03);
+            dissection.word_field("track", rest, 4);
+        }
+        _ => (),
+    }
+}
+
+fn dissect_secure(rest: &[u8], dissection: &mut Dissection) {
+    let Some(command) = rest.first() else {
+        dissection.name = "secure";
+        return;
+    };
+
+    dissection.name = match command {
+        0x11 => "secure: leaf id",
+        0x12 => "secure: send key data",
+        0x20 => "secure: session key exchange",
+        0x21 => "secure: session key forget",
+        0x22 => "secure: setup download",
+        0x23 => "secure: get track uuid",
+        0x28 => "secure: send track",
+        0x2a => "secure: terminate",
+        0x2b => "secure: disable new track protection",
+        0x30 => "secure: save track",
+        0x48 => "secure: commit track",
+        0x80 => "secure: enter session",
+        0x81 => "secure: leave session",
+        _ => "secure: unknown",
+    };
+
+    if *command == 0x28 {
+        if let (Some(wire_format), Some(disc_format)) = (rest.get(10), rest.get(11)) {
+            dissection.field("wire_format", format!("{:#04x}", wire_format));
+            dissection.field("disc_format", format!("{:#04x}", disc_format));
+        }
+    }
+}
+
+fn word(bytes: &[u8], index: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *bytes.get(index)?,
+        *bytes.get(index + 1)?,
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A control command in the secure session, as sent by the interface
+    fn secure(command: &[u8]) -> Vec<u8> {
+        [&[0x00][..], &SECURE_PREFIX[..], command].concat()
+    }
+
+    #[test]
+    fn secure_commands() {
+        let commands: [(&[u8], &str); 13] = [
+            (&[0x11, 0xff], "secure: leaf id"),
+            (
+                &[0x12, 0xff, 0x00, 0x6c, 0x00, 0x00],
+                "secure: send key data",
+            ),
+            (
+                &[0x20, 0xff, 0x00, 0x00, 0x00, 0x01, 0x02],
+                "secure: session key exchange",
+            ),
+            (
+                &[0x21, 0xff, 0x00, 0x00, 0x00],
+                "secure: session key forget",
+            ),
+            (
+                &[0x22, 0xff, 0x00, 0x00, 0x01, 0x02],
+                "secure: setup download",
+            ),
+            (
+                &[0x23, 0xff, 0x10, 0x01, 0x00, 0x03],
+                "secure: get track uuid",
+            ),
+            (
+                &[
+                    0x28, 0xff, 0x00, 0x01, 0x00, 0x10, 0x01, 0xff, 0xff, 0x00, 0x94, 0x06,
+                ],
+                "secure: send track",
+            ),
+            (&[0x2a, 0xff, 0x00], "secure: terminate"),
+            (
+                &[0x2b, 0xff, 0x00, 0x01],
+                "secure: disable new track protection",
+            ),
+            (
+                &[0x30, 0xff, 0x00, 0x10, 0x01, 0x00, 0x03],
+                "secure: save track",
+            ),
+            (
+                &[0x48, 0xff, 0x00, 0x10, 0x01, 0x00, 0x03],
+                "secure: commit track",
+            ),
+            (&[0x80, 0xff], "secure: enter session"),
+            (&[0x81, 0xff], "secure: leave session"),
+        ];
+
+        for (command, name) in commands {
+            let dissection = dissect(&secure(command));
+            assert_eq!(dissection.status, Some(NetmdStatus::Control));
+            assert_eq!(dissection.name, name, "{:02x?}", command);
+        }
+    }
+
+    #[test]
+    fn send_track_formats() {
+        let command = [
+            0x28, 0xff, 0x00, 0x01, 0x00, 0x10, 0x01, 0xff, 0xff, 0x00, 0x94, 0x06,
+        ];
+        let dissection = dissect(&secure(&command));
+
+        assert_eq!(
+            dissection.fields,
+            vec![
+                ("wire_format", "0x94".to_string()),
+                ("disc_format", "0x06".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn secure_reply() {
+        let reply = [&[0x09][..], &SECURE_PREFIX[..], &[0x48, 0x00]].concat();
+        let dissection = dissect(&reply);
+
+        assert_eq!(dissection.status, Some(NetmdStatus::Accepted));
+        assert_eq!(dissection.name, "secure: commit track");
+    }
+
+    #[test]
+    fn unknown_data() {
+        assert_eq!(dissect(&[]).name, "empty");
+        assert_eq!(dissect(&[0x00, 0x99]).name, "unknown");
+        assert_eq!(
+            dissect(&[0x07, 0x18, 0xc5]).to_string(),
+            "status 0x07: stop"
+        );
+    }
+}
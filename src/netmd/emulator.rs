@@ -0,0 +1,1349 @@
+//! An in-process emulation of a NetMD player.
+//!
+//! [`VirtualPlayer`] is a [`Transport`] which answers the commands sent by
+//! [`super::NetMDInterface`] from an in-memory [`VirtualDisc`], so the higher
+//! level interfaces can be used without a physical device attached.
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use minidisc::netmd::emulator::{VirtualDisc, VirtualPlayer};
+//! use minidisc::netmd::{NetMD, NetMDContext, NetMDInterface};
+//!
+//! let player = VirtualPlayer::new(VirtualDisc::default());
+//! let device = NetMD::new(player).unwrap();
+//! let mut context = NetMDContext::from(NetMDInterface::from(device));
+//!
+//! context.rename_disc("My Disc", None).await.unwrap();
+//! # })
+//! ```
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use cbc::cipher::block_padding::NoPadding;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit};
+use encoding_rs::SHIFT_JIS;
+use rand::RngCore;
+
+use super::base::NetMDError;
+use super::commands::OperatingStatus;
+use super::interface::{Channels, DescriptorAction, Encoding, NetMDLevel, NetmdStatus};
+use super::protocol::{retailmac, EKBOpenSource};
+use super::transport::Transport;
+use super::utils::int_to_bcd;
+
+type DesEcbEnc = ecb::Encryptor<des::Des>;
+type DesCbcEnc = cbc::Encryptor<des::Des>;
+type DesCbcDec = cbc::Decryptor<des::Des>;
+
+/// The prefix of every secure session (`1800 080046 f0030103`) command
+const SECURE_PREFIX: [u8; 9] = [0x18, 0x00, 0x08, 0x00, 0x46, 0xf0, 0x03, 0x01, 0x03];
+
+/// The disc subunit identifier of an editing (level 3) MiniDisc device
+#[rustfmt::skip]
+const SUBUNIT_IDENTIFIER: [u8; 36] = [
+    0x18, 0x09, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+    0x00, 0x19, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, // lengths and sizes
+    0x00, 0x01, // root object list
+    0x00, 0x0f, 0x00, 0x0b, 0x00, 0x10, // subunit fields
+    0x01, 0x03, 0x01, 0x70, 0x00, 0x00, 0x02, 0x01, 0x00, // media
+    0x00, 0x00, // manufacturer data
+];
+
+/// The offset of the implementation profile ID of the MiniDisc audio media
+/// in [`SUBUNIT_IDENTIFIER`], which is the NetMD level
+const LEVEL_OFFSET: usize = 28;
+
+/// The leaf ID reported by the virtual player
+const LEAF_ID: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+/// The length of a single sound frame on the wire (512 samples)
+const FRAME_DURATION: Duration = Duration::from_micros(11610);
+
+/// A track stored on a [`VirtualDisc`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualTrack {
+    pub title: String,
+    pub full_width_title: String,
+    pub encoding: Encoding,
+    pub channels: Channels,
+    pub duration: Duration,
+    pub protected: bool,
+    /// The decrypted audio data of the track
+    pub data: Vec<u8>,
+    uuid: [u8; 8],
+}
+
+impl VirtualTrack {
+    /// Create a new track with no audio data
+    pub fn new(title: &str, encoding: Encoding, duration: Duration) -> Self {
+        Self {
+            title: title.to_string(),
+            full_width_title: String::new(),
+            encoding,
+            channels: Channels::Stereo,
+            duration,
+            protected: false,
+            data: Vec::new(),
+            uuid: random_bytes(),
+        }
+    }
+}
+
+/// The contents of a disc inserted in a [`VirtualPlayer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualDisc {
+    /// The raw disc title, including any group information
+    pub title: String,
+    /// The raw full width disc title, including any group information
+    pub full_width_title: String,
+    pub tracks: Vec<VirtualTrack>,
+    pub write_protected: bool,
+    /// The total recording time of the disc in SP
+    pub capacity: Duration,
+}
+
+impl Default for VirtualDisc {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            full_width_title: String::new(),
+            tracks: Vec::new(),
+            write_protected: false,
+            capacity: Duration::from_secs(80 * 60),
+        }
+    }
+}
+
+impl VirtualDisc {
+    fn used(&self) -> Duration {
+        self.tracks.iter().map(|t| t.duration).sum()
+    }
+}
+
+/// A track transfer which is waiting on bulk data
+struct Transfer {
+    wire_format: u8,
+    disc_format: u8,
+    frames: u32,
+    total_bytes: usize,
+    received: Vec<u8>,
+}
+
+/// A failure injected with [`VirtualPlayer::fail_next`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer the command with this status instead of running it
+    Status(NetmdStatus),
+    /// Fail the command and everything after it, as if the player had been
+    /// unplugged
+    Disconnect,
+}
+
+/// An emulated NetMD player holding a [`VirtualDisc`].
+pub struct VirtualPlayer {
+    vendor_id: u16,
+    product_id: u16,
+    disc: Option<VirtualDisc>,
+    state: OperatingStatus,
+    current_track: u16,
+    replies: VecDeque<Vec<u8>>,
+    next_reply: Option<Vec<u8>>,
+    bulk_out: VecDeque<u8>,
+    transfer: Option<Transfer>,
+    session_key: Option<Vec<u8>>,
+    device_nonce: Option<Vec<u8>>,
+    kek: Option<[u8; 8]>,
+    faults: Vec<(Vec<u8>, Fault)>,
+    disconnected: bool,
+    open_descriptors: Vec<Vec<u8>>,
+    level: NetMDLevel,
+    capacity_flag: u8,
+    notify: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl VirtualPlayer {
+    /// Create a new virtual player with a disc inserted.
+    ///
+    /// The player identifies itself as a Sony MZ-RH1.
+    pub fn new(disc: VirtualDisc) -> Self {
+        Self {
+            vendor_id: 0x054c,
+            product_id: 0x0286,
+            disc: Some(disc),
+            state: OperatingStatus::Ready,
+            current_track: 0,
+            replies: VecDeque::new(),
+            next_reply: None,
+            bulk_out: VecDeque::new(),
+            transfer: None,
+            session_key: None,
+            device_nonce: None,
+            kek: None,
+            faults: Vec::new(),
+            disconnected: false,
+            open_descriptors: Vec::new(),
+            level: NetMDLevel::Level3,
+            capacity_flag: 0x80,
+            notify: None,
+        }
+    }
+
+    /// Change the USB IDs the player reports
+    pub fn with_ids(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.vendor_id = vendor_id;
+        self.product_id = product_id;
+        self
+    }
+
+    /// Report this level in the disc subunit identifier, instead of
+    /// [`NetMDLevel::Level3`]
+    pub fn with_level(mut self, level: NetMDLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Send this byte before the times in the disc capacity reply, instead
+    /// of `0x80`
+    pub fn with_capacity_flag(mut self, flag: u8) -> Self {
+        self.capacity_flag = flag;
+        self
+    }
+
+    /// Answer the session key exchange with this nonce, instead of a random
+    /// one. The nonce is sent as is, even if it isn't 8 bytes long.
+    pub fn with_device_nonce(mut self, nonce: &[u8]) -> Self {
+        self.device_nonce = Some(nonce.to_vec());
+        self
+    }
+
+    /// Fail the next command which starts with `prefix`, not counting its
+    /// status byte
+    pub fn fail_next(&mut self, prefix: &[u8], fault: Fault) {
+        self.faults.push((prefix.to_vec(), fault));
+    }
+
+    /// Fail every transfer from now on, as if the player had been unplugged
+    pub fn disconnect(&mut self) {
+        self.disconnected = true;
+    }
+
+    /// Get the IDs of the descriptors which are open, f. ex.
+    /// `[0x10, 0x18, 0x01]`
+    pub fn open_descriptors(&self) -> &[Vec<u8>] {
+        &self.open_descriptors
+    }
+
+    /// Get the disc currently in the player
+    pub fn disc(&self) -> Option<&VirtualDisc> {
+        self.disc.as_ref()
+    }
+
+    /// Get a mutable reference to the disc currently in the player
+    pub fn disc_mut(&mut self) -> Option<&mut VirtualDisc> {
+        self.disc.as_mut()
+    }
+
+    /// Insert a disc, returning the one which was previously inserted
+    pub fn insert_disc(&mut self, disc: VirtualDisc) -> Option<VirtualDisc> {
+        self.state = OperatingStatus::Ready;
+        self.disc.replace(disc)
+    }
+
+    /// Eject the disc, returning it
+    pub fn eject_disc(&mut self) -> Option<VirtualDisc> {
+        self.state = OperatingStatus::Ready;
+        self.disc.take()
+    }
+
+    fn operating_status(&self) -> u16 {
+        match &self.disc {
+            None => OperatingStatus::NoDisc.into(),
+            Some(disc) if disc.tracks.is_empty() => OperatingStatus::DiscBlank.into(),
+            Some(_) => self.state.into(),
+        }
+    }
+
+    fn tracks(&self) -> &[VirtualTrack] {
+        self.disc
+            .as_ref()
+            .map(|d| d.tracks.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn track(&self, index: u16) -> Option<&VirtualTrack> {
+        self.tracks().get(index as usize)
+    }
+
+    fn writable_disc(&mut self) -> Option<&mut VirtualDisc> {
+        self.disc.as_mut().filter(|d| !d.write_protected)
+    }
+
+    /// Handle a command, queueing the reply to be read
+    fn handle(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        let Some((&status, body)) = command.split_first() else {
+            return Err(NetMDError::InvalidResult);
+        };
+        // Any command replaces a registered notify
+        self.notify = None;
+
+        let fault = self
+            .faults
+            .iter()
+            .position(|(prefix, _)| body.starts_with(prefix))
+            .map(|index| self.faults.remove(index).1);
+
+        let (status, reply) = if let Some(fault) = fault {
+            match fault {
+                Fault::Status(status) => (status, body.to_vec()),
+                Fault::Disconnect => {
+                    self.disconnected = true;
+                    return Err(NetMDError::Disconnected);
+                }
+            }
+        } else if status == NetmdStatus::Notify as u8 {
+            self.register_notify(body)
+        } else if status == NetmdStatus::GeneralInquiry as u8 {
+            match self.understands(body) {
+                true => (NetmdStatus::Implemented, body.to_vec()),
+                false => (NetmdStatus::NotImplemented, body.to_vec()),
+            }
+        } else {
+            self.control(body)
+                .unwrap_or((NetmdStatus::NotImplemented, body.to_vec()))
+        };
+
+        self.push_reply(status, reply);
+        if let Some(next_reply) = self.next_reply.take() {
+            self.replies.push_back(next_reply);
+        }
+
+        Ok(())
+    }
+
+    /// Answer a notify with the current state as an interim reply, and
+    /// remember it until the state changes or another command is sent
+    fn register_notify(&mut self, body: &[u8]) -> (NetmdStatus, Vec<u8>) {
+        match self.control(body) {
+            Some((NetmdStatus::Accepted, reply)) => {
+                self.notify = Some((body.to_vec(), reply.clone()));
+                (NetmdStatus::Interim, reply)
+            }
+            Some((status, reply)) => (status, reply),
+            None => (NetmdStatus::NotImplemented, body.to_vec()),
+        }
+    }
+
+    /// Queue the changed reply to a registered notify, once the state it
+    /// reads is different from the interim reply
+    fn check_notify(&mut self) {
+        let Some((command, interim)) = self.notify.take() else {
+            return;
+        };
+
+        match self.control(&command) {
+            Some((NetmdStatus::Accepted, reply)) if reply != interim => {
+                self.push_reply(NetmdStatus::Changed, reply)
+            }
+            _ => self.notify = Some((command, interim)),
+        }
+    }
+
+    fn connected(&self) -> Result<(), NetMDError> {
+        match self.disconnected {
+            true => Err(NetMDError::Disconnected),
+            false => Ok(()),
+        }
+    }
+
+    fn push_reply(&mut self, status: NetmdStatus, body: Vec<u8>) {
+        self.replies.push_back([vec![status as u8], body].concat());
+    }
+
+    /// Check if a command belongs to a family the player knows about
+    fn understands(&self, body: &[u8]) -> bool {
+        matches!(
+            body,
+            [0x18, 0x08, ..]
+                | [0x18, 0x09, ..]
+                | [0x18, 0xc1, ..]
+                | [0x18, 0xc3, ..]
+                | [0x18, 0xc5, ..]
+                | [0x18, 0x50, ..]
+                | [0x18, 0x40, ..]
+                | [0x18, 0x43, ..]
+                | [0x18, 0x06, ..]
+                | [0x18, 0x07, ..]
+                | [0xff, 0x01, ..]
+        ) || body.starts_with(&SECURE_PREFIX)
+    }
+
+    /// Perform a control command, returning the status and body of the reply
+    fn control(&mut self, body: &[u8]) -> Option<(NetmdStatus, Vec<u8>)> {
+        let accepted = |reply: Vec<u8>| Some((NetmdStatus::Accepted, reply));
+
+        match body {
+            // Descriptor open/close
+            [0x18, 0x08, descriptor @ .., action, 0x00] => {
+                self.open_descriptors.retain(|open| open != descriptor);
+                if *action != DescriptorAction::Close as u8 {
+                    self.open_descriptors.push(descriptor.to_vec());
+                }
+                accepted(body.to_vec())
+            }
+
+            // Operating status block reads
+            [0x18, 0x09, 0x80, 0x01, ..] => self.status_block(body),
+
+            // Disc subunit identifier
+            [0x18, 0x09, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00] => {
+                let mut identifier = SUBUNIT_IDENTIFIER.to_vec();
+                identifier[LEVEL_OFFSET] = self.level as u8;
+                accepted(identifier)
+            }
+
+            // Acquire and release
+            [0xff, 0x01, 0x0c | 0x00, ..] => accepted(body.to_vec()),
+
+            // Playback control
+            [0x18, 0xc3, 0xff, action, 0x00, 0x00, 0x00] => {
+                self.state = match action {
+                    0x75 => OperatingStatus::Playing,
+                    0x7d => OperatingStatus::Paused,
+                    0x39 => OperatingStatus::FastForward,
+                    0x49 => OperatingStatus::Rewind,
+                    _ => return None,
+                };
+                accepted(vec![0x18, 0xc3, 0x00, *action, 0x00, 0x00, 0x00])
+            }
+            [0x18, 0xc5, 0xff, 0x00, 0x00, 0x00, 0x00] => {
+                self.state = OperatingStatus::Ready;
+                accepted(vec![0x18, 0xc5, 0x00, 0x00, 0x00, 0x00, 0x00])
+            }
+            [0x18, 0xc1, 0xff, 0x60, 0x00] => {
+                self.eject_disc();
+                accepted(vec![0x18, 0xc1, 0x00, 0x60, 0x00])
+            }
+
+            // Track and time seeking
+            [0x18, 0x50, 0xff, kind @ (0x00 | 0x01 | 0x10), rest @ ..] => {
+                let value = u16::from_be_bytes([*rest.get(4)?, *rest.get(5)?]);
+                self.current_track = match kind {
+                    0x10 => match value {
+                        0x8001 => self.current_track.saturating_add(1),
+                        0x0002 => self.current_track.saturating_sub(1),
+                        _ => self.current_track,
+                    },
+                    _ => value,
+                };
+                let max_track = self.tracks().len().saturating_sub(1) as u16;
+                self.current_track = self.current_track.min(max_track);
+
+                let mut reply = body.to_vec();
+                reply[2] = 0x00;
+                if *kind == 0x10 {
+                    reply[8..10].copy_from_slice(&self.current_track.to_be_bytes());
+                }
+                accepted(reply)
+            }
+
+            // Erasing
+            [0x18, 0x40, 0xff, 0x00, 0x00] => {
+                self.writable_disc()?.tracks.clear();
+                accepted(vec![0x18, 0x40, 0x00, 0x00, 0x00])
+            }
+            [0x18, 0x40, 0xff, 0x01, 0x00, 0x20, 0x10, 0x01, hi, lo] => {
+                let track = u16::from_be_bytes([*hi, *lo]) as usize;
+                let disc = self.writable_disc()?;
+                if track >= disc.tracks.len() {
+                    return Some((NetmdStatus::Rejected, body.to_vec()));
+                }
+                disc.tracks.remove(track);
+                accepted(vec![
+                    0x18, 0x40, 0x00, 0x01, 0x00, 0x20, 0x10, 0x01, *hi, *lo,
+                ])
+            }
+
+            // Moving
+            [0x18, 0x43, 0xff, 0x00, 0x00, 0x20, 0x10, 0x01, s_hi, s_lo, 0x20, 0x10, 0x01, d_hi, d_lo] =>
+            {
+                let source = u16::from_be_bytes([*s_hi, *s_lo]) as usize;
+                let dest = u16::from_be_bytes([*d_hi, *d_lo]) as usize;
+                let disc = self.writable_disc()?;
+                if source >= disc.tracks.len() || dest >= disc.tracks.len() {
+                    return Some((NetmdStatus::Rejected, body.to_vec()));
+                }
+                let track = disc.tracks.remove(source);
+                disc.tracks.insert(dest, track);
+
+                let mut reply = body.to_vec();
+                reply[2] = 0x00;
+                accepted(reply)
+            }
+
+            // Descriptor reads and writes
+            [0x18, 0x06, ..] => self.read_descriptor(body),
+            [0x18, 0x07, ..] => self.write_title(body),
+
+            _ if body.starts_with(&SECURE_PREFIX) => {
+                let (&subcommand, rest) = body[SECURE_PREFIX.len()..].split_first()?;
+                let (status, payload) = self.secure(subcommand, rest)?;
+                Some((
+                    status,
+                    [&SECURE_PREFIX[..], &[subcommand][..], &payload[..]].concat(),
+                ))
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Answer reads from the operating status block (`1809 8001`)
+    fn status_block(&mut self, body: &[u8]) -> Option<(NetmdStatus, Vec<u8>)> {
+        const STATUS: [u8; 13] = [
+            0x18, 0x09, 0x80, 0x01, 0x02, 0x30, 0x88, 0x00, 0x00, 0x30, 0x88, 0x04, 0x00,
+        ];
+        const POSITION: [u8; 21] = [
+            0x18, 0x09, 0x80, 0x01, 0x04, 0x30, 0x88, 0x02, 0x00, 0x30, 0x88, 0x05, 0x00, 0x30,
+            0x00, 0x03, 0x00, 0x30, 0x00, 0x02, 0x00,
+        ];
+
+        let mut reply;
+        if body.starts_with(&STATUS) {
+            let disc_state = match self.disc {
+                Some(_) => 0x40,
+                None => 0x80,
+            };
+
+            reply = STATUS.to_vec();
+            reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x09, 0x00, 0x00]);
+            reply.extend(with_length(&[
+                0x88, 0x04, 0x00, 0x03, disc_state, 0x00, 0x00,
+            ]));
+        } else if body.starts_with(&POSITION) {
+            reply = POSITION.to_vec();
+            reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x10, 0x00, 0x00]);
+            reply.extend_from_slice(&[0x00, 0x0b, 0x00, 0x02, 0x00, 0x07, 0x00]);
+            reply.extend_from_slice(&self.current_track.to_be_bytes());
+            reply.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        } else if let [0x18, 0x09, 0x80, 0x01, 0x03, 0x30, p1_hi, p1_lo, 0x00, 0x30, 0x88, 0x05, 0x00, 0x30, p2_hi, p2_lo, 0x00, ..] =
+            body
+        {
+            reply = body[..17].to_vec();
+            reply.extend_from_slice(&[0x10, 0x00]);
+
+            if [*p1_hi, *p1_lo, *p2_hi, *p2_lo] == [0x88, 0x01, 0x88, 0x07] {
+                // Recording parameters
+                reply.extend_from_slice(&[0x00, 0x0e, 0x00, 0x00, 0x00, 0x0c]);
+                reply.extend_from_slice(&[0x88, 0x05, 0x00, 0x08, 0x80, 0xe0, 0x01, 0x10]);
+                reply.extend_from_slice(&[Encoding::SP as u8, Channels::Stereo as u8, 0x40, 0x00]);
+            } else {
+                let [status_hi, status_lo] = self.operating_status().to_be_bytes();
+                reply.extend_from_slice(&[0x00, 0x08, 0x00, 0x00]);
+                reply.extend(with_length(&[
+                    *p2_hi, *p2_lo, 0x00, 0x02, status_hi, status_lo,
+                ]));
+                reply.push(0x00);
+            }
+        } else {
+            return None;
+        }
+
+        Some((NetmdStatus::Accepted, reply))
+    }
+
+    /// Answer reads from the disc and track descriptors (`1806`)
+    fn read_descriptor(&mut self, body: &[u8]) -> Option<(NetmdStatus, Vec<u8>)> {
+        self.disc.as_ref()?;
+
+        let mut reply;
+        match body {
+            // Disc flags
+            [0x18, 0x06, 0x01, 0x10, 0x10, 0x00, 0xff, 0x00, 0x00, 0x01, 0x00, 0x0b] => {
+                let mut flags = 0x10;
+                if self.disc.as_ref()?.write_protected {
+                    flags |= 0x40;
+                }
+                reply = body.to_vec();
+                reply[6] = 0x10;
+                reply.push(flags);
+            }
+
+            // Track count
+            [0x18, 0x06, 0x02, 0x10, 0x10, 0x01, 0x30, 0x00, 0x10, 0x00, 0xff, 0x00, ..] => {
+                reply = body[..10].to_vec();
+                reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x0b, 0x00, 0x00]);
+                reply.extend_from_slice(&[0x00, 0x06, 0x00, 0x10, 0x00, 0x02, 0x00]);
+                reply.push(self.tracks().len() as u8);
+            }
+
+            // Disc capacity
+            [0x18, 0x06, 0x02, 0x10, 0x10, 0x00, 0x30, 0x80, 0x03, 0x00, 0xff, 0x00, ..] => {
+                let disc = self.disc.as_ref()?;
+                let total = disc.capacity;
+                let used = disc.used().min(total);
+
+                reply = body[..10].to_vec();
+                reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x1d, 0x00, 0x00]);
+                reply.extend_from_slice(&[
+                    0x00,
+                    0x1b,
+                    self.capacity_flag,
+                    0x03,
+                    0x00,
+                    0x17,
+                    0x80,
+                    0x00,
+                ]);
+                for time in [used, total, total - used] {
+                    reply.extend_from_slice(&[0x00, 0x05]);
+                    reply.extend(bcd_time(time, true));
+                }
+            }
+
+            // Disc title
+            [0x18, 0x06, 0x02, 0x20, 0x18, 0x01, 0x00, wchar, 0x30, 0x00, 0x0a, 0x00, 0xff, 0x00, _, _, done_hi, done_lo] =>
+            {
+                let disc = self.disc.as_ref()?;
+                let title = match wchar {
+                    0 => &disc.title,
+                    _ => &disc.full_width_title,
+                };
+                let encoded = SHIFT_JIS.encode(title).0;
+                let done = (u16::from_be_bytes([*done_hi, *done_lo]) as usize).min(encoded.len());
+                let chunk = &encoded[done..];
+
+                reply = body[..12].to_vec();
+                reply.extend_from_slice(&[0x10, 0x00]);
+                if done == 0 {
+                    reply.extend_from_slice(&(chunk.len() as u16 + 6).to_be_bytes());
+                    reply.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x0a]);
+                    reply.extend_from_slice(&(encoded.len() as u16).to_be_bytes());
+                } else {
+                    reply.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                    reply.extend_from_slice(&[0x00, 0x00]);
+                }
+                reply.extend_from_slice(chunk);
+            }
+
+            // Track title
+            [0x18, 0x06, 0x02, 0x20, 0x18, wchar @ (0x02 | 0x03), hi, lo, 0x30, 0x00, 0x0a, 0x00, 0xff, 0x00, ..] =>
+            {
+                let Some(track) = self.track(u16::from_be_bytes([*hi, *lo])) else {
+                    return Some((NetmdStatus::Rejected, body.to_vec()));
+                };
+                let title = match wchar {
+                    0x02 => &track.title,
+                    _ => &track.full_width_title,
+                };
+                let encoded = SHIFT_JIS.encode(title).0;
+
+                reply = body[..12].to_vec();
+                reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+                reply.extend_from_slice(&[0x00, 0x00, 0x00, 0x0a]);
+                reply.extend(with_length(&encoded));
+            }
+
+            // Track information
+            [0x18, 0x06, 0x02, 0x20, 0x10, 0x01, hi, lo, p1_hi, p1_lo, p2_hi, p2_lo, 0xff, 0x00, ..] =>
+            {
+                let Some(track) = self.track(u16::from_be_bytes([*hi, *lo])) else {
+                    return Some((NetmdStatus::Rejected, body.to_vec()));
+                };
+                // Like every scanned reply, the information starts with a
+                // byte that isn't part of the data
+                let info = match [*p1_hi, *p1_lo, *p2_hi, *p2_lo] {
+                    [0x30, 0x00, 0x01, 0x00] => [
+                        vec![0x00, 0x01, 0x00, 0x06, 0x00, 0x00],
+                        bcd_time(track.duration, false),
+                    ]
+                    .concat(),
+                    [0x30, 0x80, 0x07, 0x00] => vec![
+                        0x00,
+                        0x07,
+                        0x00,
+                        0x04,
+                        0x01,
+                        0x10,
+                        track.encoding as u8,
+                        track.channels as u8,
+                    ],
+                    _ => return None,
+                };
+
+                reply = body[..12].to_vec();
+                reply.extend_from_slice(&[0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+                reply.extend(with_length(&info));
+            }
+
+            // Track flags
+            [0x18, 0x06, 0x01, 0x20, 0x10, 0x01, hi, lo, 0xff, 0x00, 0x00, 0x01, 0x00, 0x08] => {
+                let Some(track) = self.track(u16::from_be_bytes([*hi, *lo])) else {
+                    return Some((NetmdStatus::Rejected, body.to_vec()));
+                };
+                let flags = match track.protected {
+                    true => 0x03,
+                    false => 0x00,
+                };
+
+                reply = body.to_vec();
+                reply[8] = 0x10;
+                reply.push(flags);
+            }
+
+            _ => return None,
+        }
+
+        Some((NetmdStatus::Accepted, reply))
+    }
+
+    /// Answer title writes (`1807`)
+    fn write_title(&mut self, body: &[u8]) -> Option<(NetmdStatus, Vec<u8>)> {
+        let [0x18, 0x07, 0x02, 0x20, 0x18, kind, hi, lo, 0x30, 0x00, 0x0a, 0x00, 0x50, 0x00, _, _, 0x00, 0x00, _, _, title @ ..] =
+            body
+        else {
+            return None;
+        };
+        let title = SHIFT_JIS.decode(title).0.into_owned();
+        let disc = self.writable_disc()?;
+
+        match (kind, hi, lo) {
+            (0x01, 0x00, wchar) => match wchar {
+                0 => disc.title = title,
+                _ => disc.full_width_title = title,
+            },
+            (0x02 | 0x03, _, _) => {
+                let Some(track) = disc.tracks.get_mut(u16::from_be_bytes([*hi, *lo]) as usize)
+                else {
+                    return Some((NetmdStatus::Rejected, body[..20].to_vec()));
+                };
+                match kind {
+                    0x02 => track.title = title,
+                    _ => track.full_width_title = title,
+                }
+            }
+            _ => return None,
+        }
+
+        Some((NetmdStatus::Accepted, body[..20].to_vec()))
+    }
+
+    /// Answer secure session commands (`1800 080046 f0030103`)
+    fn secure(&mut self, subcommand: u8, rest: &[u8]) -> Option<(NetmdStatus, Vec<u8>)> {
+        let accepted = |payload: Vec<u8>| Some((NetmdStatus::Accepted, payload));
+
+        match (subcommand, rest) {
+            // Enter and leave the secure session
+            (0x80 | 0x81, [0xff]) => accepted(vec![0x00]),
+
+            // Leaf ID
+            (0x11, [0xff]) => accepted([&[0x00][..], &LEAF_ID[..]].concat()),
+
+            // Key data
+            (0x12, [0xff, header @ ..]) if header.len() >= 6 => {
+                accepted([&[0x01][..], &header[..6]].concat())
+            }
+
+            // Session key exchange
+            (0x20, [0xff, 0x00, 0x00, 0x00, host_nonce @ ..]) if host_nonce.len() == 8 => {
+                let device_nonce = match &self.device_nonce {
+                    Some(nonce) => nonce.clone(),
+                    None => random_bytes::<8>().to_vec(),
+                };
+                let nonce = [host_nonce, &device_nonce[..]].concat();
+                if nonce.len() == 16 {
+                    self.session_key =
+                        Some(retailmac(&EKBOpenSource.root_key(), &nonce, &[0u8; 8]));
+                }
+                accepted([&[0x00, 0x00, 0x00, 0x00][..], &device_nonce[..]].concat())
+            }
+
+            // Session key forget
+            (0x21, [0xff, 0x00, 0x00, 0x00]) => {
+                self.session_key = None;
+                self.kek = None;
+                accepted(vec![0x00, 0x00, 0x00, 0x00])
+            }
+
+            // Setup download
+            (0x22, [0xff, 0x00, 0x00, message @ ..]) if message.len() == 32 => {
+                let session_key = self.session_key.as_ref()?;
+                let mut message = message.to_vec();
+                DesCbcDec::new(session_key.as_slice().into(), &[0u8; 8].into())
+                    .decrypt_padded_mut::<NoPadding>(&mut message)
+                    .ok()?;
+                if message[0..4] != [1, 1, 1, 1] {
+                    return Some((NetmdStatus::Rejected, vec![0x00, 0x00, 0x00]));
+                }
+                self.kek = Some(message[24..32].try_into().ok()?);
+                accepted(vec![0x00, 0x00, 0x00])
+            }
+
+            // Send track
+            (
+                0x28,
+                [0xff, 0x00, 0x01, 0x00, 0x10, 0x01, 0xff, 0xff, 0x00, wire_format, disc_format, sizes @ ..],
+            ) if sizes.len() == 8 => {
+                if self.kek.is_none() || self.writable_disc().is_none() {
+                    return Some((NetmdStatus::Rejected, [&[0x00][..], &rest[1..]].concat()));
+                }
+                self.transfer = Some(Transfer {
+                    wire_format: *wire_format,
+                    disc_format: *disc_format,
+                    frames: u32::from_be_bytes(sizes[0..4].try_into().ok()?),
+                    total_bytes: u32::from_be_bytes(sizes[4..8].try_into().ok()?) as usize,
+                    received: Vec::new(),
+                });
+                Some((NetmdStatus::Interim, [&[0x00][..], &rest[1..]].concat()))
+            }
+
+            // Commit track
+            (0x48, [0xff, 0x00, 0x10, 0x01, hi, lo, _mac @ ..]) => {
+                accepted(vec![0x00, 0x00, 0x10, 0x01, *hi, *lo])
+            }
+
+            // Disable new track protection
+            (0x2b, [0xff, hi, lo]) => accepted(vec![0x00, *hi, *lo]),
+
+            // Track UUID
+            (0x23, [0xff, 0x10, 0x01, hi, lo]) => {
+                let track = self.track(u16::from_be_bytes([*hi, *lo]))?;
+                accepted([&[0x00, 0x10, 0x01, *hi, *lo][..], &track.uuid[..]].concat())
+            }
+
+            // Terminate
+            (0x2a, [0xff, 0x00]) => accepted(vec![0x00, 0x00]),
+
+            // Save track to array (MZ-RH1 upload)
+            (0x30, [0xff, 0x00, 0x10, 0x01, hi, lo]) => {
+                let index = u16::from_be_bytes([*hi, *lo]).checked_sub(1)?;
+                let track = self.track(index)?;
+                let codec = match (track.encoding, track.channels) {
+                    (Encoding::SP, Channels::Stereo) => 6,
+                    (Encoding::SP, Channels::Mono) => 4,
+                    (Encoding::LP2, _) => 2,
+                    (Encoding::LP4, _) => 0,
+                };
+                let frames = (track.duration.as_micros() / FRAME_DURATION.as_micros()) as u16;
+                let data = track.data.clone();
+
+                let mut payload = vec![0x00, 0x00, 0x10, 0x01];
+                payload.extend_from_slice(&frames.to_be_bytes());
+                payload.push(codec);
+                payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+                // The reply after the bulk transfer is queued straight away
+                self.bulk_out.extend(data);
+                self.next_reply = Some(
+                    [
+                        &[NetmdStatus::Accepted as u8][..],
+                        &SECURE_PREFIX[..],
+                        &[0x30, 0x00, 0x00, 0x10, 0x01, *hi, *lo, 0x00, 0x00][..],
+                    ]
+                    .concat(),
+                );
+
+                accepted(payload)
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Decrypt a finished transfer and store it on the disc as a new track
+    fn finish_transfer(&mut self, transfer: Transfer) {
+        let Some(reply) = self.store_transfer(&transfer) else {
+            self.push_reply(NetmdStatus::Rejected, SECURE_PREFIX.to_vec());
+            return;
+        };
+
+        self.push_reply(NetmdStatus::Accepted, reply);
+    }
+
+    fn store_transfer(&mut self, transfer: &Transfer) -> Option<Vec<u8>> {
+        let kek = self.kek?;
+        let session_key = self.session_key.clone()?;
+
+        // Header is 0000 0000, the packet length, the key and the IV
+        let mut random_key: [u8; 8] = transfer.received.get(8..16)?.try_into().ok()?;
+        let iv: [u8; 8] = transfer.received.get(16..24)?.try_into().ok()?;
+        let mut audio = transfer.received.get(24..transfer.total_bytes)?.to_vec();
+
+        DesEcbEnc::new(&kek.into())
+            .encrypt_padded_mut::<NoPadding>(&mut random_key, 8)
+            .ok()?;
+        DesCbcDec::new(&random_key.into(), &iv.into())
+            .decrypt_padded_mut::<NoPadding>(&mut audio)
+            .ok()?;
+
+        let encoding = match transfer.wire_format {
+            0x00 => Encoding::SP,
+            0x90 | 0x94 => Encoding::LP2,
+            0xa8 => Encoding::LP4,
+            _ => return None,
+        };
+        let channels = match transfer.disc_format {
+            4 => Channels::Mono,
+            _ => Channels::Stereo,
+        };
+
+        let mut track = VirtualTrack::new("", encoding, FRAME_DURATION * transfer.frames);
+        track.channels = channels;
+        track.data = audio;
+        let uuid = track.uuid;
+
+        let disc = self.writable_disc()?;
+        let index = disc.tracks.len() as u16;
+        disc.tracks.push(track);
+
+        // The UUID and content ID are returned encrypted with the session key
+        let mut encrypted = [0u8; 32];
+        encrypted[0..8].copy_from_slice(&uuid);
+        rand::thread_rng().fill_bytes(&mut encrypted[12..32]);
+        DesCbcEnc::new(session_key.as_slice().into(), &[0u8; 8].into())
+            .encrypt_padded_mut::<NoPadding>(&mut encrypted, 32)
+            .ok()?;
+
+        let mut reply = SECURE_PREFIX.to_vec();
+        reply.extend_from_slice(&[0x28, 0x00, 0x00, 0x01, 0x00, 0x10, 0x01]);
+        reply.extend_from_slice(&index.to_be_bytes());
+        reply.extend_from_slice(&[0x00, transfer.wire_format, transfer.disc_format]);
+        reply.extend_from_slice(&transfer.frames.to_be_bytes());
+        reply.extend_from_slice(&(transfer.total_bytes as u32).to_be_bytes());
+        reply.extend_from_slice(&encrypted);
+
+        Some(reply)
+    }
+}
+
+impl Transport for VirtualPlayer {
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    async fn poll(&mut self) -> Result<[u8; 4], NetMDError> {
+        self.connected()?;
+        if self.replies.is_empty() {
+            self.check_notify();
+        }
+
+        let length = self.replies.front().map(|r| r.len()).unwrap_or(0) as u16;
+        let [low, high] = length.to_le_bytes();
+
+        Ok([0x00, 0x00, low, high])
+    }
+
+    async fn send_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.connected()?;
+        self.handle(command)
+    }
+
+    async fn send_factory_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.connected()?;
+        let body = command.get(1..).unwrap_or_default().to_vec();
+        self.push_reply(NetmdStatus::NotImplemented, body);
+
+        Ok(())
+    }
+
+    async fn read_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        self.connected()?;
+        let mut reply = self.replies.pop_front().ok_or(NetMDError::InvalidResult)?;
+        reply.truncate(length as usize);
+
+        Ok(reply)
+    }
+
+    async fn read_factory_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        self.read_reply(length).await
+    }
+
+    async fn read_bulk(&mut self, length: usize) -> Result<Vec<u8>, NetMDError> {
+        self.connected()?;
+        let length = length.min(self.bulk_out.len());
+
+        Ok(self.bulk_out.drain(..length).collect())
+    }
+
+    async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError> {
+        self.connected()?;
+        let Some(transfer) = self.transfer.as_mut() else {
+            return Err(NetMDError::InvalidResult);
+        };
+
+        transfer.received.extend_from_slice(data);
+        if transfer.received.len() >= transfer.total_bytes {
+            let transfer = self.transfer.take().unwrap();
+            self.finish_transfer(transfer);
+        }
+
+        Ok(data.len())
+    }
+}
+
+/// Prefix data with its length as a 2 byte big-endian number
+fn with_length(data: &[u8]) -> Vec<u8> {
+    [&(data.len() as u16).to_be_bytes()[..], data].concat()
+}
+
+/// Encode a duration as BCD hours, minutes, seconds and frames
+fn bcd_time(time: Duration, wide_hours: bool) -> Vec<u8> {
+    let seconds = time.as_secs();
+    let frames = time.subsec_micros() / FRAME_DURATION.subsec_micros();
+    let hours = int_to_bcd((seconds / 3600) as i32);
+
+    let mut result = Vec::new();
+    if wide_hours {
+        result.push((hours >> 8) as u8);
+    }
+    result.push(hours as u8);
+    result.push(int_to_bcd(((seconds / 60) % 60) as i32) as u8);
+    result.push(int_to_bcd((seconds % 60) as i32) as u8);
+    result.push(int_to_bcd(frames as i32) as u8);
+
+    result
+}
+
+fn random_bytes<const S: usize>() -> [u8; S] {
+    let mut bytes = [0u8; S];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::commands::ContextError;
+    use crate::netmd::interface::{Descriptor, InterfaceError, MDSession, MDTrack, WireFormat};
+    use crate::netmd::{NetMD, NetMDContext, NetMDInterface};
+
+    fn context(disc: VirtualDisc) -> NetMDContext<VirtualPlayer> {
+        player_context(VirtualPlayer::new(disc))
+    }
+
+    fn player_context(player: VirtualPlayer) -> NetMDContext<VirtualPlayer> {
+        let device = NetMD::new(player).unwrap();
+        NetMDContext::from(NetMDInterface::from(device))
+    }
+
+    fn player_disc(context: &NetMDContext<VirtualPlayer>) -> &VirtualDisc {
+        context.interface().device.transport().disc().unwrap()
+    }
+
+    #[test]
+    fn list_content() {
+        let disc = VirtualDisc {
+            title: String::from("Mix"),
+            tracks: vec![
+                VirtualTrack::new("First", Encoding::SP, Duration::from_secs(90)),
+                VirtualTrack::new("Second", Encoding::LP2, Duration::from_secs(120)),
+            ],
+            ..Default::default()
+        };
+        let mut context = context(disc);
+
+        let content = tokio_test::block_on(context.list_content()).unwrap();
+        let tracks = content.tracks();
+
+        assert_eq!(content.track_count(), 2);
+        assert_eq!(tracks[0].title(), "First");
+        assert_eq!(tracks[0].encoding(), Encoding::SP);
+        assert_eq!(tracks[1].title(), "Second");
+        assert_eq!(tracks[1].encoding(), Encoding::LP2);
+    }
+
+    #[test]
+    fn rename_disc() {
+        let disc = VirtualDisc {
+            title: String::from("0;Old//1-2;Group//"),
+            tracks: vec![
+                VirtualTrack::new("First", Encoding::SP, Duration::from_secs(90)),
+                VirtualTrack::new("Second", Encoding::SP, Duration::from_secs(90)),
+            ],
+            ..Default::default()
+        };
+        let mut context = context(disc);
+
+        tokio_test::block_on(context.rename_disc("New", None)).unwrap();
+
+        // The group information is kept
+        assert_eq!(player_disc(&context).title, "0;New//1-2;Group//");
+        assert!(context
+            .interface()
+            .device
+            .transport()
+            .open_descriptors()
+            .is_empty());
+    }
+
+    #[test]
+    fn failed_title_write() {
+        let mut player = VirtualPlayer::new(VirtualDisc {
+            title: String::from("Old"),
+            ..Default::default()
+        });
+        player.fail_next(&[0x18, 0x07], Fault::Status(NetmdStatus::Rejected));
+        let mut context = player_context(player);
+        let interface = context.interface_mut();
+
+        let result = tokio_test::block_on(interface.set_disc_title("New", false));
+
+        assert!(matches!(result, Err(InterfaceError::Rejected(_))));
+        assert_eq!(
+            interface.device.transport().open_descriptors(),
+            [Descriptor::DiscTitleTD.get_array()]
+        );
+
+        // The descriptor is closed before the next command
+        assert_eq!(tokio_test::block_on(interface.track_count()).unwrap(), 0);
+        assert!(interface.device.transport().open_descriptors().is_empty());
+        assert_eq!(player_disc(&context).title, "Old");
+    }
+
+    #[test]
+    fn subunit_identifier() {
+        let mut context = context(VirtualDisc::default());
+
+        let identifier = tokio_test::block_on(context.disc_subunit_identifier()).unwrap();
+
+        assert_eq!(identifier.level(), Ok(NetMDLevel::Level3));
+        assert_eq!(
+            tokio_test::block_on(context.net_md_level()),
+            Ok(NetMDLevel::Level3)
+        );
+    }
+
+    #[test]
+    fn level_read_on_first_use() {
+        let disc = VirtualDisc {
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+        let player = VirtualPlayer::new(disc).with_level(NetMDLevel::Level1);
+        let mut context = player_context(player);
+
+        let result = tokio_test::block_on(context.interface_mut().erase_track(0));
+
+        assert_eq!(
+            result,
+            Err(InterfaceError::UnsupportedLevel {
+                command: "erase track",
+                required: NetMDLevel::Level3,
+                actual: NetMDLevel::Level1,
+            })
+        );
+        assert_eq!(player_disc(&context).tracks.len(), 1);
+    }
+
+    #[test]
+    fn probe_commands() {
+        let mut context = context(VirtualDisc::default());
+
+        let support = tokio_test::block_on(context.probe_commands()).unwrap();
+
+        assert_eq!(support.play, Some(true));
+        assert_eq!(support.erase_track, Some(true));
+        assert_eq!(support.secure_session, Some(true));
+    }
+
+    #[test]
+    fn probe_commands_unclear_answer() {
+        let mut player = VirtualPlayer::new(VirtualDisc::default());
+        player.fail_next(
+            &[0x18, 0x40, 0xff, 0x01],
+            Fault::Status(NetmdStatus::Changed),
+        );
+        let mut context = player_context(player);
+
+        let support = tokio_test::block_on(context.probe_commands()).unwrap();
+
+        // The other commands are still probed
+        assert_eq!(support.erase_disc, Some(true));
+        assert_eq!(support.erase_track, None);
+        assert_eq!(support.move_track, Some(true));
+        assert_eq!(support.secure_session, Some(true));
+    }
+
+    #[test]
+    fn probe_commands_disconnected() {
+        let mut player = VirtualPlayer::new(VirtualDisc::default());
+        player.fail_next(&[0x18, 0x40], Fault::Disconnect);
+        let mut context = player_context(player);
+
+        let result = tokio_test::block_on(context.probe_commands());
+
+        assert_eq!(
+            result,
+            Err(InterfaceError::CommunicationError(NetMDError::Disconnected))
+        );
+    }
+
+    #[test]
+    fn capacity_flag() {
+        // A Panasonic SJ-MR250
+        let player = VirtualPlayer::new(VirtualDisc::default())
+            .with_ids(0x04da, 0x23b3)
+            .with_capacity_flag(0x08);
+        let mut context = player_context(player);
+
+        assert!(tokio_test::block_on(context.list_content()).is_ok());
+
+        let player = VirtualPlayer::new(VirtualDisc::default()).with_ids(0x04da, 0x23b3);
+        let mut context = player_context(player);
+
+        let result = tokio_test::block_on(context.list_content());
+
+        assert!(matches!(
+            result,
+            Err(ContextError::Interface(
+                InterfaceError::UnexpectedCapacityFlag {
+                    expected: 0x08,
+                    found: 0x80
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn mode_scaled_capacity() {
+        // An 80 minute disc, reported in LP2
+        let disc = VirtualDisc {
+            capacity: Duration::from_secs(160 * 60),
+            ..Default::default()
+        };
+        let frames = 512 * 60 * 80;
+
+        // A Sharp IM-MT899H
+        let player = VirtualPlayer::new(disc.clone()).with_ids(0x04dd, 0x7202);
+        let content = tokio_test::block_on(player_context(player).list_content()).unwrap();
+        assert_eq!(content.total(), frames);
+        assert_eq!(content.left(), frames);
+
+        // Other devices are not expected to scale the capacity
+        let content = tokio_test::block_on(context(disc).list_content()).unwrap();
+        assert_eq!(content.total(), frames * 2);
+    }
+
+    #[test]
+    fn rejected_track_changes() {
+        let disc = VirtualDisc {
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+        let mut player = VirtualPlayer::new(disc);
+        player.fail_next(&[0x18, 0x40], Fault::Status(NetmdStatus::Rejected));
+        player.fail_next(&[0x18, 0x43], Fault::Status(NetmdStatus::Rejected));
+        let mut context = player_context(player);
+        let interface = context.interface_mut();
+
+        let erased = tokio_test::block_on(interface.erase_track(0));
+        let moved = tokio_test::block_on(interface.move_track(0, 0));
+
+        assert!(matches!(erased, Err(InterfaceError::Rejected(_))));
+        assert!(matches!(moved, Err(InterfaceError::Rejected(_))));
+        assert_eq!(player_disc(&context).tracks.len(), 1);
+    }
+
+    #[test]
+    fn rename_write_protected_disc() {
+        let disc = VirtualDisc {
+            title: String::from("Old"),
+            write_protected: true,
+            ..Default::default()
+        };
+        let mut context = context(disc);
+
+        let result = tokio_test::block_on(context.rename_disc("New", None));
+
+        assert!(matches!(result, Err(ContextError::WriteProtected)));
+        assert_eq!(player_disc(&context).title, "Old");
+    }
+
+    #[test]
+    fn download() {
+        let data: Vec<u8> = (0..2048 * 4).map(|i| (i % 251) as u8).collect();
+        let track = MDTrack {
+            title: String::from("Downloaded"),
+            format: WireFormat::Pcm,
+            data: data.clone(),
+            chunk_size: 0x400,
+            full_width_title: None,
+        };
+        let mut context = context(VirtualDisc::default());
+
+        let (index, _, _) = tokio_test::block_on(context.download(track, |_, _| {})).unwrap();
+
+        // The player decrypted the packets back to the original audio
+        let disc = player_disc(&context);
+        assert_eq!(index, 0);
+        assert_eq!(disc.tracks.len(), 1);
+        assert_eq!(disc.tracks[0].title, "Downloaded");
+        assert_eq!(disc.tracks[0].encoding, Encoding::SP);
+        assert_eq!(disc.tracks[0].data, data);
+    }
+
+    #[test]
+    fn short_device_nonce() {
+        let player = VirtualPlayer::new(VirtualDisc::default()).with_device_nonce(&[0x01; 7]);
+        let mut context = player_context(player);
+        let mut session = MDSession::new(context.interface_mut());
+
+        let result = tokio_test::block_on(session.init());
+
+        assert!(matches!(
+            result,
+            Err(ContextError::Interface(InterfaceError::EncryptionError(_)))
+        ));
+        assert!(session.hex_session_key.is_none());
+    }
+
+    fn track() -> MDTrack {
+        MDTrack {
+            title: String::from("Downloaded"),
+            format: WireFormat::Pcm,
+            data: vec![0; 2048],
+            chunk_size: 0x400,
+            full_width_title: None,
+        }
+    }
+
+    #[test]
+    fn rejected_session() {
+        let mut player = VirtualPlayer::new(VirtualDisc::default());
+        player.fail_next(
+            &[&SECURE_PREFIX[..], &[0x80]].concat(),
+            Fault::Status(NetmdStatus::Rejected),
+        );
+        let mut context = player_context(player);
+
+        let result = tokio_test::block_on(context.download(track(), |_, _| {}));
+
+        assert!(matches!(
+            result,
+            Err(ContextError::SessionRejected(InterfaceError::Rejected(_)))
+        ));
+        assert!(player_disc(&context).tracks.is_empty());
+    }
+
+    #[test]
+    fn disconnected_during_download() {
+        let mut player = VirtualPlayer::new(VirtualDisc::default());
+        player.fail_next(&[&SECURE_PREFIX[..], &[0x28]].concat(), Fault::Disconnect);
+        let mut context = player_context(player);
+
+        let result = tokio_test::block_on(context.download(track(), |_, _| {}));
+
+        assert!(matches!(result, Err(ContextError::Disconnected)));
+    }
+}
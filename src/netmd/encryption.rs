@@ -1,7 +1,9 @@
 use cbc::cipher::block_padding::NoPadding;
 use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit};
 use rand::RngCore;
+#[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
 use std::thread;
+#[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 use super::interface::DataEncryptorInput;
@@ -10,6 +12,7 @@ type DesEcbEnc = ecb::Decryptor<des::Des>;
 type DesCbcEnc = cbc::Encryptor<des::Des>;
 
 pub struct Encryptor {
+    #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
     #[allow(clippy::type_complexity)]
     channel: Option<UnboundedReceiver<(Vec<u8>, Vec<u8>, Vec<u8>)>>,
     state: Option<EncryptorState>,
@@ -28,6 +31,7 @@ struct EncryptorState {
 }
 
 impl Encryptor {
+    #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
     pub fn new_threaded(input: DataEncryptorInput) -> Self {
         let (tx, rx) = unbounded_channel::<(Vec<u8>, Vec<u8>, Vec<u8>)>();
 
@@ -128,6 +132,7 @@ impl Encryptor {
         let offset: usize = 0;
 
         Encryptor {
+            #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
             channel: None,
             state: Some(EncryptorState {
                 input_data,
@@ -175,9 +180,11 @@ impl Encryptor {
 
             state.packet_count += 1;
             state.offset += state.current_chunk_size;
-        } else if let Some(channel) = self.channel.as_mut() {
-            output = channel.recv().await
         } else {
+            #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
+            if let Some(channel) = self.channel.as_mut() {
+                return channel.recv().await;
+            }
             unreachable!("If you got here, this is bad!");
         }
 
@@ -188,9 +195,12 @@ impl Encryptor {
     pub fn close(&mut self) {
         if let Some(state) = self.state.as_mut() {
             state.closed = true;
-        } else if let Some(channel) = self.channel.as_mut() {
-            channel.close()
         } else {
+            #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
+            if let Some(channel) = self.channel.as_mut() {
+                channel.close();
+                return;
+            }
             unreachable!("If you got here, this is bad!");
         }
     }
@@ -0,0 +1,463 @@
+//! Sharing one device between several tasks.
+//!
+//! A [`DeviceHandle`] can be cloned and sent to any task. Every operation
+//! sent through it is queued and run to completion by a single
+//! [`DeviceActor`], so a sequence of commands (such as opening a descriptor,
+//! reading it and closing it again) is never interleaved with another one.
+//!
+//! The actor borrows the device for as long as it runs, and is not `Send`,
+//! so it should be run on a local task or on its own thread:
+//!
+//! ```no_run
+//! # tokio_test::block_on(async {
+//! use minidisc::netmd::handle::{DeviceHandle, Priority};
+//! use minidisc::netmd::device_filters;
+//! use minidisc::netmd::NetMDContext;
+//!
+//! let device = cross_usb::get_device(device_filters()).await.unwrap();
+//! let context = NetMDContext::new(device).await.unwrap();
+//!
+//! let (handle, actor) = DeviceHandle::new(context);
+//!
+//! let local = tokio::task::LocalSet::new();
+//! local.spawn_local(actor.run());
+//! local.run_until(async move {
+//!     // Status reads are queued before any other operation
+//!     println!("{:?}", handle.device_status().await.unwrap());
+//!
+//!     // Anything else can be run as one operation
+//!     let titles = handle
+//!         .run(Priority::Normal, |context| {
+//!             Box::pin(async move {
+//!                 let interface = context.interface_mut();
+//!                 let count = interface.track_count().await?;
+//!                 interface.track_titles((0..count).collect(), false).await
+//!             })
+//!         })
+//!         .await
+//!         .unwrap();
+//!     println!("{:?}", titles);
+//! }).await;
+//! # })
+//! ```
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+use super::commands::{ContextError, DeviceStatus, Disc, NetMDContext};
+use super::interface::MDTrack;
+use super::transport::Transport;
+#[cfg(feature = "native-usb")]
+use super::transport::UsbTransport;
+
+/// The future of an operation, which borrows the context while it runs
+pub type OperationFuture<'a, R> = Pin<Box<dyn Future<Output = R> + 'a>>;
+
+type StepFn<T> =
+    dyn for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, Option<Operation<T>>> + Send;
+
+/// A queued step of an operation, which returns the next step if there is one
+struct Operation<T>(Box<StepFn<T>>);
+
+impl<T> Operation<T> {
+    fn new<F>(step: F) -> Self
+    where
+        F: for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, Option<Operation<T>>>
+            + Send
+            + 'static,
+    {
+        Self(Box::new(step))
+    }
+}
+
+/// Counts an operation as pending until it is dropped, which is when its
+/// last step has finished or it can no longer run
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn acquire(pending: &Arc<AtomicUsize>) -> Self {
+        pending.fetch_add(1, Ordering::AcqRel);
+        Self(Arc::clone(pending))
+    }
+
+    /// Only count the operation if nothing else is pending
+    fn try_acquire(pending: &Arc<AtomicUsize>) -> Option<Self> {
+        pending
+            .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| Self(Arc::clone(pending)))
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+type Queued<T> = (Priority, Operation<T>, Pending);
+
+/// An error when running an operation through a [`DeviceHandle`]
+#[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum HandleError {
+    #[error("the device is busy")]
+    Busy,
+
+    #[error("the device actor has stopped")]
+    Closed,
+
+    #[error(transparent)]
+    Context(#[from] ContextError),
+}
+
+/// The order operations are run in.
+///
+/// Queued operations with a high priority run before any queued normal
+/// operation, but an operation which is running is never interrupted.
+///
+/// An operation may be made of several steps, such as
+/// [`DeviceHandle::download`]. High priority operations can run between the
+/// steps, but no other normal operation can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+/// A cloneable handle to a device run by a [`DeviceActor`].
+pub struct DeviceHandle<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    sender: mpsc::UnboundedSender<Queued<T>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for DeviceHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            pending: Arc::clone(&self.pending),
+        }
+    }
+}
+
+impl<T: Transport + 'static> DeviceHandle<T> {
+    /// Create a handle to a device, and the actor which must be run for
+    /// any operation to complete
+    pub fn new(context: NetMDContext<T>) -> (Self, DeviceActor<T>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let handle = Self {
+            sender,
+            pending: Arc::new(AtomicUsize::new(0)),
+        };
+        let actor = DeviceActor {
+            context,
+            receiver,
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+        };
+
+        (handle, actor)
+    }
+
+    /// Whether an operation is running or queued
+    pub fn is_busy(&self) -> bool {
+        self.pending.load(Ordering::Acquire) > 0
+    }
+
+    /// Run an operation once every operation queued before it with the same
+    /// or a higher priority has finished.
+    ///
+    /// The operation has the device to itself until its future completes.
+    pub async fn run<R, F>(&self, priority: Priority, operation: F) -> Result<R, HandleError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, R> + Send + 'static,
+    {
+        let pending = Pending::acquire(&self.pending);
+        self.queue(priority, operation, pending).await
+    }
+
+    /// Run an operation only if nothing else is running or queued,
+    /// returning [`HandleError::Busy`] otherwise
+    pub async fn try_run<R, F>(&self, operation: F) -> Result<R, HandleError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, R> + Send + 'static,
+    {
+        let pending = Pending::try_acquire(&self.pending).ok_or(HandleError::Busy)?;
+        self.queue(Priority::High, operation, pending).await
+    }
+
+    /// Queue an operation of one step, and wait for its result
+    async fn queue<R, F>(
+        &self,
+        priority: Priority,
+        operation: F,
+        pending: Pending,
+    ) -> Result<R, HandleError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut NetMDContext<T>) -> OperationFuture<'a, R> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let operation = Operation::new(move |context| {
+            Box::pin(async move {
+                let _ = sender.send(operation(context).await);
+                None
+            })
+        });
+
+        self.send(priority, operation, pending, receiver).await
+    }
+
+    /// Send the first step of an operation to the actor, and wait for its
+    /// result
+    async fn send<R>(
+        &self,
+        priority: Priority,
+        operation: Operation<T>,
+        pending: Pending,
+        receiver: oneshot::Receiver<R>,
+    ) -> Result<R, HandleError> {
+        // The operation and its count are dropped if the actor has stopped
+        self.sender
+            .send((priority, operation, pending))
+            .map_err(|_| HandleError::Closed)?;
+
+        receiver.await.map_err(|_| HandleError::Closed)
+    }
+
+    /// Get the current status of the device, see
+    /// [`NetMDContext::device_status`]
+    pub async fn device_status(&self) -> Result<DeviceStatus, HandleError> {
+        let status = self
+            .run(Priority::High, |context| Box::pin(context.device_status()))
+            .await??;
+
+        Ok(status)
+    }
+
+    /// Get the contents of the disc, see [`NetMDContext::list_content`]
+    pub async fn list_content(&self) -> Result<Disc, HandleError> {
+        let disc = self
+            .run(Priority::Normal, |context| Box::pin(context.list_content()))
+            .await??;
+
+        Ok(disc)
+    }
+
+    /// Download a track to the device, see [`NetMDContext::download`]
+    ///
+    /// This is run in two steps. Checking the disc and acquiring the device
+    /// is the first, and high priority operations such as
+    /// [`DeviceHandle::device_status`] can run once it has finished. Sending
+    /// the track is the second, which is one secure session and one bulk
+    /// transfer, so nothing else runs on the device until the track has been
+    /// sent and committed.
+    pub async fn download<F>(
+        &self,
+        track: MDTrack,
+        progress_callback: F,
+    ) -> Result<(u16, Vec<u8>, Vec<u8>), HandleError>
+    where
+        F: Fn(usize, usize) + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let operation = Operation::new(move |context| {
+            Box::pin(async move {
+                if let Err(error) = context.prepare_download(&track).await {
+                    let _ = sender.send(Err(error));
+                    return None;
+                }
+
+                let send = Operation::new(move |context| {
+                    Box::pin(async move {
+                        let result = context.send_prepared_track(track, progress_callback).await;
+                        let _ = sender.send(result);
+                        None
+                    })
+                });
+                Some(send)
+            })
+        });
+
+        let pending = Pending::acquire(&self.pending);
+        let result = self
+            .send(Priority::Normal, operation, pending, receiver)
+            .await??;
+
+        Ok(result)
+    }
+}
+
+/// Runs the operations sent through every [`DeviceHandle`] to a device.
+///
+/// The actor stops once every handle has been dropped.
+pub struct DeviceActor<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    context: NetMDContext<T>,
+    receiver: mpsc::UnboundedReceiver<Queued<T>>,
+    high: VecDeque<(Operation<T>, Pending)>,
+    normal: VecDeque<(Operation<T>, Pending)>,
+}
+
+impl<T: Transport> DeviceActor<T> {
+    /// Run operations until every handle has been dropped, returning the
+    /// context
+    pub async fn run(mut self) -> NetMDContext<T> {
+        loop {
+            while let Ok(operation) = self.receiver.try_recv() {
+                self.queue(operation);
+            }
+
+            let (operation, pending) =
+                match self.high.pop_front().or_else(|| self.normal.pop_front()) {
+                    Some(operation) => operation,
+                    None => match self.receiver.recv().await {
+                        Some(operation) => {
+                            self.queue(operation);
+                            continue;
+                        }
+                        None => return self.context,
+                    },
+                };
+
+            // The next step runs before any other normal operation, and the
+            // operation stops being pending once it has none
+            if let Some(next) = (operation.0)(&mut self.context).await {
+                self.normal.push_front((next, pending));
+            }
+        }
+    }
+
+    fn queue(&mut self, (priority, operation, pending): Queued<T>) {
+        match priority {
+            Priority::High => self.high.push_back((operation, pending)),
+            Priority::Normal => self.normal.push_back((operation, pending)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    use tokio_test::task;
+
+    use super::*;
+    use crate::netmd::emulator::{VirtualDisc, VirtualPlayer, VirtualTrack};
+    use crate::netmd::interface::Encoding;
+    use crate::netmd::{NetMD, NetMDInterface};
+
+    fn handle() -> (DeviceHandle<VirtualPlayer>, DeviceActor<VirtualPlayer>) {
+        let disc = VirtualDisc {
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+        let device = NetMD::new(VirtualPlayer::new(disc)).unwrap();
+
+        DeviceHandle::new(NetMDContext::from(NetMDInterface::from(device)))
+    }
+
+    /// An operation which records that it ran
+    fn record(
+        order: &Arc<Mutex<Vec<&'static str>>>,
+        name: &'static str,
+    ) -> impl for<'a> FnOnce(&'a mut NetMDContext<VirtualPlayer>) -> OperationFuture<'a, ()> {
+        let order = Arc::clone(order);
+        move |_| {
+            Box::pin(async move {
+                order.lock().unwrap().push(name);
+            })
+        }
+    }
+
+    #[test]
+    fn priority() {
+        let (handle, actor) = handle();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Every operation is queued before the actor runs
+        let mut normal = task::spawn(handle.run(Priority::Normal, record(&order, "normal")));
+        let mut status = task::spawn(handle.device_status());
+        let mut high = task::spawn(handle.run(Priority::High, record(&order, "high")));
+        assert!(normal.poll().is_pending());
+        assert!(status.poll().is_pending());
+        assert!(high.poll().is_pending());
+
+        let mut actor = task::spawn(actor.run());
+        assert!(actor.poll().is_pending());
+
+        assert!(matches!(high.poll(), Poll::Ready(Ok(()))));
+        assert!(matches!(status.poll(), Poll::Ready(Ok(_))));
+        assert!(matches!(normal.poll(), Poll::Ready(Ok(()))));
+        assert_eq!(*order.lock().unwrap(), ["high", "normal"]);
+    }
+
+    #[test]
+    fn busy() {
+        let (handle, actor) = handle();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        assert!(!handle.is_busy());
+
+        let mut queued = task::spawn(handle.run(Priority::Normal, record(&order, "queued")));
+        assert!(queued.poll().is_pending());
+        assert!(handle.is_busy());
+
+        let busy = tokio_test::block_on(handle.try_run(record(&order, "busy")));
+        assert_eq!(busy, Err(HandleError::Busy));
+
+        let mut actor = task::spawn(actor.run());
+        assert!(actor.poll().is_pending());
+        assert!(matches!(queued.poll(), Poll::Ready(Ok(()))));
+        assert!(!handle.is_busy());
+
+        let mut idle = task::spawn(handle.try_run(record(&order, "idle")));
+        assert!(idle.poll().is_pending());
+        assert!(handle.is_busy());
+        assert!(actor.poll().is_pending());
+        assert!(matches!(idle.poll(), Poll::Ready(Ok(()))));
+
+        assert!(!handle.is_busy());
+        assert_eq!(*order.lock().unwrap(), ["queued", "idle"]);
+    }
+
+    #[test]
+    fn actor_dropped() {
+        let (handle, actor) = handle();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut queued = task::spawn(handle.run(Priority::Normal, record(&order, "queued")));
+        assert!(queued.poll().is_pending());
+        assert!(handle.is_busy());
+
+        // The queued operation is no longer pending once it can't run
+        drop(actor);
+        assert!(!handle.is_busy());
+        assert!(matches!(
+            queued.poll(),
+            Poll::Ready(Err(HandleError::Closed))
+        ));
+        assert_eq!(
+            tokio_test::block_on(handle.try_run(record(&order, "closed"))),
+            Err(HandleError::Closed)
+        );
+        assert!(!handle.is_busy());
+        assert!(order.lock().unwrap().is_empty());
+    }
+}
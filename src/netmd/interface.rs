@@ -1,28 +1,29 @@
 #![cfg_attr(debug_assertions, allow(dead_code))]
-use crate::netmd::base;
-use crate::netmd::query_utils::{format_query, scan_query, QueryValue};
-use crate::netmd::utils::{
-    half_width_to_full_width_range, length_after_encoding_to_sjis, sanitize_full_width_title,
-    sanitize_half_width_title, RawTime,
-};
-use cbc::cipher::block_padding::NoPadding;
-use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit};
-use encoding_rs::SHIFT_JIS;
-use log::debug;
+use crate::netmd::query_utils::{QueryError, QueryTemplate, QueryValue};
+use crate::netmd::utils::RawTime;
+use futures_core::Stream;
+use log::{debug, trace, warn};
 use num_derive::FromPrimitive;
 use rand::RngCore;
-use std::collections::HashMap;
-use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use thiserror::Error;
 
-use super::base::NetMD;
+use super::base::{Capabilities, NetMD, NetMDError, RetryPolicy};
+use super::commands::ContextError;
+use super::dissector::dissect;
 use super::encryption::Encryptor;
-use super::utils::{cross_sleep, to_sjis};
+use super::protocol::{self, parse_groups, Command, EKBOpenSource, Exchange, GroupList};
+use super::transport::Transport;
+#[cfg(feature = "native-usb")]
+use super::transport::UsbTransport;
+use super::utils::cross_sleep;
 
 /// An action to take on the player
-#[derive(Copy, Clone)]
-enum Action {
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+pub enum Action {
     Play = 0x75,
     Pause = 0x7d,
     FastForward = 0x39,
@@ -38,6 +39,7 @@ pub enum Direction {
 
 /// The format of the disc
 #[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DiscFormat {
     LP4 = 0,
     LP2 = 2,
@@ -76,6 +78,7 @@ impl WireFormat {
 
 /// The encoding of the audio
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Encoding {
     SP = 0x90,
     LP2 = 0x92,
@@ -94,6 +97,7 @@ impl std::fmt::Display for Encoding {
 
 /// The number of channels in the audio
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Channels {
     Mono = 0x01,
     Stereo = 0x00,
@@ -115,6 +119,7 @@ enum ChannelCount {
 
 /// The protected flag on a track
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TrackFlag {
     Protected = 0x03,
     Unprotected = 0x00,
@@ -135,9 +140,10 @@ pub enum DiscFlag {
     WriteProtected = 0x40,
 }
 
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-enum NetMDLevel {
+/// The NetMD level of a device, which limits the commands it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NetMDLevel {
     Level1 = 0x20, // Network MD
     Level2 = 0x50, // Program play MD
     Level3 = 0x70, // Editing MD
@@ -156,7 +162,8 @@ impl std::convert::TryFrom<u8> for NetMDLevel {
     }
 }
 
-enum Descriptor {
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Descriptor {
     DiscTitleTD,
     AudioUTOC1TD,
     AudioUTOC4TD,
@@ -169,7 +176,7 @@ enum Descriptor {
 }
 
 impl Descriptor {
-    fn get_array(&self) -> Vec<u8> {
+    pub(super) fn get_array(&self) -> Vec<u8> {
         match self {
             Descriptor::DiscTitleTD => vec![0x10, 0x18, 0x01],
             Descriptor::AudioUTOC1TD => vec![0x10, 0x18, 0x02],
@@ -183,16 +190,18 @@ impl Descriptor {
     }
 }
 
-#[derive(Copy, Clone)]
-enum DescriptorAction {
+/// An action on a descriptor, see [`NetMDInterface::raw_descriptor_state`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum DescriptorAction {
     OpenRead = 1,
     OpenWrite = 3,
     Close = 0,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The status byte at the start of every command and reply
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
-enum NetmdStatus {
+pub enum NetmdStatus {
     // NetMD Protocol return status (first byte of request)
     Control = 0x00,
     Status = 0x01,
@@ -211,7 +220,88 @@ enum NetmdStatus {
 
 #[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
 #[error("invalid status code: {}", 1)]
-pub struct StatusError(u16);
+pub struct StatusError(pub(super) u16);
+
+/// The type of a command, which is sent as its status byte
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CommandType {
+    /// Run the command
+    Control,
+    /// Read the state of the device
+    Status,
+    /// Ask if the command, including its operands, would be accepted
+    SpecificInquiry,
+    /// Ask to be told when the state read by the command changes
+    Notify,
+    /// Ask if the command is supported at all
+    GeneralInquiry,
+}
+
+impl From<CommandType> for NetmdStatus {
+    fn from(value: CommandType) -> Self {
+        match value {
+            CommandType::Control => NetmdStatus::Control,
+            CommandType::Status => NetmdStatus::Status,
+            CommandType::SpecificInquiry => NetmdStatus::SpecificInquiry,
+            CommandType::Notify => NetmdStatus::Notify,
+            CommandType::GeneralInquiry => NetmdStatus::GeneralInquiry,
+        }
+    }
+}
+
+/// A reply from the device to a raw command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    /// The status of the reply
+    pub status: NetmdStatus,
+    /// The whole reply, including the status byte
+    pub data: Vec<u8>,
+}
+
+impl Reply {
+    /// The reply without its status byte
+    pub fn body(&self) -> &[u8] {
+        self.data.get(1..).unwrap_or_default()
+    }
+
+    /// Scan the reply with a template, see [`QueryTemplate::decode`]
+    pub fn decode<'a>(
+        &'a self,
+        template: &QueryTemplate,
+    ) -> Result<Vec<QueryValue<'a>>, QueryError> {
+        template.decode(&self.data)
+    }
+
+    /// Turn a reply which was not accepted by the device into an error
+    pub fn accepted(self) -> Result<Self, InterfaceError> {
+        match self.status {
+            NetmdStatus::Accepted | NetmdStatus::Implemented | NetmdStatus::Interim => Ok(self),
+            _ => Err(self.into_error()),
+        }
+    }
+
+    pub(super) fn from_data(data: Vec<u8>) -> Result<Self, InterfaceError> {
+        trace!("RECV<<< {}", dissect(&data));
+
+        let status_byte = *data
+            .first()
+            .ok_or(InterfaceError::MissingField("a status"))?;
+        let status = NetmdStatus::try_from(status_byte)?;
+        debug!("Device status: {:?}", status);
+
+        Ok(Self { status, data })
+    }
+
+    fn into_error(self) -> InterfaceError {
+        match self.status {
+            NetmdStatus::NotImplemented => {
+                InterfaceError::NotImplemented(format!("{:02X?}", self.data))
+            }
+            NetmdStatus::Rejected => InterfaceError::Rejected(format!("{:02X?}", self.data)),
+            _ => InterfaceError::Unknown(format!("{:02X?}", self.data)),
+        }
+    }
+}
 
 impl TryFrom<u8> for NetmdStatus {
     type Error = StatusError;
@@ -235,12 +325,250 @@ impl TryFrom<u8> for NetmdStatus {
     }
 }
 
-struct MediaInfo {
-    supported_media_type: u32,
-    implementation_profile_id: u8,
-    media_type_attributes: u8,
-    md_audio_version: u8,
-    supports_md_clip: u8,
+/// Which commands a device says it supports, from
+/// [`NetMDInterface::probe_commands`]
+///
+/// A command is `None` if the device didn't give a clear answer, f. ex.
+/// because the inquiry timed out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandSupport {
+    pub play: Option<bool>,
+    pub pause: Option<bool>,
+    pub fast_forward: Option<bool>,
+    pub rewind: Option<bool>,
+    pub stop: Option<bool>,
+    pub go_to_track: Option<bool>,
+    pub go_to_time: Option<bool>,
+    pub track_change: Option<bool>,
+    pub eject_disc: Option<bool>,
+    pub erase_disc: Option<bool>,
+    pub erase_track: Option<bool>,
+    pub move_track: Option<bool>,
+    pub set_disc_title: Option<bool>,
+    pub set_track_title: Option<bool>,
+    /// Tracks can be uploaded from the device with
+    /// [`NetMDInterface::save_track_to_array`]
+    pub save_track: Option<bool>,
+    /// Secure sessions, which are needed to download tracks
+    pub secure_session: Option<bool>,
+}
+
+/// A type of media supported by a device, from its [`SubunitIdentifier`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaInfo {
+    /// The type of media, `0x301` for MiniDisc audio
+    pub supported_media_type: u32,
+    /// The [`NetMDLevel`] of the device for this media
+    pub implementation_profile_id: u8,
+    pub media_type_attributes: u8,
+    pub md_audio_version: u8,
+    pub supports_md_clip: bool,
+}
+
+/// The disc subunit identifier descriptor of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubunitIdentifier {
+    pub generation_id: u8,
+    pub root_objects: Vec<u32>,
+    pub attributes: u8,
+    pub disc_subunit_version: u8,
+    pub media: Vec<MediaInfo>,
+    pub manufacturer_data: Vec<u8>,
+}
+
+impl SubunitIdentifier {
+    /// Get the [`NetMDLevel`] of the device from its MiniDisc audio media
+    pub fn level(&self) -> Result<NetMDLevel, InterfaceError> {
+        match self.media.iter().find(|m| m.supported_media_type == 0x301) {
+            Some(media) => NetMDLevel::try_from(media.implementation_profile_id),
+            None => Err(InterfaceError::NoSupportedMedia),
+        }
+    }
+}
+
+/// The current reported status from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OperatingStatus {
+    Ready,
+    Playing,
+    Paused,
+    FastForward,
+    Rewind,
+    ReadingTOC,
+    NoDisc,
+    DiscBlank,
+    ReadyForTransfer,
+    /// A status which is not known yet
+    Unknown(u16),
+}
+
+impl From<u16> for OperatingStatus {
+    fn from(value: u16) -> Self {
+        match value {
+            50687 => OperatingStatus::Ready,
+            50037 => OperatingStatus::Playing,
+            50045 => OperatingStatus::Paused,
+            49983 => OperatingStatus::FastForward,
+            49999 => OperatingStatus::Rewind,
+            65315 => OperatingStatus::ReadingTOC,
+            65296 => OperatingStatus::NoDisc,
+            65535 => OperatingStatus::DiscBlank,
+            65319 => OperatingStatus::ReadyForTransfer,
+            value => OperatingStatus::Unknown(value),
+        }
+    }
+}
+
+impl From<OperatingStatus> for u16 {
+    fn from(value: OperatingStatus) -> Self {
+        match value {
+            OperatingStatus::Ready => 50687,
+            OperatingStatus::Playing => 50037,
+            OperatingStatus::Paused => 50045,
+            OperatingStatus::FastForward => 49983,
+            OperatingStatus::Rewind => 49999,
+            OperatingStatus::ReadingTOC => 65315,
+            OperatingStatus::NoDisc => 65296,
+            OperatingStatus::DiscBlank => 65535,
+            OperatingStatus::ReadyForTransfer => 65319,
+            OperatingStatus::Unknown(value) => value,
+        }
+    }
+}
+
+/// The status of a device, see [`NetMDInterface::status`] and
+/// [`NetMDInterface::full_status`]
+///
+/// The status block itself only holds the state of the disc. The other
+/// fields are read from the rest of the operating status and the disc by
+/// [`NetMDInterface::full_status`], and are `None` when they were not read or
+/// the device does not report them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusBlock {
+    /// The state of the disc, `0x40` once a disc is loaded and `0x80`
+    /// without one
+    pub disc_state: u8,
+    /// Whether the disc is write protected
+    pub write_protected: Option<bool>,
+    /// The mode the device will record in
+    pub recording_parameters: Option<RecordingParameters>,
+    /// What the device is doing, such as playing or paused
+    pub operating_status: Option<OperatingStatus>,
+    /// The current track, starting at 0
+    pub track: Option<u16>,
+    /// The number of tracks on the disc
+    pub track_count: Option<u16>,
+    /// The whole status block, including the bytes which are not known
+    pub raw: Vec<u8>,
+}
+
+impl StatusBlock {
+    pub(super) fn from_raw(raw: Vec<u8>) -> Result<Self, InterfaceError> {
+        let disc_state = *raw
+            .get(4)
+            .ok_or(InterfaceError::MissingField("the disc state"))?;
+
+        Ok(Self {
+            disc_state,
+            write_protected: None,
+            recording_parameters: None,
+            operating_status: None,
+            track: None,
+            track_count: None,
+            raw,
+        })
+    }
+
+    /// Whether there is a disc in the device, even if it is still loading
+    pub fn disc_present(&self) -> bool {
+        self.disc_state != 0x80
+    }
+}
+
+/// A field of the operating status block, see
+/// [`NetMDInterface::playback_status1`] and
+/// [`NetMDInterface::playback_status2`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaybackStatus {
+    /// The ID of the field, f. ex. `0x8806` for the operating status
+    pub field: u16,
+    /// The contents of the field, including the bytes which are not known
+    pub data: Vec<u8>,
+}
+
+impl PlaybackStatus {
+    pub(super) fn from_raw(raw: &[u8]) -> Result<Self, InterfaceError> {
+        let [field_high, field_low, length_high, length_low, data @ ..] = raw else {
+            return Err(InterfaceError::MissingField("the playback status"));
+        };
+        let field = u16::from_be_bytes([*field_high, *field_low]);
+        let length = u16::from_be_bytes([*length_high, *length_low]) as usize;
+
+        // Some devices report a length longer than the field, the data still
+        // starts at the same place
+        let data = match data.get(..length) {
+            Some(data) => data,
+            None => {
+                debug!(
+                    "Playback status field {:04x} has a length of {}, but only {} bytes",
+                    field,
+                    length,
+                    data.len()
+                );
+                data
+            }
+        };
+
+        Ok(Self {
+            field,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The operating status, if this is the operating status field
+    pub fn operating_status(&self) -> Option<OperatingStatus> {
+        match (self.field, &self.data[..]) {
+            (0x8806, [high, low, ..]) => Some(u16::from_be_bytes([*high, *low]).into()),
+            _ => None,
+        }
+    }
+
+    /// The recording mode, if this is the recording parameters field
+    pub fn recording_parameters(&self) -> Option<Result<RecordingParameters, InterfaceError>> {
+        match (self.field, &self.data[..]) {
+            (0x8805, [_, _, _, _, encoding, channels, ..]) => {
+                Some(RecordingParameters::from_bytes(*encoding, *channels))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The mode a device records in, see [`NetMDInterface::recording_parameters`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordingParameters {
+    pub encoding: Encoding,
+    pub channels: Channels,
+}
+
+impl RecordingParameters {
+    pub(super) fn from_bytes(encoding: u8, channels: u8) -> Result<Self, InterfaceError> {
+        let encoding = match encoding {
+            0x90 => Encoding::SP,
+            0x92 => Encoding::LP2,
+            0x93 => Encoding::LP4,
+            e => return Err(InterfaceError::InvalidEncoding(e)),
+        };
+
+        let channels = match channels {
+            0x00 => Channels::Stereo,
+            0x01 => Channels::Mono,
+            e => return Err(InterfaceError::InvalidChannels(e)),
+        };
+
+        Ok(Self { encoding, channels })
+    }
 }
 
 /// An error when encrypting packets
@@ -256,7 +584,7 @@ pub enum EncryptionError {
 /// An error for any action in the interface
 #[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum InterfaceError {
-    #[error("could not parse data from a device")]
+    #[error("could not parse data from a device: {0}")]
     QueryError(#[from] crate::netmd::query_utils::QueryError),
 
     #[error("communication with the device failed")]
@@ -265,6 +593,9 @@ pub enum InterfaceError {
     #[error("track {0} is in 2 groups")]
     GroupError(String),
 
+    #[error("invalid group in the disc title: {0}")]
+    InvalidGroup(String),
+
     #[error("encryption error: {0:?}")]
     EncryptionError(#[from] EncryptionError),
 
@@ -277,12 +608,31 @@ pub enum InterfaceError {
     #[error("invalid NetMD level: {0}")]
     InvalidLevel(u8),
 
+    #[error("{command} needs a {required:?} device, but the device is {actual:?}")]
+    UnsupportedLevel {
+        command: &'static str,
+        required: NetMDLevel,
+        actual: NetMDLevel,
+    },
+
+    #[error("the device sent a truncated descriptor")]
+    TruncatedDescriptor,
+
+    #[error("the device sent a reply without {0}")]
+    MissingField(&'static str),
+
     #[error("track encoding value {0} out of range (0x90..0x92)")]
     InvalidEncoding(u8),
 
     #[error("disc format value {0} out of range (0..6)")]
     InvalidDiscFormat(u8),
 
+    #[error("channel value {0} out of range (0..1)")]
+    InvalidChannels(u8),
+
+    #[error("track flag value {0:#04x} is not a known flag")]
+    InvalidTrackFlag(u8),
+
     #[error("the device rejected the message")]
     Rejected(String),
 
@@ -297,129 +647,374 @@ pub enum InterfaceError {
 
     #[error("the device replied with an unknown status")]
     Unknown(String),
+
+    #[error("the device does not support {0}")]
+    Unsupported(&'static str),
+
+    #[error("disc capacity flag {found:#04x} does not match {expected:#04x} for this device")]
+    UnexpectedCapacityFlag { expected: u8, found: u8 },
+
+    #[error("the secure session has not been started")]
+    NoSession,
 }
 
 /// An interface for interacting with a NetMD device
-pub struct NetMDInterface {
-    pub device: NetMD,
+pub struct NetMDInterface<
+    #[cfg(feature = "native-usb")] T = UsbTransport,
+    #[cfg(not(feature = "native-usb"))] T,
+> {
+    pub device: NetMD<T>,
+    level: Option<NetMDLevel>,
+    level_read: bool,
+    pending_closes: Vec<Descriptor>,
+    notify_pending: bool,
 }
 
-#[allow(dead_code)]
-impl NetMDInterface {
-    /// The maximum number of times to retry after an interim response
-    const MAX_INTERIM_READ_ATTEMPTS: u8 = 4;
-
-    /// The amount of time to wait after an interim response (in milliseconds)
-    const INTERIM_RESPONSE_RETRY_INTERVAL: u32 = 100;
-
+#[cfg(feature = "native-usb")]
+impl NetMDInterface<UsbTransport> {
     /// Get a new interface to a NetMD device
     pub async fn new(device: cross_usb::DeviceInfo) -> Result<Self, InterfaceError> {
-        let device = base::NetMD::new(device).await?;
-        Ok(NetMDInterface { device })
+        let transport = UsbTransport::new(device).await?;
+        let device = NetMD::new(transport)?;
+
+        Ok(NetMDInterface::from(device))
     }
+}
 
-    fn construct_multibyte(&mut self, buffer: &[u8], n: u8, offset: &mut usize) -> u32 {
-        let mut output: u32 = 0;
-        for _ in 0..n as usize {
-            output <<= 8;
-            output |= buffer[*offset] as u32;
-            *offset += 1;
+impl<T: Transport> From<NetMD<T>> for NetMDInterface<T> {
+    /// Create an interface from an already opened device.
+    ///
+    /// The [`NetMDLevel`] of the device is read before the first command
+    /// which needs a level, so commands it cannot perform are refused
+    /// without being sent.
+    fn from(value: NetMD<T>) -> Self {
+        Self {
+            device: value,
+            level: None,
+            level_read: false,
+            pending_closes: Vec::new(),
+            notify_pending: false,
         }
-        output
     }
+}
 
-    // TODO: Finish proper implementation
-    async fn disc_subunit_identifier(&mut self) -> Result<NetMDLevel, InterfaceError> {
-        self.change_descriptor_state(
-            &Descriptor::DiscSubunitIdentifier,
-            &DescriptorAction::OpenRead,
-        )
-        .await?;
-
-        let query = format_query("1809 00 ff00 0000 0000".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(
-            reply,
-            "1809 00 1000 %?%? %?%? %w %b %b %b %b %w %*".to_string(),
-        )?;
-
-        let _descriptor_length = res[0].to_i64().unwrap();
-        let _generation_id = res[1].to_i64().unwrap();
-        let size_of_list_id = res[2].to_i64().unwrap();
-        let _size_of_object_id = res[3].to_i64().unwrap();
-        let _size_of_object_position = res[4].to_i64().unwrap();
-        let amt_of_root_object_lists = res[5].to_i64().unwrap();
-        let buffer = res[6].to_vec().unwrap();
-        let mut root_objects: Vec<u32> = Vec::new();
-
-        let mut buffer_offset: usize = 0;
-
-        for _ in 0..amt_of_root_object_lists {
-            root_objects.push(self.construct_multibyte(
-                &buffer,
-                size_of_list_id as u8,
-                &mut buffer_offset,
-            ));
+/// An interface using a temporary [`RetryPolicy`], see
+/// [`NetMDInterface::with_retry_policy`].
+///
+/// The previous policy is restored when this is dropped.
+pub struct RetryPolicyGuard<'a, T: Transport> {
+    interface: &'a mut NetMDInterface<T>,
+    previous: RetryPolicy,
+}
+
+impl<T: Transport> std::ops::Deref for RetryPolicyGuard<'_, T> {
+    type Target = NetMDInterface<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.interface
+    }
+}
+
+impl<T: Transport> std::ops::DerefMut for RetryPolicyGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.interface
+    }
+}
+
+impl<T: Transport> Drop for RetryPolicyGuard<'_, T> {
+    fn drop(&mut self) {
+        self.interface.set_retry_policy(self.previous);
+    }
+}
+
+/// A descriptor which is open on the device, see
+/// [`NetMDInterface::open_descriptor`].
+///
+/// The descriptor should be closed with [`DescriptorGuard::close`]. If the
+/// guard is dropped without closing it, for example because a command
+/// failed, the descriptor is closed before the next query is sent.
+struct DescriptorGuard<'a, T: Transport> {
+    interface: &'a mut NetMDInterface<T>,
+    descriptor: Descriptor,
+    closed: bool,
+}
+
+impl<T: Transport> DescriptorGuard<'_, T> {
+    /// Close the descriptor now, reporting any error from the device
+    async fn close(mut self) -> Result<(), InterfaceError> {
+        self.closed = true;
+
+        let descriptor = self.descriptor;
+        self.interface
+            .change_descriptor_state(&descriptor, &DescriptorAction::Close)
+            .await
+    }
+}
+
+impl<T: Transport> std::ops::Deref for DescriptorGuard<'_, T> {
+    type Target = NetMDInterface<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.interface
+    }
+}
+
+impl<T: Transport> std::ops::DerefMut for DescriptorGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.interface
+    }
+}
+
+impl<T: Transport> Drop for DescriptorGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.interface.pending_closes.push(self.descriptor);
+        }
+    }
+}
+
+/// A notify command registered with the device, see
+/// [`NetMDInterface::notify`].
+///
+/// The device answers a notify with an interim reply holding the current
+/// state, and later with a changed reply once that state changes. Each call
+/// to [`Notification::next`] registers the command again if needed, so this
+/// can be used as a stream of changes, also with [`Notification::into_stream`].
+///
+/// USB gives the device no way to send a reply by itself, so the device is
+/// still polled every [`Notification::interval`], 200ms by default, while
+/// waiting for a change. Only the poll is sent, which is much cheaper than
+/// reading the whole status each time.
+///
+/// Nothing else can be sent to the device while this exists. Once it is
+/// dropped, any descriptor it opened is closed and a late reply is
+/// discarded before the next command.
+pub struct Notification<'a, T: Transport> {
+    interface: &'a mut NetMDInterface<T>,
+    command: Vec<u8>,
+    descriptor: Option<Descriptor>,
+    interval: Duration,
+    interim: Option<Reply>,
+    registered: bool,
+}
+
+impl<'a, T: Transport> Notification<'a, T> {
+    /// The time between polls of the device while waiting for a change
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Sets the time between polls of the device while waiting for a change
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// The interim reply to the last time the command was registered, which
+    /// holds the state at that time
+    pub fn interim(&self) -> Option<&Reply> {
+        self.interim.as_ref()
+    }
+
+    /// Wait for the device to report a change, returning the changed reply.
+    ///
+    /// A rejected notify is returned as an error, which usually means the
+    /// device can not report this kind of change. The command is left
+    /// unregistered after an error, until this is called again.
+    pub async fn next(&mut self) -> Result<Reply, InterfaceError> {
+        // Without a timeout, waiting only ends with a change or an error
+        loop {
+            if let Some(reply) = self.wait(Duration::MAX).await? {
+                return Ok(reply);
+            }
         }
+    }
+
+    /// Wait for the device to report a change like [`Notification::next`],
+    /// giving up with `None` after polling for about `timeout`.
+    ///
+    /// The command stays registered after a timeout, so a change can still
+    /// be waited for afterwards.
+    pub async fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Reply>, InterfaceError> {
+        self.wait(timeout).await
+    }
 
-        let _subunit_dependent_length = self.construct_multibyte(&buffer, 2, &mut buffer_offset);
-        let _subunit_fields_length = self.construct_multibyte(&buffer, 2, &mut buffer_offset);
-        let _attributes = buffer[buffer_offset];
-        buffer_offset += 1;
-        let _disc_subunit_version = buffer[buffer_offset];
-        buffer_offset += 1;
-
-        let mut supported_media_type_specifications: Vec<MediaInfo> = Vec::new();
-        let amt_supported_media_types = buffer[buffer_offset];
-        buffer_offset += 1;
-        for _ in 0..amt_supported_media_types {
-            let supported_media_type = self.construct_multibyte(&buffer, 2, &mut buffer_offset);
-
-            let implementation_profile_id = buffer[buffer_offset];
-            buffer_offset += 1;
-            let media_type_attributes = buffer[buffer_offset];
-            buffer_offset += 1;
-
-            let _type_dep_length = self.construct_multibyte(&buffer, 2, &mut buffer_offset);
-
-            let md_audio_version = buffer[buffer_offset];
-            buffer_offset += 1;
-            let supports_md_clip = buffer[buffer_offset];
-            buffer_offset += 1;
-
-            supported_media_type_specifications.push(MediaInfo {
-                supported_media_type,
-                implementation_profile_id,
-                media_type_attributes,
-                md_audio_version,
-                supports_md_clip,
-            })
+    /// Turn the notification into a [`Stream`] of changed replies
+    pub fn into_stream(self) -> Notifications<'a, T> {
+        Notifications {
+            notification: Some(self),
+            next: None,
         }
+    }
 
-        let manufacturer_dep_length = self.construct_multibyte(&buffer, 2, &mut buffer_offset);
-        let _manufacturer_dep_data =
-            &buffer[buffer_offset..buffer_offset + manufacturer_dep_length as usize];
+    async fn wait(&mut self, timeout: Duration) -> Result<Option<Reply>, InterfaceError> {
+        if !self.registered {
+            self.register().await?;
+        }
 
-        self.change_descriptor_state(&Descriptor::DiscSubunitIdentifier, &DescriptorAction::Close)
-            .await?;
+        // Only the time spent sleeping is counted, see `RetryPolicy::max_total_delay`
+        let mut waited = Duration::ZERO;
+        loop {
+            let (length, _) = self.interface.device.poll().await?;
+            if length == 0 {
+                if waited >= timeout {
+                    return Ok(None);
+                }
 
-        for media in supported_media_type_specifications {
-            if media.supported_media_type != 0x301 {
+                cross_sleep(self.interval).await;
+                waited = waited.saturating_add(self.interval);
                 continue;
             }
 
-            return NetMDLevel::try_from(media.implementation_profile_id);
+            let reply = Reply::from_data(self.interface.device.read_reply(None).await?)?;
+            if reply.status == NetmdStatus::Interim {
+                continue;
+            }
+
+            self.registered = false;
+            self.interface.notify_pending = false;
+
+            return match reply.status {
+                NetmdStatus::Changed => Ok(Some(reply)),
+                _ => Err(reply.into_error()),
+            };
         }
-        Err(InterfaceError::NoSupportedMedia)
+    }
+
+    async fn register(&mut self) -> Result<(), InterfaceError> {
+        let reply = self
+            .interface
+            .raw_command(CommandType::Notify, &self.command)
+            .await?;
+
+        if reply.status != NetmdStatus::Interim {
+            return Err(match reply.accepted() {
+                Ok(reply) => InterfaceError::Unknown(format!("{:02X?}", reply.data)),
+                Err(error) => error,
+            });
+        }
+
+        self.interim = Some(reply);
+        self.registered = true;
+        self.interface.notify_pending = true;
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> Drop for Notification<'_, T> {
+    fn drop(&mut self) {
+        if let Some(descriptor) = self.descriptor {
+            self.interface.pending_closes.push(descriptor);
+        }
+    }
+}
+
+type NextNotification<'a, T> =
+    Pin<Box<dyn Future<Output = (Notification<'a, T>, Result<Reply, InterfaceError>)> + 'a>>;
+
+/// A [`Stream`] of the changed replies to a [`Notification`], see
+/// [`Notification::into_stream`].
+///
+/// The stream ends after an error, which is returned as its last item, and
+/// the notification is dropped.
+pub struct Notifications<'a, T: Transport> {
+    notification: Option<Notification<'a, T>>,
+    next: Option<NextNotification<'a, T>>,
+}
+
+impl<T: Transport> Stream for Notifications<'_, T> {
+    type Item = Result<Reply, InterfaceError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(mut notification) = self.notification.take() {
+            self.next = Some(Box::pin(async move {
+                let reply = notification.next().await;
+                (notification, reply)
+            }));
+        }
+
+        let Some(next) = self.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match next.as_mut().poll(cx) {
+            Poll::Ready((notification, reply)) => {
+                self.next = None;
+                if reply.is_ok() {
+                    self.notification = Some(notification);
+                }
+                Poll::Ready(Some(reply))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Transport> NetMDInterface<T> {
+    /// Gets the capabilities and quirks of the device
+    pub fn capabilities(&self) -> &Capabilities {
+        self.device.capabilities()
+    }
+
+    /// Gets the policy used when waiting for the device
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        self.device.retry_policy()
+    }
+
+    /// Sets the policy used when waiting for the device
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.device.set_retry_policy(policy)
+    }
+
+    /// Use a different retry policy until the returned guard is dropped.
+    ///
+    /// ```no_run
+    /// # use minidisc::netmd::{NetMDInterface, base::RetryPolicy};
+    /// # use std::time::Duration;
+    /// # async fn example(interface: &mut NetMDInterface) -> Result<(), Box<dyn std::error::Error>> {
+    /// let patient = RetryPolicy {
+    ///     max_delay: Duration::from_secs(5),
+    ///     ..Default::default()
+    /// };
+    /// interface.with_retry_policy(patient).erase_disc().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_retry_policy(&mut self, policy: RetryPolicy) -> RetryPolicyGuard<'_, T> {
+        let previous = *self.retry_policy();
+        self.set_retry_policy(policy);
+
+        RetryPolicyGuard {
+            interface: self,
+            previous,
+        }
+    }
+
+    /// Read the disc subunit identifier, which describes the media the
+    /// device supports
+    pub async fn disc_subunit_identifier(&mut self) -> Result<SubunitIdentifier, InterfaceError> {
+        let mut descriptor = self
+            .open_descriptor(
+                Descriptor::DiscSubunitIdentifier,
+                DescriptorAction::OpenRead,
+            )
+            .await?;
+
+        let identifier = descriptor.run(protocol::disc_subunit_identifier()?).await;
+
+        descriptor.close().await?;
+
+        identifier
     }
 
     /* TODO: Finish implementation
     fn factory(&mut self) -> Result<NetMDLevel, Box<dyn Error>> {
         let device_name = self.net_md_device.device_name().expect("The device has no name");
 
-        let himd = device_name.contains("MZ-RH") || device_name.contains("MZ-NH");
+        let himd = self.capabilities().himd;
 
         self.disc_subunit_identifier()?;
 
@@ -427,10 +1022,49 @@ impl NetMDInterface {
     }
     */
 
-    async fn net_md_level(&mut self) -> Result<NetMDLevel, InterfaceError> {
-        let result = self.disc_subunit_identifier().await?;
+    /// Get the [`NetMDLevel`] of the device, which is only read once
+    pub async fn net_md_level(&mut self) -> Result<NetMDLevel, InterfaceError> {
+        if let Some(level) = self.level {
+            return Ok(level);
+        }
+
+        self.level_read = true;
+        let level = self.disc_subunit_identifier().await?.level()?;
+        self.level = Some(level);
+
+        Ok(level)
+    }
+
+    /// Read the level of the device, unless that was already tried. Errors
+    /// are only logged, as a device which doesn't report its level is
+    /// allowed to try anything.
+    async fn read_level(&mut self) {
+        if self.level_read {
+            return;
+        }
 
-        Ok(result)
+        if let Err(error) = self.net_md_level().await {
+            debug!("Could not read the NetMD level: {}", error);
+        }
+    }
+
+    /// Refuse a command if the device is below the level needed for it,
+    /// reading the level first if needed
+    async fn require_level(
+        &mut self,
+        required: NetMDLevel,
+        command: &'static str,
+    ) -> Result<(), InterfaceError> {
+        self.read_level().await;
+
+        match self.level {
+            Some(actual) if actual < required => Err(InterfaceError::UnsupportedLevel {
+                command,
+                required,
+                actual,
+            }),
+            _ => Ok(()),
+        }
     }
 
     async fn change_descriptor_state(
@@ -438,105 +1072,260 @@ impl NetMDInterface {
         descriptor: &Descriptor,
         action: &DescriptorAction,
     ) -> Result<(), InterfaceError> {
-        let mut query = format_query("1808".to_string(), vec![]).unwrap();
+        let command = protocol::descriptor_state(&descriptor.get_array(), *action)?;
+
+        self.run(command).await
+    }
+
+    /// Open a descriptor, returning a guard which closes it again.
+    ///
+    /// Commands which use the descriptor are sent through the guard.
+    async fn open_descriptor(
+        &mut self,
+        descriptor: Descriptor,
+        action: DescriptorAction,
+    ) -> Result<DescriptorGuard<'_, T>, InterfaceError> {
+        self.change_descriptor_state(&descriptor, &action).await?;
+
+        Ok(DescriptorGuard {
+            interface: self,
+            descriptor,
+            closed: false,
+        })
+    }
 
-        query.append(&mut descriptor.get_array());
+    /// Clean up after a dropped [`DescriptorGuard`] or [`Notification`],
+    /// by closing any descriptor left open and discarding a late reply to a
+    /// notify.
+    ///
+    /// This is best effort, as the device may have cleaned up itself.
+    async fn settle(&mut self) {
+        if self.notify_pending {
+            self.notify_pending = false;
+            self.discard_waiting_reply().await;
+        }
+
+        for descriptor in std::mem::take(&mut self.pending_closes) {
+            debug!("Closing descriptor {:?} left open", descriptor);
 
-        query.push(*action as u8);
+            let close =
+                protocol::descriptor_state(&descriptor.get_array(), DescriptorAction::Close);
+            let result = match close {
+                Ok(command) => self.drive(command).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                warn!("Could not close descriptor {:?}: {}", descriptor, error);
+            }
+        }
+    }
 
-        query.push(0x00);
+    async fn discard_waiting_reply(&mut self) {
+        let waiting = match self.device.poll().await {
+            Ok((length, _)) => length > 0,
+            Err(error) => {
+                warn!("Could not poll for a reply to a notify: {}", error);
+                false
+            }
+        };
 
-        match self.send_query(&query, false, false).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
+        if waiting {
+            match self.device.read_reply(None).await {
+                Ok(data) => debug!("Discarding reply to a notify: {}", dissect(&data)),
+                Err(error) => warn!("Could not read a reply to a notify: {}", error),
+            }
         }
     }
 
-    /// Send a query to the NetMD player
-    async fn send_query(
-        &mut self,
-        query: &[u8],
-        test: bool,
-        accept_interim: bool,
-    ) -> Result<Vec<u8>, InterfaceError> {
-        self.send_command(query, test).await?;
+    /// Run a command from the [`protocol`] on the device
+    async fn run<O>(&mut self, command: Command<O>) -> Result<O, InterfaceError> {
+        self.settle().await;
 
-        let result = self.read_reply(accept_interim).await?;
+        self.drive(command).await
+    }
 
-        Ok(result)
+    /// Run a command with the current retry policy, without settling first
+    async fn drive<O>(&mut self, command: Command<O>) -> Result<O, InterfaceError> {
+        let policy = *self.device.retry_policy();
+
+        self.device.drive(command.with_policy(policy)).await
     }
 
-    async fn send_command(
+    /// Send a command which is not wrapped by this crate and read its reply.
+    ///
+    /// The command is everything after the status byte, which is set from
+    /// `command_type`. The reply is returned whatever its status is, so use
+    /// [`Reply::accepted`] to treat a rejection as an error. Notify commands
+    /// return the interim reply, see [`NetMDInterface::read_raw_reply`] to
+    /// wait for the change.
+    ///
+    /// ```no_run
+    /// # use minidisc::netmd::NetMDInterface;
+    /// # use minidisc::netmd::interface::CommandType;
+    /// # use minidisc::netmd::query_utils::{QueryTemplate, QueryValue};
+    /// # async fn example(interface: &mut NetMDInterface) -> Result<(), Box<dyn std::error::Error>> {
+    /// // Ask whether the device can eject the disc, without ejecting it
+    /// let reply = interface
+    ///     .raw_command(CommandType::GeneralInquiry, &[0x18, 0xc1, 0xff, 0x60, 0x00])
+    ///     .await?;
+    /// println!("{:?}", reply.status);
+    ///
+    /// // Templates from the query module work for raw commands too
+    /// let template = QueryTemplate::new("1850 ff010000 0000 %w")?;
+    /// let reply = interface
+    ///     .raw_command(CommandType::Control, &template.encode(&[QueryValue::Number(3)])?)
+    ///     .await?
+    ///     .accepted()?;
+    /// println!("{:02x?}", reply.body());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn raw_command(
         &mut self,
-        query: &[u8],
-        test: bool,
-    ) -> Result<(), InterfaceError> {
-        let status_byte = match test {
-            true => NetmdStatus::GeneralInquiry,
-            false => NetmdStatus::Control,
-        };
+        command_type: CommandType,
+        command: &[u8],
+    ) -> Result<Reply, InterfaceError> {
+        self.settle().await;
 
-        let mut new_query = Vec::new();
+        let exchange = Exchange::new(command_type, command, *self.device.retry_policy());
+        self.device.drive(exchange).await
+    }
+
+    /// Send a command without reading its reply.
+    ///
+    /// The command is everything after the status byte, which is set from
+    /// `command_type`. The reply must be read with
+    /// [`NetMDInterface::read_raw_reply`] before sending anything else.
+    pub async fn send_raw_command(
+        &mut self,
+        command_type: CommandType,
+        command: &[u8],
+    ) -> Result<(), InterfaceError> {
+        let mut new_query = Vec::with_capacity(command.len() + 1);
 
-        new_query.push(status_byte as u8);
-        new_query.extend_from_slice(query);
+        new_query.push(NetmdStatus::from(command_type) as u8);
+        new_query.extend_from_slice(command);
 
+        trace!("SENT>>> {}", dissect(&new_query));
         self.device.send_command(new_query).await?;
 
         Ok(())
     }
 
-    async fn read_reply(&mut self, accept_interim: bool) -> Result<Vec<u8>, InterfaceError> {
-        let mut current_attempt = 0;
-        let mut data;
+    /// Read the reply to the last command, whatever its status is.
+    ///
+    /// Interim replies are retried following the [`RetryPolicy`], unless
+    /// `accept_interim` is set.
+    pub async fn read_raw_reply(&mut self, accept_interim: bool) -> Result<Reply, InterfaceError> {
+        let exchange = Exchange::reply(*self.device.retry_policy()).accept_interim(accept_interim);
 
-        while current_attempt < Self::MAX_INTERIM_READ_ATTEMPTS {
-            data = self.device.read_reply(None).await?;
+        self.device.drive(exchange).await
+    }
 
-            let status = NetmdStatus::try_from(data[0])?;
-            debug!("Device status: {:?}", status);
+    /// Open or close a descriptor by its raw ID, f. ex. `[0x10, 0x18, 0x01]`
+    /// for the disc title.
+    ///
+    /// Unlike the descriptors used by this crate, nothing closes a raw
+    /// descriptor if a later command fails.
+    pub async fn raw_descriptor_state(
+        &mut self,
+        descriptor_id: &[u8],
+        action: DescriptorAction,
+    ) -> Result<Reply, InterfaceError> {
+        let command = protocol::descriptor_state(descriptor_id, action)?;
 
-            match status {
-                NetmdStatus::NotImplemented => {
-                    return Err(InterfaceError::NotImplemented(format!("{:02X?}", data)))
-                }
-                NetmdStatus::Rejected => {
-                    return Err(InterfaceError::Rejected(format!("{:02X?}", data)))
-                }
-                NetmdStatus::Interim if !accept_interim => {
-                    let sleep_time = Self::INTERIM_RESPONSE_RETRY_INTERVAL
-                        * (u32::pow(2, current_attempt as u32) - 1);
+        self.raw_command(CommandType::Control, command.query())
+            .await
+    }
 
-                    cross_sleep(Duration::from_millis(sleep_time as u64)).await;
+    /// Read from a descriptor by its raw ID.
+    ///
+    /// The descriptor is opened for reading, `command` is sent as a control
+    /// command, and the descriptor is closed again even if the command was
+    /// rejected.
+    pub async fn read_raw_descriptor(
+        &mut self,
+        descriptor_id: &[u8],
+        command: &[u8],
+    ) -> Result<Reply, InterfaceError> {
+        self.raw_descriptor_state(descriptor_id, DescriptorAction::OpenRead)
+            .await?
+            .accepted()?;
 
-                    current_attempt += 1;
-                    continue; // Retry!
-                }
-                NetmdStatus::Accepted | NetmdStatus::Implemented | NetmdStatus::Interim => {
-                    if current_attempt >= Self::MAX_INTERIM_READ_ATTEMPTS {
-                        return Err(InterfaceError::MaxRetries);
-                    }
-                    return Ok(data);
-                }
-                _ => return Err(InterfaceError::Unknown(format!("{:02X?}", data))),
-            }
-        }
+        let reply = self.raw_command(CommandType::Control, command).await;
 
-        // This should NEVER happen unless the code is changed wrongly
-        unreachable!("The max number of retries is set to 0!")
+        self.raw_descriptor_state(descriptor_id, DescriptorAction::Close)
+            .await?
+            .accepted()?;
+
+        reply
     }
 
-    async fn playback_control(&mut self, action: Action) -> Result<(), InterfaceError> {
-        let query = format_query(
-            "18c3 ff %b 000000".to_string(),
-            vec![QueryValue::Number(action as i64)],
-        )?;
+    /// Register a raw notify command, see [`Notification`].
+    ///
+    /// The command is everything after the status byte, like
+    /// [`NetMDInterface::raw_command`]. Any descriptor it reads from must be
+    /// opened first.
+    pub async fn notify(&mut self, command: &[u8]) -> Result<Notification<'_, T>, InterfaceError> {
+        self.start_notify(None, command.to_vec()).await
+    }
 
-        let reply = self.send_query(&query, false, false).await?;
+    /// Be notified when the operating status of the device changes, for
+    /// example when a button on the device is pressed.
+    ///
+    /// The replies can be scanned like [`NetMDInterface::operating_status`].
+    pub async fn notify_operating_status(&mut self) -> Result<Notification<'_, T>, InterfaceError> {
+        let command = protocol::operating_status()?;
+
+        self.start_notify(
+            Some(Descriptor::OperatingStatusBlock),
+            command.query().to_vec(),
+        )
+        .await
+    }
 
-        scan_query(reply, "18c3 00 %b 000000".to_string())?;
+    /// Be notified when the status block changes, which includes a disc being
+    /// inserted or ejected.
+    ///
+    /// The replies can be scanned like [`NetMDInterface::status`].
+    pub async fn notify_status(&mut self) -> Result<Notification<'_, T>, InterfaceError> {
+        let command = protocol::status()?;
 
-        Ok(())
+        self.start_notify(
+            Some(Descriptor::OperatingStatusBlock),
+            command.query().to_vec(),
+        )
+        .await
+    }
+
+    async fn start_notify(
+        &mut self,
+        descriptor: Option<Descriptor>,
+        command: Vec<u8>,
+    ) -> Result<Notification<'_, T>, InterfaceError> {
+        if let Some(descriptor) = descriptor {
+            self.change_descriptor_state(&descriptor, &DescriptorAction::OpenRead)
+                .await?;
+        }
+
+        let mut notification = Notification {
+            interface: self,
+            command,
+            descriptor,
+            interval: Duration::from_millis(200),
+            interim: None,
+            registered: false,
+        };
+        notification.register().await?;
+
+        Ok(notification)
+    }
+
+    async fn playback_control(&mut self, action: Action) -> Result<(), InterfaceError> {
+        self.require_level(NetMDLevel::Level2, "playback control")
+            .await?;
+
+        self.run(protocol::playback_control(action)?).await
     }
 
     /// Begin playback or resume after paused
@@ -562,217 +1351,228 @@ impl NetMDInterface {
     //TODO: Implement fix for LAM-1
     /// Stop playback
     pub async fn stop(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("18c5 ff 00000000".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "18c5 00 00000000".to_string())?;
+        self.require_level(NetMDLevel::Level2, "stop").await?;
 
-        Ok(())
+        self.run(protocol::stop()?).await
     }
 
     pub async fn acquire(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("ff 010c ffff ffff ffff ffff ffff ffff".to_string(), vec![])?;
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "ff 010c ffff ffff ffff ffff ffff ffff".to_string())?;
-
-        Ok(())
+        self.run(protocol::acquire()?).await
     }
 
     pub async fn release(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("ff 0100 ffff ffff ffff ffff ffff ffff".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "ff 0100 ffff ffff ffff ffff ffff ffff".to_string())?;
-
-        Ok(())
+        self.run(protocol::release()?).await
     }
 
-    pub async fn status(&mut self) -> Result<Vec<u8>, InterfaceError> {
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::OpenRead,
-        )
-        .await?;
-
-        let query = format_query(
-            "1809 8001 0230 8800 0030 8804 00 ff00 00000000".to_string(),
-            vec![],
-        )?;
+    /// Read the status block, which holds the state of the disc
+    pub async fn status(&mut self) -> Result<StatusBlock, InterfaceError> {
+        let mut descriptor = self
+            .open_descriptor(Descriptor::OperatingStatusBlock, DescriptorAction::OpenRead)
+            .await?;
 
-        let reply = self.send_query(&query, false, false).await?;
+        let status = descriptor.run(protocol::status()?).await?;
 
-        let res = scan_query(
-            reply,
-            "1809 8001 0230 8800 0030 8804 00 1000 00090000 %x".to_string(),
-        )?;
+        descriptor.close().await?;
 
-        self.change_descriptor_state(&Descriptor::OperatingStatusBlock, &DescriptorAction::Close)
-            .await?;
+        Ok(status)
+    }
 
-        let final_array = res[0].to_vec().unwrap();
+    /// Read the status block along with every other known status field,
+    /// see [`StatusBlock`]
+    ///
+    /// The recording parameters and operating status can't be read from
+    /// every device, so they are left out when reading them fails.
+    pub async fn full_status(&mut self) -> Result<StatusBlock, InterfaceError> {
+        let mut status = self.status().await?;
+
+        status.recording_parameters = self.recording_parameters().await.ok();
+        status.operating_status = self
+            .playback_status2()
+            .await
+            .ok()
+            .and_then(|p| p.operating_status());
+
+        if status.disc_present() {
+            let flags = self.disc_flags().await?;
+            status.write_protected = Some((flags & DiscFlag::WriteProtected as u8) != 0);
+            status.track_count = Some(self.track_count().await?);
+            status.track = Some(self.position().await?[0]);
+        }
 
-        Ok(final_array)
+        Ok(status)
     }
 
     /// Check if a disc is loaded in the player
     pub async fn disc_present(&mut self) -> Result<bool, InterfaceError> {
         let status = self.status().await?;
 
-        Ok(status[4] == 0x40)
+        Ok(status.disc_state == 0x40)
     }
 
     async fn full_operating_status(&mut self) -> Result<(u8, u16), InterfaceError> {
         // WARNING: Does not work for all devices. See https://github.com/cybercase/webminidisc/issues/21
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::OpenRead,
-        )
-        .await?;
-
-        let query = format_query(
-            "1809 8001 0330 8802 0030 8805 0030 8806 00 ff00 00000000".to_string(),
-            vec![],
-        )
-        .unwrap();
-        let reply = self.send_query(&query, false, false).await?;
-
-        let result = scan_query(
-            reply,
-            "1809 8001 0330 8802 0030 8805 0030 8806 00 1000 00%?0000 00%b 8806 %x".to_string(),
-        )?;
-
-        let operating_status = result[1].to_vec().unwrap();
-        let status_mode = result[0].to_i64().unwrap() as u8;
-
-        self.change_descriptor_state(&Descriptor::OperatingStatusBlock, &DescriptorAction::Close)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::OperatingStatusBlock, DescriptorAction::OpenRead)
             .await?;
 
-        if operating_status.len() < 2 && !operating_status.is_empty() {
-            return Err(InterfaceError::InvalidStatus(StatusError(operating_status[0] as u16)));
-        }
+        let status = descriptor.run(protocol::operating_status()?).await?;
 
-        let operating_status_number =
-            (operating_status[0] as u16) << 8 | operating_status[1] as u16;
+        descriptor.close().await?;
 
-        Ok((status_mode, operating_status_number))
+        Ok(status)
     }
 
-    pub async fn operating_status(&mut self) -> Result<u16, InterfaceError> {
+    pub async fn operating_status(&mut self) -> Result<OperatingStatus, InterfaceError> {
         let status = self.full_operating_status().await?.1;
 
-        Ok(status)
+        Ok(status.into())
     }
 
-    async fn playback_status_query(&mut self, p1: u32, p2: u32) -> Result<Vec<u8>, InterfaceError> {
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::OpenRead,
-        )
-        .await?;
-
-        let query = format_query(
-            "1809 8001 0330 %w 0030 8805 0030 %w 00 ff00 00000000".to_string(),
-            vec![QueryValue::Number(p1 as i64), QueryValue::Number(p2 as i64)],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
+    async fn playback_status_query(
+        &mut self,
+        p1: u32,
+        p2: u32,
+    ) -> Result<PlaybackStatus, InterfaceError> {
+        let mut descriptor = self
+            .open_descriptor(Descriptor::OperatingStatusBlock, DescriptorAction::OpenRead)
+            .await?;
 
-        let res = scan_query(
-            reply,
-            "1809 8001 0330 %?%? %?%? %?%? %?%? %?%? %? 1000 00%?0000 %x %?".to_string(),
-        )?;
+        let status = descriptor.run(protocol::playback_status(p1, p2)?).await?;
 
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::Close
-        )
-        .await?;
+        descriptor.close().await?;
 
-        Ok(res[0].to_vec().unwrap())
+        Ok(status)
     }
 
-    pub async fn playback_status1(&mut self) -> Result<Vec<u8>, InterfaceError> {
+    /// Read the first playback status, which holds the recording parameters
+    pub async fn playback_status1(&mut self) -> Result<PlaybackStatus, InterfaceError> {
         self.playback_status_query(0x8801, 0x8807).await
     }
 
-    pub async fn playback_status2(&mut self) -> Result<Vec<u8>, InterfaceError> {
+    /// Read the second playback status, which holds the operating status
+    pub async fn playback_status2(&mut self) -> Result<PlaybackStatus, InterfaceError> {
         self.playback_status_query(0x8802, 0x8806).await
     }
 
     /// Get the current playback position
     pub async fn position(&mut self) -> Result<[u16; 5], InterfaceError> {
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::OpenRead,
-        ).await?;
-
-        let query = format_query(
-            "1809 8001 0430 8802 0030 8805 0030 0003 0030 0002 00 ff00 00000000".to_string(),
-            vec![],
-        )
-        .unwrap();
+        let mut descriptor = self
+            .open_descriptor(Descriptor::OperatingStatusBlock, DescriptorAction::OpenRead)
+            .await?;
 
         //TODO: Ensure this is ok, we might want to do proper error handling
-        let reply = match self.send_query(&query, false, false).await {
-            Ok(d) => d,
+        let position = match descriptor.run(protocol::position()?).await {
+            Ok(position) => position,
+            Err(error @ InterfaceError::QueryError(_)) => return Err(error),
             Err(_) => return Ok([0u16; 5]),
         };
 
-        let result = scan_query(reply, "1809 8001 0430 %?%? %?%? %?%? %?%? %?%? %?%? %?%? %? %?00 00%?0000 000b 0002 0007 00 %w %B %B %B %B".to_string())?;
-
-        let final_result = [
-            result[0].to_i64().unwrap() as u16,
-            result[1].to_i64().unwrap() as u16,
-            result[2].to_i64().unwrap() as u16,
-            result[3].to_i64().unwrap() as u16,
-            result[4].to_i64().unwrap() as u16,
-        ];
-
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::Close
-        ).await?;
+        descriptor.close().await?;
 
-        Ok(final_result)
+        Ok(position)
     }
 
     /// Eject the disc from the player if supported
     pub async fn eject_disc(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("18c1 ff 6000".to_string(), vec![]).unwrap();
-
-        let _reply = self.send_query(&query, false, false).await?;
+        if !self.device.capabilities().eject {
+            return Err(InterfaceError::Unsupported("eject"));
+        }
 
-        Ok(())
+        self.run(protocol::eject_disc()?).await
     }
 
     /// Check if the machine has the capability to eject a disc
     pub async fn can_eject_disc(&mut self) -> Result<bool, InterfaceError> {
-        let query = format_query("18c1 ff 6000".to_string(), vec![]).unwrap();
+        self.inquire(protocol::eject_disc()?).await
+    }
 
-        match self.send_query(&query, true, false).await {
-            Ok(_) => Ok(true),
-            Err(error) => Err(error),
+    /// Ask the device whether it supports a command, without running it
+    async fn inquire<O>(&mut self, command: Command<O>) -> Result<bool, InterfaceError> {
+        self.settle().await;
+
+        let policy = *self.device.retry_policy();
+        self.device
+            .drive(command.inquiry().with_policy(policy))
+            .await
+    }
+
+    /// Ask the device which commands it supports.
+    ///
+    /// This only sends general inquiries, so none of the commands are
+    /// actually run and nothing on the disc is changed.
+    pub async fn probe_commands(&mut self) -> Result<CommandSupport, InterfaceError> {
+        Ok(CommandSupport {
+            play: self
+                .probe("play", protocol::playback_control(Action::Play)?)
+                .await?,
+            pause: self
+                .probe("pause", protocol::playback_control(Action::Pause)?)
+                .await?,
+            fast_forward: self
+                .probe(
+                    "fast forward",
+                    protocol::playback_control(Action::FastForward)?,
+                )
+                .await?,
+            rewind: self
+                .probe("rewind", protocol::playback_control(Action::Rewind)?)
+                .await?,
+            stop: self.probe("stop", protocol::stop()?).await?,
+            go_to_track: self.probe("go to track", protocol::go_to_track(0)?).await?,
+            go_to_time: self
+                .probe("go to time", protocol::go_to_time(0, 0, 0, 0, 0)?)
+                .await?,
+            track_change: self
+                .probe("track change", protocol::track_change(Direction::Next)?)
+                .await?,
+            eject_disc: self.probe("eject disc", protocol::eject_disc()?).await?,
+            erase_disc: self.probe("erase disc", protocol::erase_disc()?).await?,
+            erase_track: self.probe("erase track", protocol::erase_track(0)?).await?,
+            move_track: self
+                .probe("move track", protocol::move_track(0, 1)?)
+                .await?,
+            set_disc_title: self
+                .probe("set disc title", protocol::set_disc_title("", "", false)?)
+                .await?,
+            set_track_title: self
+                .probe(
+                    "set track title",
+                    protocol::set_track_title(0, "", "", false)?,
+                )
+                .await?,
+            save_track: self.probe("save track", protocol::save_track(0)?).await?,
+            secure_session: self
+                .probe("secure session", protocol::enter_secure_session()?)
+                .await?,
+        })
+    }
+
+    /// Ask the device whether it supports a command for
+    /// [`NetMDInterface::probe_commands`], returning `None` if the device
+    /// didn't answer clearly. Only a disconnected device stops the probe.
+    async fn probe<O>(
+        &mut self,
+        command_name: &'static str,
+        command: Command<O>,
+    ) -> Result<Option<bool>, InterfaceError> {
+        match self.inquire(command).await {
+            Ok(supported) => Ok(Some(supported)),
+            Err(InterfaceError::CommunicationError(NetMDError::Disconnected)) => {
+                Err(NetMDError::Disconnected.into())
+            }
+            Err(error) => {
+                debug!("Could not ask if {} is supported: {}", command_name, error);
+                Ok(None)
+            }
         }
     }
 
     /// Move the playback to a specific track
     pub async fn go_to_track(&mut self, track_number: u16) -> Result<u16, InterfaceError> {
-        let query = format_query(
-            "1850 ff010000 0000 %w".to_string(),
-            vec![QueryValue::Number(track_number as i64)],
-        )
-        .unwrap();
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(reply, "1850 00010000 0000 %w".to_string())?;
-
-        let value = res[0].to_i64().unwrap();
+        self.require_level(NetMDLevel::Level2, "go to track")
+            .await?;
 
-        Ok(value as u16)
+        self.run(protocol::go_to_track(track_number)?).await
     }
 
     /// Move the playback to a specific time
@@ -784,156 +1584,75 @@ impl NetMDInterface {
         second: u8,
         frame: u8,
     ) -> Result<u16, InterfaceError> {
-        let query = format_query(
-            "1850 ff000000 0000 %w %B%B%B%B".to_string(),
-            vec![
-                QueryValue::Number(track_number as i64),
-                QueryValue::Number(hour as i64),
-                QueryValue::Number(minute as i64),
-                QueryValue::Number(second as i64),
-                QueryValue::Number(frame as i64),
-            ],
-        )
-        .unwrap();
+        self.require_level(NetMDLevel::Level2, "go to time").await?;
 
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(reply, "1850 00000000 %?%? %w %B%B%B%B".to_string())?;
-
-        let value = res[0].to_i64().unwrap();
-
-        Ok(value as u16)
+        let command = protocol::go_to_time(track_number, hour, minute, second, frame)?;
+        self.run(command).await
     }
 
     /// Change track in a [`Direction`]
     pub async fn track_change(&mut self, direction: Direction) -> Result<(), InterfaceError> {
-        let query = format_query(
-            "1850 ff10 00000000 %w".to_string(),
-            vec![QueryValue::Number(direction as i64)],
-        )
-        .unwrap();
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "1850 0010 00000000 %?%?".to_string())?;
+        self.require_level(NetMDLevel::Level2, "track change")
+            .await?;
 
-        Ok(())
+        self.run(protocol::track_change(direction)?).await
     }
 
     /// Erase the disc entirely
     pub async fn erase_disc(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("1840 ff 0000".to_string(), vec![]).unwrap();
-        let reply = self.send_query(&query, false, false).await?;
-        scan_query(reply, "1840 00 0000".to_string())?;
-        Ok(())
+        self.require_level(NetMDLevel::Level3, "erase disc").await?;
+
+        self.run(protocol::erase_disc()?).await
     }
 
     // TODO: Ensure this is returning the correct value, it
     // looks like it actually might be a 16 bit integer
     /// Get the flags from a disc
     pub async fn disc_flags(&mut self) -> Result<u8, InterfaceError> {
-        self.change_descriptor_state(&Descriptor::RootTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::RootTD, DescriptorAction::OpenRead)
             .await?;
 
-        let query = format_query("1806 01101000 ff00 0001000b".to_string(), vec![]).unwrap();
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(reply, "1806 01101000 1000 0001000b %b".to_string()).unwrap();
+        let flags = descriptor.run(protocol::disc_flags()?).await?;
 
-        self.change_descriptor_state(&Descriptor::RootTD, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
-        Ok(res[0].to_i64().unwrap() as u8)
+        Ok(flags)
     }
 
     /// Get the number of tracks on  the disc
     pub async fn track_count(&mut self) -> Result<u16, InterfaceError> {
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
             .await?;
 
-        let query =
-            format_query("1806 02101001 3000 1000 ff00 00000000".to_string(), vec![]).unwrap();
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(
-            reply,
-            "1806 02101001 %?%? %?%? 1000 00%?0000 0006 0010000200%b".to_string(),
-        )
-        .unwrap();
+        let count = descriptor.run(protocol::track_count()?).await?;
 
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
-        Ok(res[0].to_i64().unwrap() as u16)
+        Ok(count)
     }
 
     /// Gets the disc title as it is stored
     pub async fn raw_disc_title(&mut self, wchar: bool) -> Result<String, InterfaceError> {
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::OpenRead)
+        let mut contents = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
             .await?;
-        self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::OpenRead)
+        let mut descriptor = contents
+            .open_descriptor(Descriptor::DiscTitleTD, DescriptorAction::OpenRead)
             .await?;
 
-        let mut done: i32 = 0;
-        let mut remaining: i32 = 0;
-        let mut total = 1;
-        let mut result: Vec<String> = Vec::new();
-        let mut chunksize;
-        let mut chunk;
-
-        while done < total {
-            let wchar_value = match wchar {
-                true => 1,
-                false => 0,
-            };
-
-            let query = format_query(
-                "1806 02201801 00%b 3000 0a00 ff00 %w%w".to_string(),
-                vec![
-                    QueryValue::Number(wchar_value),
-                    QueryValue::Number(remaining as i64),
-                    QueryValue::Number(done as i64),
-                ],
-            )
-            .unwrap();
-
-            let reply = self.send_query(&query, false, false).await?;
-
-            if remaining == 0 {
-                let res = scan_query(
-                    reply,
-                    "1806 02201801 00%? 3000 0a00 1000 %w0000 %?%?000a %w %*".to_string(),
-                )?;
-
-                chunksize = res[0].to_i64().unwrap() as i32;
-                total = res[1].to_i64().unwrap() as i32;
-                chunk = SHIFT_JIS.decode(&res[2].to_vec().unwrap()).0.into();
-
-                chunksize -= 6;
-            } else {
-                let res = scan_query(
-                    reply,
-                    "1806 02201801 00%? 3000 0a00 1000 %w%?%? %*".to_string(),
-                )?;
-                chunksize = res[0].to_i64().unwrap() as i32;
-                chunk = SHIFT_JIS.decode(&res[1].to_vec().unwrap()).0.into();
-            }
-
-            result.push(chunk);
-            done += chunksize;
-            remaining = total - done;
-        }
-
-        let res = result.join("");
-
-        self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::Close)
-            .await?;
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::Close)
+        descriptor.settle().await;
+        let policy = *descriptor.retry_policy();
+        let title = descriptor
+            .device
+            .drive(protocol::raw_disc_title(wchar).with_policy(policy))
             .await?;
 
-        Ok(res)
+        descriptor.close().await?;
+        contents.close().await?;
+
+        Ok(title)
     }
 
     /// Gets the disc title without group information
@@ -962,93 +1681,13 @@ impl NetMDInterface {
         Ok(title)
     }
 
-    /// Gets all groups on the disc
-    pub async fn track_group_list(
-        &mut self,
-    ) -> Result<Vec<(Option<String>, Option<String>, Vec<u16>)>, InterfaceError> {
+    /// Gets all groups on the disc, see [`parse_groups`]
+    pub async fn track_group_list(&mut self) -> Result<GroupList, InterfaceError> {
         let raw_title = self.raw_disc_title(false).await?;
-        let group_list = raw_title.split("//");
-        let mut track_dict: HashMap<u16, (String, u16)> = HashMap::new();
         let track_count = self.track_count().await?;
-        let mut result: Vec<(Option<String>, Option<String>, Vec<u16>)> = Vec::new();
-
         let raw_full_title = self.raw_disc_title(true).await?;
 
-        let mut full_width_group_list = raw_full_title.split("／／");
-
-        for (i, group) in group_list.enumerate() {
-            if group.is_empty() {
-                continue;
-            }
-
-            if group.starts_with("0;") || group.find(';').is_none() || !raw_title.contains("//") {
-                continue;
-            }
-
-            let track_range: String = match group.split_once(';') {
-                Some(string) => string.0.to_string(),
-                None => return Err(InterfaceError::GroupError("no groups were found".into())),
-            };
-            if track_range.is_empty() {
-                continue;
-            }
-
-            let group_name = &group[track_range.len() + 1..];
-
-            let full_width_range = half_width_to_full_width_range(&track_range);
-
-            let full_width_group_name = full_width_group_list
-                .find(|n| n.starts_with(&full_width_range))
-                .unwrap()
-                .split_once('；')
-                .unwrap()
-                .1;
-
-            let mut track_minmax: Vec<&str> = Vec::new();
-            if track_range.find('-').is_some() {
-                track_minmax = track_range.split('-').collect();
-            } else {
-                track_minmax.push(track_range.as_str());
-            }
-
-            let (track_min, mut track_max) = (
-                track_minmax[0].parse::<u16>().unwrap(),
-                track_minmax[1].parse::<u16>().unwrap(),
-            );
-
-            track_max = u16::min(track_max, track_count);
-
-            // TODO: Do some error handling here
-            assert!(track_min <= track_max);
-
-            let mut track_list: Vec<u16> = Vec::new();
-            for track in track_min - 1..track_max {
-                if track_dict.contains_key(&track) {
-                    return Err(InterfaceError::GroupError(format!(
-                        "track {} is in 2 groups",
-                        track
-                    )));
-                }
-                track_dict.insert(track, (String::from(group_name), i as u16));
-                track_list.push(track);
-            }
-
-            result.push((
-                Some(String::from(group_name)),
-                Some(String::from(full_width_group_name)),
-                track_list.clone(),
-            ));
-        }
-
-        let mut remaining_tracks = Vec::new();
-        for i in 0..track_count {
-            if !track_dict.contains_key(&i) {
-                remaining_tracks.push(i);
-            }
-        }
-        result.insert(0, (None, None, remaining_tracks));
-
-        Ok(result)
+        parse_groups(&raw_title, &raw_full_title, track_count)
     }
 
     /// Gets a list of track titles from a set
@@ -1057,48 +1696,21 @@ impl NetMDInterface {
         tracks: Vec<u16>,
         wchar: bool,
     ) -> Result<Vec<String>, InterfaceError> {
-        let wchar_value = match wchar {
-            true => 3,
-            false => 2,
-        };
-
         let descriptor_type = match wchar {
             true => Descriptor::AudioUTOC4TD,
             false => Descriptor::AudioUTOC1TD,
         };
 
-        self.change_descriptor_state(&descriptor_type, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(descriptor_type, DescriptorAction::OpenRead)
             .await?;
 
         let mut track_titles: Vec<String> = vec![];
         for i in tracks {
-            let query = format_query(
-                "1806 022018%b %w 3000 0a00 ff00 00000000".to_string(),
-                vec![
-                    QueryValue::Number(wchar_value),
-                    QueryValue::Number(i as i64),
-                ],
-            )
-            .unwrap();
-
-            let reply = self.send_query(&query, false, false).await?;
-
-            let res = scan_query(
-                reply,
-                "1806 022018%? %?%? %?%? %?%? 1000 00%?0000 00%?000a %x".to_string(),
-            )
-            .unwrap();
-
-            track_titles.push(
-                encoding_rs::SHIFT_JIS
-                    .decode(&res[0].to_vec().unwrap())
-                    .0
-                    .into(),
-            )
+            track_titles.push(descriptor.run(protocol::track_title(i, wchar)?).await?);
         }
 
-        self.change_descriptor_state(&descriptor_type, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
         Ok(track_titles)
     }
@@ -1120,58 +1732,33 @@ impl NetMDInterface {
     // Caution: This does not respect groups. Use the functions available in
     // NetMDContext to properly rename a disc.
     pub async fn set_disc_title(&mut self, title: &str, wchar: bool) -> Result<(), InterfaceError> {
+        self.require_level(NetMDLevel::Level3, "set disc title")
+            .await?;
+
         let current_title = self.raw_disc_title(wchar).await?;
         if current_title == title {
             return Err(InterfaceError::TitleError);
         }
 
-        let new_title: Vec<u8>;
-        let old_len = length_after_encoding_to_sjis(&current_title);
-
-        let wchar_value = match wchar {
-            true => {
-                new_title = to_sjis(&sanitize_full_width_title(title));
-                1
-            }
-            false => {
-                new_title = to_sjis(&sanitize_half_width_title(title));
-                0
-            }
-        };
-
-        let new_len = new_title.len();
+        let command = protocol::set_disc_title(title, &current_title, wchar)?;
 
-        if self.device.vendor_id() == 0x04dd {
-            self.change_descriptor_state(&Descriptor::AudioUTOC1TD, &DescriptorAction::OpenWrite)
+        let utoc1_disc_title = self.device.capabilities().utoc1_disc_title;
+        let mut descriptor = if utoc1_disc_title {
+            self.open_descriptor(Descriptor::AudioUTOC1TD, DescriptorAction::OpenWrite)
                 .await?
         } else {
             self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::Close)
                 .await?;
-            self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::OpenWrite)
+            self.open_descriptor(Descriptor::DiscTitleTD, DescriptorAction::OpenWrite)
                 .await?
-        }
-
-        let query = format_query(
-            "1807 02201801 00%b 3000 0a00 5000 %w 0000 %w %*".to_string(),
-            vec![
-                QueryValue::Number(wchar_value),
-                QueryValue::Number(new_len as i64),
-                QueryValue::Number(old_len as i64),
-                QueryValue::Array(new_title),
-            ],
-        )?;
-
-        let _ = self.send_query(&query, false, false).await;
+        };
 
-        if self.device.vendor_id() == 0x04dd {
-            self.change_descriptor_state(&Descriptor::AudioUTOC1TD, &DescriptorAction::Close)
+        descriptor.run(command).await?;
+        descriptor.close().await?;
+        if !utoc1_disc_title {
+            self.open_descriptor(Descriptor::DiscTitleTD, DescriptorAction::OpenRead)
                 .await?
-        } else {
-            self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::Close)
-                .await?;
-            self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::OpenRead)
-                .await?;
-            self.change_descriptor_state(&Descriptor::DiscTitleTD, &DescriptorAction::Close)
+                .close()
                 .await?;
         }
 
@@ -1185,83 +1772,52 @@ impl NetMDInterface {
         title: &str,
         wchar: bool,
     ) -> Result<(), InterfaceError> {
-        let new_title: Vec<u8>;
-        let (wchar_value, descriptor) = match wchar {
-            true => {
-                new_title = to_sjis(&sanitize_full_width_title(title));
-                (3, Descriptor::AudioUTOC4TD)
-            }
-            false => {
-                new_title = to_sjis(&sanitize_half_width_title(title));
-                (2, Descriptor::AudioUTOC1TD)
-            }
-        };
+        self.require_level(NetMDLevel::Level3, "set track title")
+            .await?;
 
-        let new_len = new_title.len();
+        let descriptor = match wchar {
+            true => Descriptor::AudioUTOC4TD,
+            false => Descriptor::AudioUTOC1TD,
+        };
 
-        let old_len: u16 = match self.track_title(track, wchar).await {
+        let old_title = match self.track_title(track, wchar).await {
             Ok(current_title) => {
                 if title == current_title {
                     return Ok(());
                 }
-                length_after_encoding_to_sjis(&current_title) as u16
+                current_title
             }
             Err(error) => match error {
-                InterfaceError::Rejected(_) => 0,
+                InterfaceError::Rejected(_) => String::new(),
                 _ => return Err(error),
             },
         };
 
-        self.change_descriptor_state(&descriptor, &DescriptorAction::OpenWrite)
-            .await?;
+        let command = protocol::set_track_title(track, title, &old_title, wchar)?;
 
-        let query = format_query(
-            "1807 022018%b %w 3000 0a00 5000 %w 0000 %w %*".to_string(),
-            vec![
-                QueryValue::Number(wchar_value),
-                QueryValue::Number(track as i64),
-                QueryValue::Number(new_len as i64),
-                QueryValue::Number(old_len as i64),
-                QueryValue::Array(new_title),
-            ],
-        )?;
-        let reply = self.send_query(&query, false, false).await?;
-
-        let _ = scan_query(
-            reply,
-            "1807 022018%? %?%? 3000 0a00 5000 %?%? 0000 %?%?".to_string(),
-        );
-        self.change_descriptor_state(&descriptor, &DescriptorAction::Close)
+        let mut descriptor = self
+            .open_descriptor(descriptor, DescriptorAction::OpenWrite)
             .await?;
 
+        descriptor.run(command).await?;
+        descriptor.close().await?;
+
         Ok(())
     }
 
     /// Erases a track from the disc's UTOC
     pub async fn erase_track(&mut self, track: u16) -> Result<(), InterfaceError> {
-        let query = format_query(
-            "1840 ff01 00 201001 %w".to_string(),
-            vec![QueryValue::Number(track as i64)],
-        )?;
-
-        let _result = self.send_query(&query, false, false).await;
+        self.require_level(NetMDLevel::Level3, "erase track")
+            .await?;
 
-        Ok(())
+        self.run(protocol::erase_track(track)?).await
     }
 
     /// Moves a track to another index on the disc
     pub async fn move_track(&mut self, source: u16, dest: u16) -> Result<(), InterfaceError> {
-        let query = format_query(
-            "1843 ff00 00 201001 %w 201001 %w".to_string(),
-            vec![
-                QueryValue::Number(source as i64),
-                QueryValue::Number(dest as i64),
-            ],
-        )?;
-
-        let _result = self.send_query(&query, false, false).await;
+        self.require_level(NetMDLevel::Level3, "move track").await?;
 
-        Ok(())
+        self.run(protocol::move_track(source, dest)?).await
     }
 
     /// Raw information about a track
@@ -1271,28 +1827,15 @@ impl NetMDInterface {
         p1: i32,
         p2: i32,
     ) -> Result<Vec<u8>, InterfaceError> {
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
             .await?;
 
-        let query = format_query(
-            "1806 02201001 %w %w %w ff00 00000000".to_string(),
-            vec![
-                QueryValue::Number(track as i64),
-                QueryValue::Number(p1 as i64),
-                QueryValue::Number(p2 as i64),
-            ],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        let res = scan_query(
-            reply,
-            "1806 02201001 %?%? %?%? %?%? 1000 00%?0000 %x".to_string(),
-        )?;
-
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::Close)
-            .await?;
+        let info = descriptor.run(protocol::track_info(track, p1, p2)?).await?;
 
-        Ok(res[0].to_vec().unwrap())
+        descriptor.close().await?;
+
+        Ok(info)
     }
 
     /// Gets the length of tracks as a raw duration from a set
@@ -1302,46 +1845,15 @@ impl NetMDInterface {
     ) -> Result<Vec<RawTime>, InterfaceError> {
         let mut times = vec![];
 
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
             .await?;
 
         for track in tracks {
-            let query = format_query(
-                "1806 02201001 %w %w %w ff00 00000000".to_string(),
-                vec![
-                    QueryValue::Number(track as i64),
-                    QueryValue::Number(0x3000),
-                    QueryValue::Number(0x0100),
-                ],
-            )?;
-
-            let reply = self.send_query(&query, false, false).await?;
-
-            let res = scan_query(
-                reply,
-                "1806 02201001 %?%? %?%? %?%? 1000 00%?0000 %x".to_string(),
-            )?;
-
-            let result = scan_query(
-                res[0].to_vec().unwrap(),
-                "01 0006 0000 %B %B %B %B".to_string(),
-            )?;
-
-            let times_num: Vec<u64> = result
-                .into_iter()
-                .map(|v| v.to_i64().unwrap() as u64)
-                .collect();
-
-            times.push(RawTime {
-                hours: times_num[0],
-                minutes: times_num[1],
-                seconds: times_num[2],
-                frames: times_num[3],
-            });
+            times.push(descriptor.run(protocol::track_length(track)?).await?);
         }
 
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
         Ok(times)
     }
@@ -1356,97 +1868,65 @@ impl NetMDInterface {
         &mut self,
         track_number: u16,
     ) -> Result<(Encoding, Channels), InterfaceError> {
-        let raw_value = self.raw_track_info(track_number, 0x3080, 0x0700).await?;
-        let result = scan_query(raw_value, "07 0004 0110 %b %b".to_string())?;
-
-        let encoding = match result[0].to_i64() {
-            Ok(0x90) => Encoding::SP,
-            Ok(0x92) => Encoding::LP2,
-            Ok(0x93) => Encoding::LP4,
-            Ok(e) => return Err(InterfaceError::InvalidEncoding(e as u8)),
-            Err(_) => unreachable!(),
-        };
+        let mut descriptor = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
+            .await?;
 
-        let channels = match result[1].to_i64() {
-            Ok(0x00) => Channels::Stereo,
-            Ok(0x01) => Channels::Mono,
-            Ok(e) => return Err(InterfaceError::InvalidDiscFormat(e as u8)),
-            Err(_) => unreachable!(),
-        };
+        let encoding = descriptor
+            .run(protocol::track_encoding(track_number)?)
+            .await?;
+
+        descriptor.close().await?;
 
-        Ok((encoding, channels))
+        Ok(encoding)
     }
 
     /// Gets a track's flags
     pub async fn track_flags(&mut self, track: u16) -> Result<u8, InterfaceError> {
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::AudioContentsTD, DescriptorAction::OpenRead)
             .await?;
 
-        let query = format_query(
-            "1806 01201001 %w ff00 00010008".to_string(),
-            vec![QueryValue::Number(track as i64)],
-        )?;
-        let reply = self.send_query(&query, false, false).await?;
+        let flags = descriptor.run(protocol::track_flags(track)?).await?;
 
-        let res = scan_query(reply, "1806 01201001 %?%? 10 00 00010008 %b".to_string())?;
-
-        self.change_descriptor_state(&Descriptor::AudioContentsTD, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
-        Ok(res[0].to_i64().unwrap() as u8)
+        Ok(flags)
     }
 
     /// Gets the disc capacity as a [std::time::Duration]
     pub async fn disc_capacity(&mut self) -> Result<[RawTime; 3], InterfaceError> {
-        self.change_descriptor_state(&Descriptor::RootTD, &DescriptorAction::OpenRead)
+        let mut descriptor = self
+            .open_descriptor(Descriptor::RootTD, DescriptorAction::OpenRead)
             .await?;
 
-        let query = format_query("1806 02101000 3080 0300 ff00 00000000".to_string(), vec![])?;
-        let reply = self.send_query(&query, false, false).await?;
-
-        // 8003 changed to %?03 - Panasonic returns 0803 instead. This byte's meaning is unknown
-        let res = scan_query(
-            reply,
-            "1806 02101000 3080 0300 1000 001d0000 001b %?03 0017 8000 0005 %W %B %B %B 0005 %W %B %B %B 0005 %W %B %B %B".to_string()
-        )?;
-
-        let res_num: Vec<RawTime> = res
-            .windows(4)
-            .step_by(4)
-            .map(|t| RawTime {
-                hours: t[0].to_i64().unwrap() as u64,
-                minutes: t[1].to_i64().unwrap() as u64,
-                seconds: t[2].to_i64().unwrap() as u64,
-                frames: t[3].to_i64().unwrap() as u64,
-            })
-            .collect();
-
-        self.change_descriptor_state(&Descriptor::RootTD, &DescriptorAction::Close)
-            .await?;
+        // Most devices return 8003, but Panasonic returns 0803. This byte's meaning is unknown
+        let (flag, capacity) = descriptor.run(protocol::disc_capacity()?).await?;
 
-        Ok(res_num.try_into().unwrap())
-    }
+        descriptor.close().await?;
 
-    pub async fn recording_parameters(&mut self) -> Result<Vec<u8>, InterfaceError> {
-        self.change_descriptor_state(
-            &Descriptor::OperatingStatusBlock,
-            &DescriptorAction::OpenRead,
-        )
-        .await?;
+        let expected = self.device.capabilities().capacity_flag;
+        if flag != expected {
+            return Err(InterfaceError::UnexpectedCapacityFlag {
+                expected,
+                found: flag,
+            });
+        }
 
-        let query = format_query(
-            "1809 8001 0330 8801 0030 8805 0030 8807 00 ff00 00000000".to_string(),
-            vec![],
-        )?;
+        Ok(capacity)
+    }
 
-        let reply = self.send_query(&query, false, false).await?;
+    /// Get the mode the device will record in
+    pub async fn recording_parameters(&mut self) -> Result<RecordingParameters, InterfaceError> {
+        let mut descriptor = self
+            .open_descriptor(Descriptor::OperatingStatusBlock, DescriptorAction::OpenRead)
+            .await?;
 
-        let res = scan_query(reply, "1809 8001 0330 8801 0030 8805 0030 8807 00 1000 000e0000 000c 8805 0008 80e0 0110 %b %b 4000".to_string())?;
+        let parameters = descriptor.run(protocol::recording_parameters()?).await?;
 
-        self.change_descriptor_state(&Descriptor::OperatingStatusBlock, &DescriptorAction::Close)
-            .await?;
+        descriptor.close().await?;
 
-        Ok(res.into_iter().map(|x| x.to_i64().unwrap() as u8).collect())
+        Ok(parameters)
     }
 
     /// Gets the bytes of a track
@@ -1457,70 +1937,36 @@ impl NetMDInterface {
         track: u16,
         progress_callback: Option<F>,
     ) -> Result<(DiscFormat, u16, Vec<u8>), InterfaceError> {
-        let query = format_query(
-            "1800 080046 f003010330 ff00 1001 %w".to_string(),
-            vec![QueryValue::Number((track + 1) as i64)],
-        )?;
-
-        let reply = self.send_query(&query, false, true).await?;
-
-        let res = scan_query(
-            reply,
-            "1800 080046 f0030103 300000 1001 %w %b %d".to_string(),
-        )?;
+        if !self.device.capabilities().upload {
+            return Err(InterfaceError::Unsupported("track upload"));
+        }
 
-        let frames = res[0].to_i64().unwrap() as u16;
-        let codec = res[1].to_i64().unwrap() as u8;
-        let length = res[2].to_i64().unwrap() as usize;
+        let (frames, codec, length) = self.run(protocol::save_track(track)?).await?;
 
         let result = self
             .device
             .read_bulk(length, 0x10000, progress_callback)
             .await?;
 
-        scan_query(
-            self.read_reply(false).await?,
-            "1800 080046 f003010330 0000 1001 %?%? %?%?".to_string(),
-        )?;
+        self.drive(protocol::save_track_finished()).await?;
 
         cross_sleep(Duration::from_millis(500)).await;
 
-        let format: DiscFormat = match codec & 0x06 {
-            0 => DiscFormat::LP4,
-            2 => DiscFormat::LP2,
-            4 => DiscFormat::SPMono,
-            6 => DiscFormat::SPStereo,
-            e => return Err(InterfaceError::InvalidDiscFormat(e)),
-        };
+        let format = protocol::disc_format(codec)?;
 
         Ok((format, frames, result))
     }
 
     pub async fn disable_new_track_protection(&mut self, val: u16) -> Result<(), InterfaceError> {
-        let query = format_query(
-            "1800 080046 f0030103 2b ff %w".to_string(),
-            vec![QueryValue::Number(val as i64)],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        scan_query(reply, "1800 080046 f0030103 2b 00 %?%?".to_string())?;
-        Ok(())
+        self.run(protocol::disable_new_track_protection(val)?).await
     }
 
     pub async fn enter_secure_session(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("1800 080046 f0030103 80 ff".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        scan_query(reply, "1800 080046 f0030103 80 00".to_string())?;
-        Ok(())
+        self.run(protocol::enter_secure_session()?).await
     }
 
     pub async fn leave_secure_session(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("1800 080046 f0030103 81 ff".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        scan_query(reply, "1800 080046 f0030103 81 00".to_string())?;
-        Ok(())
+        self.run(protocol::leave_secure_session()?).await
     }
 
     /// Read the leaf ID of the present NetMD device. The leaf ID tells
@@ -1530,12 +1976,7 @@ impl NetMDInterface {
     ///
     /// The leaf ID is a 8-byte constant
     pub async fn leaf_id(&mut self) -> Result<[u8; 8], InterfaceError> {
-        let query = format_query("1800 080046 f0030103 11 ff".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        let res = scan_query(reply, "1800 080046 f0030103 11 00 %*".to_string())?;
-
-        Ok(res[0].to_vec().unwrap().try_into().unwrap())
+        self.run(protocol::leaf_id()?).await
     }
 
     pub async fn send_key_data(
@@ -1545,68 +1986,20 @@ impl NetMDInterface {
         depth: i32,
         ekbsignature: [u8; 24],
     ) -> Result<(), InterfaceError> {
-        let chainlen = keychain.len();
-        let databytes = 16 + 16 * chainlen + 24;
+        let command = protocol::send_key_data(ekbid, keychain, depth, ekbsignature)?;
 
-        if !(1..=63).contains(&depth) {
-            return Err(EncryptionError::InvalidDepth(depth))?;
-        }
-
-        let keychains = keychain.concat();
-
-        let query = format_query(
-            "1800 080046 f0030103 12 ff %w 0000 %w %d %d %d 00000000 %* %*".to_string(),
-            vec![
-                QueryValue::Number(databytes as i64),
-                QueryValue::Number(databytes as i64),
-                QueryValue::Number(chainlen as i64),
-                QueryValue::Number(depth as i64),
-                QueryValue::Number(ekbid as i64),
-                QueryValue::Array(keychains),
-                QueryValue::Array(ekbsignature.to_vec()),
-            ],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(
-            reply,
-            "1800 080046 f0030103 12 01 %?%? %?%?%?%?".to_string(),
-        )?;
-
-        Ok(())
+        self.run(command).await
     }
 
     pub async fn session_key_exchange(
         &mut self,
         hostnonce: Vec<u8>,
     ) -> Result<Vec<u8>, InterfaceError> {
-        if hostnonce.len() != 8 {
-            return Err(EncryptionError::InvalidLength(
-                "host nonce",
-                hostnonce.len(),
-            ))?;
-        }
-
-        let query = format_query(
-            "1800 080046 f0030103 20 ff 000000 %*".to_string(),
-            vec![QueryValue::Array(hostnonce)],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(reply, "1800 080046 f0030103 20 %? 000000 %#".to_string())?;
-
-        Ok(res[0].to_vec().unwrap())
+        self.run(protocol::session_key_exchange(&hostnonce)?).await
     }
 
     pub async fn session_key_forget(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("1800 080046 f0030103 21 ff 000000".to_string(), vec![])?;
-
-        let reply = self.send_query(&query, false, false).await?;
-        let _ = scan_query(reply, "1800 080046 f0030103 21 00 000000".to_string())?;
-
-        Ok(())
+        self.run(protocol::session_key_forget()?).await
     }
 
     pub async fn setup_download(
@@ -1615,40 +2008,9 @@ impl NetMDInterface {
         keyenckey: &[u8],
         hex_session_key: &[u8],
     ) -> Result<(), InterfaceError> {
-        if contentid.len() != 20 {
-            return Err(EncryptionError::InvalidLength(
-                "content ID",
-                contentid.len(),
-            ))?;
-        }
-        if keyenckey.len() != 8 {
-            return Err(EncryptionError::InvalidLength(
-                "key encryption",
-                keyenckey.len(),
-            ))?;
-        }
-        if hex_session_key.len() != 8 {
-            return Err(EncryptionError::InvalidLength(
-                "session key",
-                hex_session_key.len(),
-            ))?;
-        }
-
-        let mut message = [vec![1, 1, 1, 1], contentid.to_vec(), keyenckey.to_vec()].concat();
-        DesCbcEnc::new(hex_session_key.into(), &[0u8; 8].into())
-            .encrypt_padded_mut::<NoPadding>(message.as_mut_slice(), 32)
-            .unwrap();
-
-        let query = format_query(
-            "1800 080046 f0030103 22 ff 0000 %*".to_string(),
-            vec![QueryValue::Array(message)],
-        )?;
+        let command = protocol::setup_download(contentid, keyenckey, hex_session_key)?;
 
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "1800 080046 f0030103 22 00 0000".to_string())?;
-
-        Ok(())
+        self.run(command).await
     }
 
     pub async fn commit_track(
@@ -1656,31 +2018,9 @@ impl NetMDInterface {
         track_number: u16,
         hex_session_key: &[u8],
     ) -> Result<(), InterfaceError> {
-        if hex_session_key.len() != 8 {
-            return Err(EncryptionError::InvalidLength(
-                "hex session key",
-                hex_session_key.len(),
-            ))?;
-        }
+        let command = protocol::commit_track(track_number, hex_session_key)?;
 
-        let mut message = [0u8; 8];
-        DesEcbEnc::new(hex_session_key.into())
-            .encrypt_padded_mut::<NoPadding>(&mut message, 8)
-            .unwrap();
-
-        let query = format_query(
-            "1800 080046 f0030103 48 ff 00 1001 %w %*".to_string(),
-            vec![
-                QueryValue::Number(track_number as i64),
-                QueryValue::Array(Vec::from(message)),
-            ],
-        )?;
-
-        let reply = self.send_query(&query, false, false).await?;
-
-        scan_query(reply, "1800 080046 f0030103 48 00 00 1001 %?%?".to_string())?;
-
-        Ok(())
+        self.run(command).await
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -1695,47 +2035,25 @@ impl NetMDInterface {
         progress_callback: F,
     ) -> Result<(u16, Vec<u8>, Vec<u8>), InterfaceError>
     {
-        if hex_session_key.len() != 8 {
-            return Err(EncryptionError::InvalidLength(
-                "hex session key",
-                hex_session_key.len(),
-            ))?;
-        }
+        let track_sent = protocol::track_sent(hex_session_key)?;
 
-        // Sharps are slow
-        cross_sleep(Duration::from_millis(200)).await;
+        // Some devices (Sharps) are slow
+        cross_sleep(self.device.capabilities().download_delay).await;
 
         let total_bytes: usize = (pkt_size + 24) as usize; //framesizedict[wireformat] * frames + pktcount * 24;
 
-        let query = format_query(
-            "1800 080046 f0030103 28 ff 000100 1001 ffff 00 %b %b %d %d".to_string(),
-            vec![
-                QueryValue::Number(wireformat as i64),
-                QueryValue::Number(discformat as i64),
-                QueryValue::Number(frames as i64),
-                QueryValue::Number(total_bytes as i64),
-            ],
-        )?;
-        let mut reply = self.send_query(&query, false, true).await?;
-        scan_query(
-            reply,
-            "1800 080046 f0030103 28 00 000100 1001 %?%? 00 %*".to_string(),
-        )?;
+        let command = protocol::send_track(wireformat, discformat, frames, total_bytes)?;
+        self.run(command).await?;
         self.device.poll().await?;
 
-        // Sharps are slow
-        cross_sleep(Duration::from_millis(200)).await;
+        // Some devices (Sharps) are slow
+        cross_sleep(self.device.capabilities().download_delay).await;
 
         let mut written_bytes = 0;
         let mut packet_count = 0;
 
         while let Some((key, iv, data)) = packets.next().await {
-            let binpack = if packet_count == 0 {
-                let packed_length: Vec<u8> = pkt_size.to_be_bytes().to_vec();
-                [vec![0, 0, 0, 0], packed_length, key, iv, data].concat()
-            } else {
-                data
-            };
+            let binpack = protocol::download_packet(packet_count, pkt_size, key, iv, data);
             self.device.write_bulk(&binpack).await?;
             written_bytes += binpack.len();
             packet_count += 1;
@@ -1746,113 +2064,18 @@ impl NetMDInterface {
             }
         }
 
-        reply = self.read_reply(false).await?;
+        let sent = self.drive(track_sent).await;
         self.device.poll().await?;
-        let res = scan_query(
-            reply,
-            "1800 080046 f0030103 28 00 000100 1001 %w 00 %?%? %?%?%?%? %?%?%?%? %*".to_string(),
-        )?;
-
-        let mut encrypted_data = res[1].to_vec().unwrap();
-        DesCbcDec::new(hex_session_key.into(), &[0u8; 8].into())
-            .decrypt_padded_mut::<NoPadding>(&mut encrypted_data)
-            .unwrap();
 
-        let part1 = encrypted_data[0..8].to_vec();
-        let part2 = encrypted_data[12..32].to_vec();
-
-        Ok((res[0].to_i64().unwrap() as u16, part1, part2))
+        sent
     }
 
     pub async fn track_uuid(&mut self, track: u16) -> Result<String, InterfaceError> {
-        let query = format_query(
-            "1800 080046 f0030103 23 ff 1001 %w".to_string(),
-            vec![QueryValue::Number(track as i64)],
-        )?;
-        let reply = self.send_query(&query, false, false).await?;
-
-        let res = scan_query(reply, "1800 080046 f0030103 23 00 1001 %?%? %*".to_string())?;
-
-        Ok(String::from_utf8_lossy(&res[0].to_vec().unwrap()).to_string())
+        self.run(protocol::track_uuid(track)?).await
     }
 
     pub async fn terminate(&mut self) -> Result<(), InterfaceError> {
-        let query = format_query("1800 080046 f0030103 2a ff00".to_string(), vec![])?;
-        self.send_query(&query, false, false).await?;
-
-        Ok(())
-    }
-}
-
-type DesEcbEnc = ecb::Encryptor<des::Des>;
-type DesCbcEnc = cbc::Encryptor<des::Des>;
-type DesCbcDec = cbc::Decryptor<des::Des>;
-type TDesCbcEnc = cbc::Encryptor<des::TdesEde3>;
-
-pub(super) fn retailmac(key: &[u8], value: &[u8], iv: &[u8; 8]) -> Vec<u8> {
-    let mut subkey_a = [0u8; 8];
-    subkey_a.copy_from_slice(&key[0..8]);
-
-    let mut beginning = [0u8; 8];
-    beginning.copy_from_slice(&value[0..8]);
-
-    let mut end = [0u8; 8];
-    end.copy_from_slice(&value[8..]);
-
-    DesCbcEnc::new(&subkey_a.into(), iv.into())
-        .encrypt_padded_mut::<NoPadding>(&mut beginning, 8)
-        .unwrap();
-
-    let iv2 = &beginning[beginning.len() - 8..];
-
-    let mut wonky_key = [0u8; 24];
-    wonky_key[0..16].copy_from_slice(key);
-    wonky_key[16..].copy_from_slice(&key[0..8]);
-    TDesCbcEnc::new(&wonky_key.into(), iv2.into())
-        .encrypt_padded_mut::<NoPadding>(&mut end, 8)
-        .unwrap();
-
-    end[..8].to_vec()
-}
-
-pub(super) struct EKBData {
-    chains: [[u8; 16]; 2],
-    depth: i32,
-    signature: [u8; 24],
-}
-
-pub(super) struct EKBOpenSource;
-
-impl EKBOpenSource {
-    pub fn root_key(&self) -> [u8; 16] {
-        [
-            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x0f, 0xed, 0xcb, 0xa9, 0x87, 0x65,
-            0x43, 0x21,
-        ]
-    }
-
-    pub fn ekb_id(&self) -> i32 {
-        0x26422642
-    }
-
-    pub fn ekb_data_for_leaf_id(&self) -> EKBData {
-        EKBData {
-            chains: [
-                [
-                    0x25, 0x45, 0x06, 0x4d, 0xea, 0xca, 0x14, 0xf9, 0x96, 0xbd, 0xc8, 0xa4, 0x06,
-                    0xc2, 0x2b, 0x81,
-                ],
-                [
-                    0xfb, 0x60, 0xbd, 0xdd, 0x0d, 0xbc, 0xab, 0x84, 0x8a, 0x00, 0x5e, 0x03, 0x19,
-                    0x4d, 0x3e, 0xda,
-                ],
-            ],
-            depth: 9,
-            signature: [
-                0x8f, 0x2b, 0xc3, 0x52, 0xe8, 0x6c, 0x5e, 0xd3, 0x06, 0xdc, 0xae, 0x18, 0xd2, 0xf3,
-                0x8c, 0x7f, 0x89, 0xb5, 0xe1, 0x85, 0x55, 0xa1, 0x05, 0xea,
-            ],
-        }
+        self.run(protocol::terminate()?).await
     }
 }
 
@@ -1916,7 +2139,7 @@ impl MDTrack {
         [0x14, 0xe3, 0x83, 0x4e, 0xe2, 0xd3, 0xcc, 0xa5]
     }
 
-    #[cfg(not(target_family = "wasm"))]
+    #[cfg(all(feature = "threaded-encryption", not(target_family = "wasm")))]
     pub fn get_encrypting_iterator(&mut self) -> Encryptor {
         Encryptor::new_threaded(DataEncryptorInput {
             kek: self.get_kek(),
@@ -1926,7 +2149,7 @@ impl MDTrack {
         })
     }
 
-    #[cfg(target_family = "wasm")]
+    #[cfg(not(all(feature = "threaded-encryption", not(target_family = "wasm"))))]
     pub fn get_encrypting_iterator(&mut self) -> Encryptor {
         Encryptor::new(DataEncryptorInput {
             kek: self.get_kek(),
@@ -1937,14 +2160,30 @@ impl MDTrack {
     }
 }
 
-pub(super) struct MDSession<'a> {
-    pub md: &'a mut NetMDInterface,
+pub(super) struct MDSession<'a, T> {
+    pub md: &'a mut NetMDInterface<T>,
     pub ekb_object: EKBOpenSource,
     pub hex_session_key: Option<Vec<u8>>,
 }
 
-impl<'a> MDSession<'a> {
-    pub async fn init(&mut self) -> Result<(), Box<dyn Error>> {
+impl<'a, T: Transport> MDSession<'a, T> {
+    /// Open the secure session.
+    ///
+    /// Fails with [`ContextError::SessionRejected`] if the device refuses
+    /// the handshake.
+    pub async fn init(&mut self) -> Result<(), ContextError> {
+        self.handshake().await.map_err(|error| match error {
+            InterfaceError::Rejected(_) | InterfaceError::NotImplemented(_) => {
+                ContextError::SessionRejected(error)
+            }
+            error => error.into(),
+        })
+    }
+
+    async fn handshake(&mut self) -> Result<(), InterfaceError> {
+        // Titles are set during the session, so the level can't be read then
+        self.md.read_level().await;
+
         self.md.enter_secure_session().await?;
         self.md.leaf_id().await?;
 
@@ -1960,14 +2199,13 @@ impl<'a> MDSession<'a> {
         let mut nonce = vec![0u8; 8];
         rand::thread_rng().fill_bytes(&mut nonce);
 
-        let mut devnonce = self.md.session_key_exchange(nonce.clone()).await?;
-        nonce.append(&mut devnonce);
+        let devnonce = self.md.session_key_exchange(nonce.clone()).await?;
 
-        self.hex_session_key = Some(retailmac(&self.ekb_object.root_key(), &nonce, &[0u8; 8]));
+        self.hex_session_key = Some(protocol::session_key(&nonce, &devnonce)?);
         Ok(())
     }
 
-    pub async fn close(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn close(&mut self) -> Result<(), ContextError> {
         if self.hex_session_key.is_none() {
             self.md.session_key_forget().await?;
         }
@@ -1981,17 +2219,13 @@ impl<'a> MDSession<'a> {
         mut track: MDTrack,
         progress_callback: F,
         disc_format: Option<DiscFormat>,
-    ) -> Result<(u16, Vec<u8>, Vec<u8>), Box<dyn Error>>
+    ) -> Result<(u16, Vec<u8>, Vec<u8>), ContextError>
     {
-        if self.hex_session_key.is_none() {
-            return Err("Cannot download a track using a non-init()'ed session!".into());
-        }
+        let Some(hex_session_key) = self.hex_session_key.clone() else {
+            return Err(InterfaceError::NoSession.into());
+        };
         self.md
-            .setup_download(
-                &track.content_id(),
-                &track.get_kek(),
-                self.hex_session_key.as_ref().unwrap(),
-            )
+            .setup_download(&track.content_id(), &track.get_kek(), &hex_session_key)
             .await?;
         let data_format = track.data_format();
         let final_disc_format = disc_format.unwrap_or(data_format.disc_for_wire());
@@ -2004,7 +2238,7 @@ impl<'a> MDSession<'a> {
                 track.frame_count() as u32,
                 track.total_size() as u32,
                 track.get_encrypting_iterator(),
-                self.hex_session_key.as_ref().unwrap().as_slice(),
+                &hex_session_key,
                 progress_callback,
             )
             .await?;
@@ -2017,14 +2251,12 @@ impl<'a> MDSession<'a> {
                 .set_track_title(track_index, &full_width, true)
                 .await?;
         }
-        self.md
-            .commit_track(track_index, self.hex_session_key.as_ref().unwrap())
-            .await?;
+        self.md.commit_track(track_index, &hex_session_key).await?;
 
         Ok((track_index, uuid, ccid))
     }
 
-    pub fn new(md: &'a mut NetMDInterface) -> Self {
+    pub fn new(md: &'a mut NetMDInterface<T>) -> Self {
         MDSession {
             md,
             ekb_object: EKBOpenSource,
@@ -2032,3 +2264,94 @@ impl<'a> MDSession<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::emulator::{Fault, VirtualDisc, VirtualPlayer, VirtualTrack};
+
+    fn interface() -> NetMDInterface<VirtualPlayer> {
+        let disc = VirtualDisc {
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+
+        NetMDInterface::from(NetMD::new(VirtualPlayer::new(disc)).unwrap())
+    }
+
+    fn player<'a>(notification: &'a mut Notification<'_, VirtualPlayer>) -> &'a mut VirtualPlayer {
+        notification.interface.device.transport_mut()
+    }
+
+    fn next_item<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        tokio_test::block_on(std::future::poll_fn(|cx| {
+            Pin::new(&mut *stream).poll_next(cx)
+        }))
+    }
+
+    #[test]
+    fn notify_status() {
+        let mut interface = interface();
+        let mut notification = tokio_test::block_on(interface.notify_status()).unwrap();
+        notification.set_interval(Duration::ZERO);
+
+        let interim = notification.interim().unwrap().clone();
+        assert_eq!(interim.status, NetmdStatus::Interim);
+        assert_eq!(
+            tokio_test::block_on(notification.next_timeout(Duration::ZERO)),
+            Ok(None)
+        );
+
+        player(&mut notification).eject_disc();
+        let changed = tokio_test::block_on(notification.next()).unwrap();
+
+        assert_eq!(changed.status, NetmdStatus::Changed);
+        assert_ne!(changed.data[1..], interim.data[1..]);
+
+        // The descriptor is closed before the next command
+        drop(notification);
+        assert!(!tokio_test::block_on(interface.status())
+            .unwrap()
+            .disc_present());
+        assert!(interface.device.transport().open_descriptors().is_empty());
+    }
+
+    #[test]
+    fn notify_rejected() {
+        let mut interface = interface();
+        let command = protocol::status().unwrap().query().to_vec();
+        interface
+            .device
+            .transport_mut()
+            .fail_next(&command, Fault::Status(NetmdStatus::Rejected));
+
+        let result = tokio_test::block_on(interface.notify_status());
+
+        assert!(matches!(result, Err(InterfaceError::Rejected(_))));
+    }
+
+    #[test]
+    fn notify_stream_ends_after_error() {
+        let mut interface = interface();
+        let mut notification = tokio_test::block_on(interface.notify_operating_status()).unwrap();
+        notification.set_interval(Duration::ZERO);
+
+        // The change is reported, and registering again is rejected
+        let command = protocol::operating_status().unwrap().query().to_vec();
+        player(&mut notification).eject_disc();
+        player(&mut notification).fail_next(&command, Fault::Status(NetmdStatus::Rejected));
+        let mut stream = notification.into_stream();
+
+        let changed = next_item(&mut stream).unwrap().unwrap();
+        assert_eq!(changed.status, NetmdStatus::Changed);
+        assert!(matches!(
+            next_item(&mut stream),
+            Some(Err(InterfaceError::Rejected(_)))
+        ));
+        assert!(next_item(&mut stream).is_none());
+    }
+}
@@ -2,14 +2,34 @@
 //! devices.
 
 pub mod base;
+#[cfg(all(feature = "blocking", not(target_family = "wasm")))]
+pub mod blocking;
+#[cfg(all(feature = "fs", not(target_family = "wasm")))]
+pub mod capture;
 pub mod commands;
+#[cfg(feature = "native-usb")]
+pub mod discovery;
+pub mod dissector;
+pub mod emulator;
 pub mod encryption;
+pub mod handle;
 pub mod interface;
+pub mod protocol;
+pub mod query_utils;
+pub mod registry;
+pub mod timer;
+pub mod transport;
+pub mod watcher;
 mod mappings;
-mod query_utils;
 mod utils;
 
 #[doc(inline)]
+#[cfg(feature = "native-usb")]
+pub use base::device_filters;
+
+#[doc(inline)]
+#[cfg(feature = "native-usb")]
+#[allow(deprecated)]
 pub use base::DEVICE_IDS_CROSSUSB;
 
 #[doc(inline)]
@@ -20,3 +40,6 @@ pub use interface::NetMDInterface;
 
 #[doc(inline)]
 pub use base::NetMD;
+
+#[doc(inline)]
+pub use transport::Transport;
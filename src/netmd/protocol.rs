@@ -0,0 +1,2052 @@
+//! The NetMD protocol without any I/O.
+//!
+//! Every exchange with a device is a [`Machine`], which asks for one
+//! [`Request`] at a time to be carried out on the device and is given the
+//! [`Response`] to it, until it is done. This makes the protocol usable from
+//! any I/O model, such as a synchronous bridge or another USB stack, and
+//! testable on bytes alone. [`super::NetMD::drive`] runs a machine over a
+//! [`super::Transport`], and is what the higher level interfaces use.
+//!
+//! The commands of the NetMD interface are built by the functions of this
+//! module, as a [`Command`] which encodes the query and decodes the reply.
+//!
+//! ```
+//! use minidisc::netmd::base::RetryPolicy;
+//! use minidisc::netmd::interface::{CommandType, NetmdStatus};
+//! use minidisc::netmd::protocol::{Exchange, Machine, Request, Response, Step};
+//!
+//! // Ask whether the device can eject the disc
+//! let command = [0x18, 0xc1, 0xff, 0x60, 0x00];
+//! let mut exchange = Exchange::new(CommandType::GeneralInquiry, &command, RetryPolicy::default());
+//!
+//! // The device is polled to check it is ready before the command is sent
+//! assert_eq!(exchange.start(), Request::Poll);
+//! assert_eq!(
+//!     exchange.advance(Response::Polled([0; 4])).unwrap(),
+//!     Step::Request(Request::Send(vec![0x04, 0x18, 0xc1, 0xff, 0x60, 0x00])),
+//! );
+//!
+//! // Then it is polled until a reply is waiting, and the reply is read
+//! assert_eq!(exchange.advance(Response::Sent).unwrap(), Step::Request(Request::Poll));
+//! assert_eq!(
+//!     exchange.advance(Response::Polled([0, 0, 6, 0])).unwrap(),
+//!     Step::Request(Request::Read(6)),
+//! );
+//!
+//! let reply = vec![0x0c, 0x18, 0xc1, 0xff, 0x60, 0x00];
+//! match exchange.advance(Response::Read(reply)).unwrap() {
+//!     Step::Done(reply) => assert_eq!(reply.status, NetmdStatus::Implemented),
+//!     Step::Request(request) => panic!("unexpected request {:?}", request),
+//! }
+//! ```
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::base::{NetMDError, RetryPolicy};
+use super::dissector::dissect;
+use super::interface::{
+    Action, Channels, CommandType, DescriptorAction, Direction, DiscFormat, Encoding,
+    EncryptionError, InterfaceError, MediaInfo, NetmdStatus, PlaybackStatus, RecordingParameters,
+    Reply, StatusBlock, StatusError, SubunitIdentifier,
+};
+use super::query_utils::{query, scan};
+use super::utils::{
+    half_width_to_full_width_range, length_after_encoding_to_sjis, sanitize_full_width_title,
+    sanitize_half_width_title, to_sjis, RawTime,
+};
+
+use cbc::cipher::block_padding::NoPadding;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit};
+use encoding_rs::SHIFT_JIS;
+use log::trace;
+
+/// An operation to carry out on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Poll the device, see [`super::Transport::poll`]
+    Poll,
+    /// Send a command, see [`super::Transport::send_command`]
+    Send(Vec<u8>),
+    /// Send a factory command, see [`super::Transport::send_factory_command`]
+    SendFactory(Vec<u8>),
+    /// Read a reply of this length, see [`super::Transport::read_reply`]
+    Read(u16),
+    /// Read a factory reply of this length, see
+    /// [`super::Transport::read_factory_reply`]
+    ReadFactory(u16),
+    /// Wait for this long without blocking
+    Wait(Duration),
+}
+
+/// The result of carrying out a [`Request`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The raw 4 byte result of a poll
+    Polled([u8; 4]),
+    /// The command was sent
+    Sent,
+    /// The bytes which were read
+    Read(Vec<u8>),
+    /// The wait is over
+    Waited,
+}
+
+/// What a [`Machine`] needs next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step<O> {
+    /// Carry out a request and pass its response to [`Machine::advance`]
+    Request(Request),
+    /// The machine is done
+    Done(O),
+}
+
+/// A state machine for one exchange with a device.
+///
+/// Call [`Machine::start`] once, then carry out every request and pass the
+/// response to [`Machine::advance`] until it is done. A machine should not
+/// be used again after it is done or has returned an error.
+pub trait Machine {
+    type Output;
+    type Error: From<NetMDError>;
+
+    /// The first request
+    fn start(&mut self) -> Request;
+
+    /// Handle the response to the last request
+    fn advance(&mut self, response: Response) -> Result<Step<Self::Output>, Self::Error>;
+}
+
+fn unexpected<O>(response: Response) -> Result<O, NetMDError> {
+    trace!("Unexpected response {:?}", response);
+    Err(NetMDError::InvalidResult)
+}
+
+/// Send a command once the device is ready for it
+#[derive(Debug, Clone)]
+pub struct SendCommand {
+    command: Option<Vec<u8>>,
+    factory: bool,
+}
+
+impl SendCommand {
+    /// Send a raw command, including its status byte
+    pub fn new(command: Vec<u8>) -> Self {
+        Self {
+            command: Some(command),
+            factory: false,
+        }
+    }
+
+    /// Send a raw factory command
+    pub fn factory(command: Vec<u8>) -> Self {
+        Self {
+            command: Some(command),
+            factory: true,
+        }
+    }
+}
+
+impl Machine for SendCommand {
+    type Output = ();
+    type Error = NetMDError;
+
+    fn start(&mut self) -> Request {
+        // First poll to ensure the device is ready
+        Request::Poll
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<()>, NetMDError> {
+        match response {
+            Response::Polled(poll) if self.command.is_some() => match poll[2] {
+                0 => {
+                    let command = self.command.take().unwrap_or_default();
+                    match self.factory {
+                        false => Ok(Step::Request(Request::Send(command))),
+                        true => Ok(Step::Request(Request::SendFactory(command))),
+                    }
+                }
+                _ => Err(NetMDError::NotReady),
+            },
+            Response::Sent if self.command.is_none() => Ok(Step::Done(())),
+            response => unexpected(response),
+        }
+    }
+}
+
+/// Poll until a reply is waiting, following a [`RetryPolicy`], and read it
+#[derive(Debug, Clone)]
+pub struct ReadReply {
+    policy: RetryPolicy,
+    factory: bool,
+    override_length: Option<u16>,
+    attempt: u32,
+    waited: Duration,
+    state: ReadState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    Polling,
+    Waiting,
+    Reading,
+}
+
+impl ReadReply {
+    /// Read a reply
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            factory: false,
+            override_length: None,
+            attempt: 0,
+            waited: Duration::ZERO,
+            state: ReadState::Polling,
+        }
+    }
+
+    /// Read a factory reply
+    pub fn factory(policy: RetryPolicy) -> Self {
+        Self {
+            factory: true,
+            ..Self::new(policy)
+        }
+    }
+
+    /// Read this many bytes, instead of the length the device reports
+    pub fn with_length(mut self, length: u16) -> Self {
+        self.override_length = Some(length);
+        self
+    }
+
+    fn poll(&mut self) -> Result<Step<Vec<u8>>, NetMDError> {
+        if self.attempt >= self.policy.max_attempts {
+            return Err(NetMDError::Timeout);
+        }
+
+        self.state = ReadState::Polling;
+        Ok(Step::Request(Request::Poll))
+    }
+}
+
+impl Machine for ReadReply {
+    type Output = Vec<u8>;
+    type Error = NetMDError;
+
+    fn start(&mut self) -> Request {
+        self.attempt = 0;
+        self.waited = Duration::ZERO;
+        self.state = ReadState::Polling;
+
+        Request::Poll
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<Vec<u8>>, NetMDError> {
+        match (self.state, response) {
+            (ReadState::Polling, Response::Polled(poll)) => {
+                let length = u16::from_le_bytes([poll[2], poll[3]]);
+                if length > 0 {
+                    let length = self.override_length.unwrap_or(length);
+
+                    self.state = ReadState::Reading;
+                    return match self.factory {
+                        false => Ok(Step::Request(Request::Read(length))),
+                        true => Ok(Step::Request(Request::ReadFactory(length))),
+                    };
+                }
+
+                // Back off while trying again
+                let sleep_time = self.policy.poll_delay(self.attempt);
+                self.waited = self.waited.saturating_add(sleep_time);
+                if self
+                    .policy
+                    .max_total_delay
+                    .is_some_and(|max_total_delay| self.waited > max_total_delay)
+                {
+                    return Err(NetMDError::Timeout);
+                }
+
+                self.state = ReadState::Waiting;
+                Ok(Step::Request(Request::Wait(sleep_time)))
+            }
+            (ReadState::Waiting, Response::Waited) => {
+                self.attempt += 1;
+                self.poll()
+            }
+            (ReadState::Reading, Response::Read(data)) => Ok(Step::Done(data)),
+            (_, response) => unexpected(response),
+        }
+    }
+}
+
+/// Send a command and read its reply, retrying interim replies following a
+/// [`RetryPolicy`].
+///
+/// The reply is returned whatever its status is, use [`Reply::accepted`] to
+/// treat a rejection as an error.
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    send: Option<SendCommand>,
+    read: ReadReply,
+    policy: RetryPolicy,
+    accept_interim: bool,
+    interim_attempts: u32,
+    state: ExchangeState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExchangeState {
+    Sending,
+    Reading,
+    Waiting,
+}
+
+impl Exchange {
+    /// Send a command, which is everything after the status byte set from
+    /// `command_type`, and read its reply.
+    ///
+    /// An interim reply is accepted if the command is a notify.
+    pub fn new(command_type: CommandType, command: &[u8], policy: RetryPolicy) -> Self {
+        let mut query = Vec::with_capacity(command.len() + 1);
+        query.push(NetmdStatus::from(command_type) as u8);
+        query.extend_from_slice(command);
+
+        trace!("SENT>>> {}", dissect(&query));
+        Self {
+            send: Some(SendCommand::new(query)),
+            accept_interim: command_type == CommandType::Notify,
+            ..Self::reply(policy)
+        }
+    }
+
+    /// Only read the reply to a command which was already sent
+    pub fn reply(policy: RetryPolicy) -> Self {
+        Self {
+            send: None,
+            read: ReadReply::new(policy),
+            policy,
+            accept_interim: false,
+            interim_attempts: 0,
+            state: ExchangeState::Reading,
+        }
+    }
+
+    /// Sets whether an interim reply is returned, instead of being retried
+    pub fn accept_interim(mut self, accept_interim: bool) -> Self {
+        self.accept_interim = accept_interim;
+        self
+    }
+
+    fn read(&mut self) -> Step<Reply> {
+        self.state = ExchangeState::Reading;
+        Step::Request(self.read.start())
+    }
+}
+
+impl Machine for Exchange {
+    type Output = Reply;
+    type Error = InterfaceError;
+
+    fn start(&mut self) -> Request {
+        match self.send.as_mut() {
+            Some(send) => {
+                self.state = ExchangeState::Sending;
+                send.start()
+            }
+            None => {
+                self.state = ExchangeState::Reading;
+                self.read.start()
+            }
+        }
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<Reply>, InterfaceError> {
+        match self.state {
+            ExchangeState::Sending => {
+                let Some(send) = self.send.as_mut() else {
+                    return unexpected(response).map_err(Into::into);
+                };
+                match send.advance(response)? {
+                    Step::Request(request) => Ok(Step::Request(request)),
+                    Step::Done(()) => Ok(self.read()),
+                }
+            }
+            ExchangeState::Reading => {
+                let data = match self.read.advance(response)? {
+                    Step::Request(request) => return Ok(Step::Request(request)),
+                    Step::Done(data) => data,
+                };
+
+                let reply = Reply::from_data(data)?;
+                if reply.status != NetmdStatus::Interim || self.accept_interim {
+                    return Ok(Step::Done(reply));
+                }
+
+                self.interim_attempts += 1;
+                if self.interim_attempts >= self.policy.max_interim_attempts {
+                    return Err(InterfaceError::MaxRetries);
+                }
+
+                self.state = ExchangeState::Waiting;
+                let delay = self.policy.interim_delay(self.interim_attempts - 1);
+                Ok(Step::Request(Request::Wait(delay)))
+            }
+            ExchangeState::Waiting => match response {
+                // Retry!
+                Response::Waited => Ok(self.read()),
+                response => unexpected(response).map_err(Into::into),
+            },
+        }
+    }
+}
+
+/// The groups on a disc, as half width title, full width title and the
+/// tracks in it, starting at 0. The first group holds the tracks which are
+/// not in any group, and has no titles.
+pub type GroupList = Vec<(Option<String>, Option<String>, Vec<u16>)>;
+
+/// Parse the groups out of the raw half width and full width disc titles,
+/// see [`super::NetMDInterface::raw_disc_title`]
+pub fn parse_groups(
+    raw_title: &str,
+    raw_full_title: &str,
+    track_count: u16,
+) -> Result<GroupList, InterfaceError> {
+    let group_list = raw_title.split("//");
+    let mut track_dict: HashMap<u16, (String, u16)> = HashMap::new();
+    let mut result: GroupList = Vec::new();
+
+    let mut full_width_group_list = raw_full_title.split("／／");
+
+    for (i, group) in group_list.enumerate() {
+        if group.is_empty() {
+            continue;
+        }
+
+        if group.starts_with("0;") || group.find(';').is_none() || !raw_title.contains("//") {
+            continue;
+        }
+
+        let track_range: String = match group.split_once(';') {
+            Some(string) => string.0.to_string(),
+            None => return Err(InterfaceError::GroupError("no groups were found".into())),
+        };
+        if track_range.is_empty() {
+            continue;
+        }
+
+        let group_name = &group[track_range.len() + 1..];
+
+        let full_width_range = half_width_to_full_width_range(&track_range);
+
+        let full_width_group_name = full_width_group_list
+            .find(|n| n.starts_with(&full_width_range))
+            .and_then(|n| n.split_once('；'))
+            .map(|(_, name)| name)
+            .ok_or_else(|| {
+                InterfaceError::InvalidGroup(format!(
+                    "group {} has no full width title",
+                    track_range
+                ))
+            })?;
+
+        let invalid_range =
+            || InterfaceError::InvalidGroup(format!("invalid track range `{}`", track_range));
+
+        let (track_min, track_max) = match track_range.split_once('-') {
+            Some((min, max)) => (min, max),
+            None => (track_range.as_str(), track_range.as_str()),
+        };
+        let track_min = track_min.parse::<u16>().map_err(|_| invalid_range())?;
+        let track_max = track_max.parse::<u16>().map_err(|_| invalid_range())?;
+
+        let track_max = u16::min(track_max, track_count);
+        if track_min == 0 || track_min > track_max {
+            return Err(invalid_range());
+        }
+
+        let mut track_list: Vec<u16> = Vec::new();
+        for track in track_min - 1..track_max {
+            if track_dict.contains_key(&track) {
+                return Err(InterfaceError::GroupError(format!(
+                    "track {} is in 2 groups",
+                    track
+                )));
+            }
+            track_dict.insert(track, (String::from(group_name), i as u16));
+            track_list.push(track);
+        }
+
+        result.push((
+            Some(String::from(group_name)),
+            Some(String::from(full_width_group_name)),
+            track_list.clone(),
+        ));
+    }
+
+    let mut remaining_tracks = Vec::new();
+    for i in 0..track_count {
+        if !track_dict.contains_key(&i) {
+            remaining_tracks.push(i);
+        }
+    }
+    result.insert(0, (None, None, remaining_tracks));
+
+    Ok(result)
+}
+
+/// Decodes an accepted reply, including its status byte
+type Decode<O> = Box<dyn FnOnce(Vec<u8>) -> Result<O, InterfaceError> + Send>;
+
+/// A command to the device and the decoding of its reply.
+///
+/// The functions below build every command [`super::NetMDInterface`] sends.
+/// The command is sent as a control command, and the reply must be accepted
+/// by the device before it is decoded into the output of the command.
+///
+/// Commands which read from a descriptor expect it to be open already, see
+/// [`descriptor_state`].
+pub struct Command<O> {
+    query: Option<Vec<u8>>,
+    accept_interim: bool,
+    policy: RetryPolicy,
+    decode: Option<Decode<O>>,
+    exchange: Option<Exchange>,
+}
+
+impl<O> Command<O> {
+    fn new<F>(query: Vec<u8>, decode: F) -> Self
+    where
+        F: FnOnce(Vec<u8>) -> Result<O, InterfaceError> + Send + 'static,
+    {
+        Self {
+            query: Some(query),
+            accept_interim: false,
+            policy: RetryPolicy::default(),
+            decode: Some(Box::new(decode)),
+            exchange: None,
+        }
+    }
+
+    /// Only read the reply to a command which was already sent
+    fn reply<F>(decode: F) -> Self
+    where
+        F: FnOnce(Vec<u8>) -> Result<O, InterfaceError> + Send + 'static,
+    {
+        Self {
+            query: None,
+            ..Self::new(Vec::new(), decode)
+        }
+    }
+
+    /// Sets whether an interim reply is decoded, instead of being retried
+    pub fn accept_interim(mut self, accept_interim: bool) -> Self {
+        self.accept_interim = accept_interim;
+        self
+    }
+
+    /// Sets the policy used when waiting for the reply
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The command which is sent, without its status byte. This is empty
+    /// for a command which only reads a reply.
+    pub fn query(&self) -> &[u8] {
+        self.query.as_deref().unwrap_or_default()
+    }
+
+    /// Ask the device whether it supports the command, without running it
+    pub fn inquiry(self) -> Inquiry {
+        Inquiry {
+            query: self.query.unwrap_or_default(),
+            policy: self.policy,
+            exchange: None,
+        }
+    }
+}
+
+impl<O> Machine for Command<O> {
+    type Output = O;
+    type Error = InterfaceError;
+
+    fn start(&mut self) -> Request {
+        let exchange = match &self.query {
+            Some(query) => Exchange::new(CommandType::Control, query, self.policy),
+            None => Exchange::reply(self.policy),
+        };
+
+        self.exchange
+            .insert(exchange.accept_interim(self.accept_interim))
+            .start()
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<O>, InterfaceError> {
+        let Some(exchange) = self.exchange.as_mut() else {
+            return unexpected(response).map_err(Into::into);
+        };
+        let reply = match exchange.advance(response)? {
+            Step::Request(request) => return Ok(Step::Request(request)),
+            Step::Done(reply) => reply.accepted()?,
+        };
+
+        let decode = self.decode.take().ok_or(NetMDError::InvalidResult)?;
+        decode(reply.data).map(Step::Done)
+    }
+}
+
+/// Ask the device whether it supports a command, see [`Command::inquiry`].
+///
+/// This is `false` when the device replies that the command is not
+/// implemented or would be rejected.
+#[derive(Debug, Clone)]
+pub struct Inquiry {
+    query: Vec<u8>,
+    policy: RetryPolicy,
+    exchange: Option<Exchange>,
+}
+
+impl Inquiry {
+    /// Sets the policy used when waiting for the reply
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Machine for Inquiry {
+    type Output = bool;
+    type Error = InterfaceError;
+
+    fn start(&mut self) -> Request {
+        let exchange = Exchange::new(CommandType::GeneralInquiry, &self.query, self.policy);
+
+        self.exchange.insert(exchange).start()
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<bool>, InterfaceError> {
+        let Some(exchange) = self.exchange.as_mut() else {
+            return unexpected(response).map_err(Into::into);
+        };
+        let reply = match exchange.advance(response)? {
+            Step::Request(request) => return Ok(Step::Request(request)),
+            Step::Done(reply) => reply,
+        };
+
+        match reply.accepted() {
+            Ok(_) => Ok(Step::Done(true)),
+            Err(InterfaceError::NotImplemented(_)) | Err(InterfaceError::Rejected(_)) => {
+                Ok(Step::Done(false))
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Open or close a descriptor by its raw ID, f. ex. `[0x10, 0x18, 0x01]`
+/// for the disc title
+pub fn descriptor_state(
+    descriptor_id: &[u8],
+    action: DescriptorAction,
+) -> Result<Command<()>, InterfaceError> {
+    let mut query = query!("1808")?;
+    query.extend_from_slice(descriptor_id);
+    query.push(action as u8);
+    query.push(0x00);
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+/// Read the disc subunit identifier, from the open disc subunit identifier
+/// descriptor
+pub fn disc_subunit_identifier() -> Result<Command<SubunitIdentifier>, InterfaceError> {
+    let query = query!("1809 00 ff00 0000 0000")?;
+
+    Ok(Command::new(query, |reply| {
+        let (
+            _descriptor_length,
+            generation_id,
+            size_of_list_id,
+            _size_of_object_id,
+            _size_of_object_position,
+            amt_of_root_object_lists,
+            buffer,
+        ): (u16, u8, u8, u8, u8, u16, Vec<u8>) =
+            scan!(reply, "1809 00 1000 %?%? %?%? %w %b %b %b %b %w %*")?;
+
+        parse_subunit_identifier(
+            &buffer,
+            generation_id,
+            size_of_list_id,
+            amt_of_root_object_lists,
+        )
+    }))
+}
+
+fn construct_multibyte(buffer: &[u8], n: u8, offset: &mut usize) -> Result<u32, InterfaceError> {
+    let mut output: u32 = 0;
+    for _ in 0..n as usize {
+        output <<= 8;
+        output |= *buffer
+            .get(*offset)
+            .ok_or(InterfaceError::TruncatedDescriptor)? as u32;
+        *offset += 1;
+    }
+    Ok(output)
+}
+
+fn take_bytes<'b>(
+    buffer: &'b [u8],
+    n: usize,
+    offset: &mut usize,
+) -> Result<&'b [u8], InterfaceError> {
+    let bytes = buffer
+        .get(*offset..*offset + n)
+        .ok_or(InterfaceError::TruncatedDescriptor)?;
+    *offset += n;
+    Ok(bytes)
+}
+
+fn parse_subunit_identifier(
+    buffer: &[u8],
+    generation_id: u8,
+    size_of_list_id: u8,
+    amt_of_root_object_lists: u16,
+) -> Result<SubunitIdentifier, InterfaceError> {
+    let mut offset: usize = 0;
+
+    let mut root_objects: Vec<u32> = Vec::new();
+    for _ in 0..amt_of_root_object_lists {
+        root_objects.push(construct_multibyte(buffer, size_of_list_id, &mut offset)?);
+    }
+
+    let _subunit_dependent_length = construct_multibyte(buffer, 2, &mut offset)?;
+    let _subunit_fields_length = construct_multibyte(buffer, 2, &mut offset)?;
+    let attributes = construct_multibyte(buffer, 1, &mut offset)? as u8;
+    let disc_subunit_version = construct_multibyte(buffer, 1, &mut offset)? as u8;
+
+    let mut media = Vec::new();
+    let amt_supported_media_types = construct_multibyte(buffer, 1, &mut offset)?;
+    for _ in 0..amt_supported_media_types {
+        let supported_media_type = construct_multibyte(buffer, 2, &mut offset)?;
+        let implementation_profile_id = construct_multibyte(buffer, 1, &mut offset)? as u8;
+        let media_type_attributes = construct_multibyte(buffer, 1, &mut offset)? as u8;
+
+        // The audio version and clip support, then anything unknown
+        let type_dep_length = construct_multibyte(buffer, 2, &mut offset)?;
+        let type_dep = take_bytes(buffer, type_dep_length as usize, &mut offset)?;
+
+        media.push(MediaInfo {
+            supported_media_type,
+            implementation_profile_id,
+            media_type_attributes,
+            md_audio_version: type_dep.first().copied().unwrap_or_default(),
+            supports_md_clip: type_dep.get(1).is_some_and(|clip| *clip != 0),
+        })
+    }
+
+    let manufacturer_dep_length = construct_multibyte(buffer, 2, &mut offset)?;
+    let manufacturer_data =
+        take_bytes(buffer, manufacturer_dep_length as usize, &mut offset)?.to_vec();
+
+    Ok(SubunitIdentifier {
+        generation_id,
+        root_objects,
+        attributes,
+        disc_subunit_version,
+        media,
+        manufacturer_data,
+    })
+}
+
+/// Play, pause, fast forward or rewind
+pub fn playback_control(action: Action) -> Result<Command<()>, InterfaceError> {
+    let query = query!("18c3 ff %b 000000", action as u8)?;
+
+    Ok(Command::new(query, |reply| {
+        let _action: u8 = scan!(reply, "18c3 00 %b 000000")?;
+        Ok(())
+    }))
+}
+
+/// Stop playback
+pub fn stop() -> Result<Command<()>, InterfaceError> {
+    let query = query!("18c5 ff 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "18c5 00 00000000")?;
+        Ok(())
+    }))
+}
+
+/// Take control of the device
+pub fn acquire() -> Result<Command<()>, InterfaceError> {
+    let query = query!("ff 010c ffff ffff ffff ffff ffff ffff")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "ff 010c ffff ffff ffff ffff ffff ffff")?;
+        Ok(())
+    }))
+}
+
+/// Give control of the device back
+pub fn release() -> Result<Command<()>, InterfaceError> {
+    let query = query!("ff 0100 ffff ffff ffff ffff ffff ffff")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "ff 0100 ffff ffff ffff ffff ffff ffff")?;
+        Ok(())
+    }))
+}
+
+/// Read the status block, from the open operating status block descriptor.
+///
+/// The same command can be registered as a notify.
+pub fn status() -> Result<Command<StatusBlock>, InterfaceError> {
+    let query = query!("1809 8001 0230 8800 0030 8804 00 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let status: Vec<u8> = scan!(reply, "1809 8001 0230 8800 0030 8804 00 1000 00090000 %x")?;
+        StatusBlock::from_raw(status)
+    }))
+}
+
+/// Read the status mode and the raw operating status, from the open
+/// operating status block descriptor.
+///
+/// The same command can be registered as a notify.
+pub fn operating_status() -> Result<Command<(u8, u16)>, InterfaceError> {
+    let query = query!("1809 8001 0330 8802 0030 8805 0030 8806 00 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let (status_mode, operating_status): (u8, Vec<u8>) = scan!(
+            reply,
+            "1809 8001 0330 8802 0030 8805 0030 8806 00 1000 00%?0000 00%b 8806 %x"
+        )?;
+
+        let operating_status_number = match operating_status[..] {
+            [high, low, ..] => u16::from_be_bytes([high, low]),
+            [status] => return Err(InterfaceError::InvalidStatus(StatusError(status as u16))),
+            [] => return Err(InterfaceError::MissingField("the operating status")),
+        };
+
+        Ok((status_mode, operating_status_number))
+    }))
+}
+
+/// Read a field of the operating status block, from the open operating
+/// status block descriptor
+pub fn playback_status(p1: u32, p2: u32) -> Result<Command<PlaybackStatus>, InterfaceError> {
+    let query = query!(
+        "1809 8001 0330 %w 0030 8805 0030 %w 00 ff00 00000000",
+        p1,
+        p2
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let status: Vec<u8> = scan!(
+            reply,
+            "1809 8001 0330 %?%? %?%? %?%? %?%? %?%? %? 1000 00%?0000 %x %?"
+        )?;
+
+        PlaybackStatus::from_raw(&status)
+    }))
+}
+
+/// Read the playback position as track, hours, minutes, seconds and frames,
+/// from the open operating status block descriptor
+pub fn position() -> Result<Command<[u16; 5]>, InterfaceError> {
+    let query = query!("1809 8001 0430 8802 0030 8805 0030 0003 0030 0002 00 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let (track, hours, minutes, seconds, frames): (u16, u16, u16, u16, u16) = scan!(reply, "1809 8001 0430 %?%? %?%? %?%? %?%? %?%? %?%? %?%? %? %?00 00%?0000 000b 0002 0007 00 %w %B %B %B %B")?;
+
+        Ok([track, hours, minutes, seconds, frames])
+    }))
+}
+
+/// Eject the disc
+pub fn eject_disc() -> Result<Command<()>, InterfaceError> {
+    let query = query!("18c1 ff 6000")?;
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+/// Move the playback to a track, returning the track
+pub fn go_to_track(track_number: u16) -> Result<Command<u16>, InterfaceError> {
+    let query = query!("1850 ff010000 0000 %w", track_number)?;
+
+    Ok(Command::new(query, |reply| {
+        let track: u16 = scan!(reply, "1850 00010000 0000 %w")?;
+        Ok(track)
+    }))
+}
+
+/// Move the playback to a time in a track, returning the track
+pub fn go_to_time(
+    track_number: u16,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    frame: u8,
+) -> Result<Command<u16>, InterfaceError> {
+    let query = query!(
+        "1850 ff000000 0000 %w %B%B%B%B",
+        track_number,
+        hour,
+        minute,
+        second,
+        frame,
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let (track, _hour, _minute, _second, _frame): (u16, u8, u8, u8, u8) =
+            scan!(reply, "1850 00000000 %?%? %w %B%B%B%B")?;
+        Ok(track)
+    }))
+}
+
+/// Change track in a [`Direction`]
+pub fn track_change(direction: Direction) -> Result<Command<()>, InterfaceError> {
+    let query = query!("1850 ff10 00000000 %w", direction as u16)?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1850 0010 00000000 %?%?")?;
+        Ok(())
+    }))
+}
+
+/// Erase the disc entirely
+pub fn erase_disc() -> Result<Command<()>, InterfaceError> {
+    let query = query!("1840 ff 0000")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1840 00 0000")?;
+        Ok(())
+    }))
+}
+
+/// Read the flags of the disc, from the open root descriptor
+pub fn disc_flags() -> Result<Command<u8>, InterfaceError> {
+    let query = query!("1806 01101000 ff00 0001000b")?;
+
+    Ok(Command::new(query, |reply| {
+        let flags: u8 = scan!(reply, "1806 01101000 1000 0001000b %b")?;
+        Ok(flags)
+    }))
+}
+
+/// Read the number of tracks on the disc, from the open audio contents
+/// descriptor
+pub fn track_count() -> Result<Command<u16>, InterfaceError> {
+    let query = query!("1806 02101001 3000 1000 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let count: u8 = scan!(
+            reply,
+            "1806 02101001 %?%? %?%? 1000 00%?0000 0006 0010000200%b"
+        )?;
+        Ok(count as u16)
+    }))
+}
+
+/// Read the raw disc title, from the open disc title descriptor.
+///
+/// The title is read in chunks, so this sends as many commands as the
+/// device needs.
+pub fn raw_disc_title(wchar: bool) -> ReadDiscTitle {
+    ReadDiscTitle {
+        wchar,
+        policy: RetryPolicy::default(),
+        done: 0,
+        total: 1,
+        chunks: Vec::new(),
+        command: None,
+    }
+}
+
+/// The machine which reads the disc title, see [`raw_disc_title`]
+pub struct ReadDiscTitle {
+    wchar: bool,
+    policy: RetryPolicy,
+    done: i32,
+    total: i32,
+    chunks: Vec<String>,
+    command: Option<Command<(i32, Option<i32>, String)>>,
+}
+
+impl ReadDiscTitle {
+    /// Sets the policy used when waiting for the replies
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn chunk(&self) -> Result<Command<(i32, Option<i32>, String)>, InterfaceError> {
+        let first = self.done == 0;
+        let remaining = match first {
+            true => 0,
+            false => self.total - self.done,
+        };
+        let query = query!(
+            "1806 02201801 00%b 3000 0a00 ff00 %w%w",
+            self.wchar as u8,
+            remaining,
+            self.done,
+        )?;
+
+        let command = Command::new(query, move |reply| {
+            if first {
+                let (chunksize, total, data): (i32, i32, Vec<u8>) = scan!(
+                    reply,
+                    "1806 02201801 00%? 3000 0a00 1000 %w0000 %?%?000a %w %*"
+                )?;
+                Ok((chunksize - 6, Some(total), SHIFT_JIS.decode(&data).0.into()))
+            } else {
+                let (chunksize, data): (i32, Vec<u8>) =
+                    scan!(reply, "1806 02201801 00%? 3000 0a00 1000 %w%?%? %*")?;
+                Ok((chunksize, None, SHIFT_JIS.decode(&data).0.into()))
+            }
+        });
+
+        Ok(command.with_policy(self.policy))
+    }
+}
+
+impl Machine for ReadDiscTitle {
+    type Output = String;
+    type Error = InterfaceError;
+
+    fn start(&mut self) -> Request {
+        self.done = 0;
+        self.total = 1;
+        self.chunks.clear();
+
+        // The first chunk is always valid to encode
+        let mut command = self.chunk().unwrap();
+        let request = command.start();
+        self.command = Some(command);
+
+        request
+    }
+
+    fn advance(&mut self, response: Response) -> Result<Step<String>, InterfaceError> {
+        let Some(command) = self.command.as_mut() else {
+            return unexpected(response).map_err(Into::into);
+        };
+        let (chunksize, total, chunk) = match command.advance(response)? {
+            Step::Request(request) => return Ok(Step::Request(request)),
+            Step::Done(chunk) => chunk,
+        };
+
+        if let Some(total) = total {
+            self.total = total;
+        }
+        self.chunks.push(chunk);
+        self.done += chunksize;
+
+        if self.done < self.total {
+            let mut command = self.chunk()?;
+            let request = command.start();
+            self.command = Some(command);
+            return Ok(Step::Request(request));
+        }
+
+        self.command = None;
+        Ok(Step::Done(self.chunks.concat()))
+    }
+}
+
+/// Read the title of a track, from the open audio UTOC1 descriptor or the
+/// audio UTOC4 descriptor for the full width title
+pub fn track_title(track: u16, wchar: bool) -> Result<Command<String>, InterfaceError> {
+    let wchar_value = match wchar {
+        true => 3,
+        false => 2,
+    };
+    let query = query!(
+        "1806 022018%b %w 3000 0a00 ff00 00000000",
+        wchar_value,
+        track
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let title: Vec<u8> = scan!(
+            reply,
+            "1806 022018%? %?%? %?%? %?%? 1000 00%?0000 00%?000a %x"
+        )?;
+        Ok(SHIFT_JIS.decode(&title).0.into())
+    }))
+}
+
+/// Write the disc title replacing `old_title`, to the open disc title
+/// descriptor, or the audio UTOC1 descriptor on some devices
+pub fn set_disc_title(
+    title: &str,
+    old_title: &str,
+    wchar: bool,
+) -> Result<Command<()>, InterfaceError> {
+    let (wchar_value, new_title) = match wchar {
+        true => (1, to_sjis(&sanitize_full_width_title(title))),
+        false => (0, to_sjis(&sanitize_half_width_title(title))),
+    };
+
+    let query = query!(
+        "1807 02201801 00%b 3000 0a00 5000 %w 0000 %w %*",
+        wchar_value,
+        new_title.len(),
+        length_after_encoding_to_sjis(old_title),
+        new_title,
+    )?;
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+/// Write the title of a track replacing `old_title`, to the open audio UTOC1
+/// descriptor or the audio UTOC4 descriptor for the full width title
+pub fn set_track_title(
+    track: u16,
+    title: &str,
+    old_title: &str,
+    wchar: bool,
+) -> Result<Command<()>, InterfaceError> {
+    let (wchar_value, new_title) = match wchar {
+        true => (3, to_sjis(&sanitize_full_width_title(title))),
+        false => (2, to_sjis(&sanitize_half_width_title(title))),
+    };
+
+    let query = query!(
+        "1807 022018%b %w 3000 0a00 5000 %w 0000 %w %*",
+        wchar_value,
+        track,
+        new_title.len(),
+        length_after_encoding_to_sjis(old_title) as u16,
+        new_title,
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1807 022018%? %?%? 3000 0a00 5000 %?%? 0000 %?%?")?;
+        Ok(())
+    }))
+}
+
+/// Erase a track from the disc
+pub fn erase_track(track: u16) -> Result<Command<()>, InterfaceError> {
+    let query = query!("1840 ff01 00 201001 %w", track)?;
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+/// Move a track to another index on the disc
+pub fn move_track(source: u16, dest: u16) -> Result<Command<()>, InterfaceError> {
+    let query = query!("1843 ff00 00 201001 %w 201001 %w", source, dest)?;
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+/// Read raw information about a track, from the open audio contents
+/// descriptor
+pub fn track_info(track: u16, p1: i32, p2: i32) -> Result<Command<Vec<u8>>, InterfaceError> {
+    track_info_then(track, p1, p2, Ok)
+}
+
+fn track_info_then<O, F>(
+    track: u16,
+    p1: i32,
+    p2: i32,
+    decode: F,
+) -> Result<Command<O>, InterfaceError>
+where
+    F: FnOnce(Vec<u8>) -> Result<O, InterfaceError> + Send + 'static,
+{
+    let query = query!("1806 02201001 %w %w %w ff00 00000000", track, p1, p2)?;
+
+    Ok(Command::new(query, move |reply| {
+        let info: Vec<u8> = scan!(reply, "1806 02201001 %?%? %?%? %?%? 1000 00%?0000 %x")?;
+        decode(info)
+    }))
+}
+
+/// Read the length of a track, from the open audio contents descriptor
+pub fn track_length(track: u16) -> Result<Command<RawTime>, InterfaceError> {
+    track_info_then(track, 0x3000, 0x0100, |info| {
+        let (hours, minutes, seconds, frames) = scan!(info, "01 0006 0000 %B %B %B %B")?;
+
+        Ok(RawTime {
+            hours,
+            minutes,
+            seconds,
+            frames,
+        })
+    })
+}
+
+/// Read the encoding of a track, from the open audio contents descriptor
+pub fn track_encoding(track: u16) -> Result<Command<(Encoding, Channels)>, InterfaceError> {
+    track_info_then(track, 0x3080, 0x0700, |info| {
+        let (encoding, channels): (u8, u8) = scan!(info, "07 0004 0110 %b %b")?;
+
+        let parameters = RecordingParameters::from_bytes(encoding, channels)?;
+        Ok((parameters.encoding, parameters.channels))
+    })
+}
+
+/// Read the flags of a track, from the open audio contents descriptor
+pub fn track_flags(track: u16) -> Result<Command<u8>, InterfaceError> {
+    let query = query!("1806 01201001 %w ff00 00010008", track)?;
+
+    Ok(Command::new(query, |reply| {
+        let flags: u8 = scan!(reply, "1806 01201001 %?%? 10 00 00010008 %b")?;
+        Ok(flags)
+    }))
+}
+
+/// Read the used, total and remaining time on the disc, from the open root
+/// descriptor.
+///
+/// The first value is a byte in the reply which is `0x80` on most devices
+/// and `0x08` on Panasonic devices, see [`super::base::Capabilities::capacity_flag`].
+pub fn disc_capacity() -> Result<Command<(u8, [RawTime; 3])>, InterfaceError> {
+    let query = query!("1806 02101000 3080 0300 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let (flag, h1, m1, s1, f1, h2, m2, s2, f2, h3, m3, s3, f3) = scan!(
+            reply,
+            "1806 02101000 3080 0300 1000 001d0000 001b %b03 0017 8000 0005 %W %B %B %B 0005 %W %B %B %B 0005 %W %B %B %B"
+        )?;
+
+        let time = |hours, minutes, seconds, frames| RawTime {
+            hours,
+            minutes,
+            seconds,
+            frames,
+        };
+
+        Ok((
+            flag,
+            [
+                time(h1, m1, s1, f1),
+                time(h2, m2, s2, f2),
+                time(h3, m3, s3, f3),
+            ],
+        ))
+    }))
+}
+
+/// Read the mode the device will record in, from the open operating status
+/// block descriptor
+pub fn recording_parameters() -> Result<Command<RecordingParameters>, InterfaceError> {
+    let query = query!("1809 8001 0330 8801 0030 8805 0030 8807 00 ff00 00000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let (p1, p2): (u8, u8) = scan!(reply, "1809 8001 0330 8801 0030 8805 0030 8807 00 1000 000e0000 000c 8805 0008 80e0 0110 %b %b 4000")?;
+
+        RecordingParameters::from_bytes(p1, p2)
+    }))
+}
+
+/// Start uploading a track, returning its frames, codec and length in
+/// bytes. The data is then read in bulk, followed by
+/// [`save_track_finished`].
+pub fn save_track(track: u16) -> Result<Command<(u16, u8, usize)>, InterfaceError> {
+    let query = query!("1800 080046 f003010330 ff00 1001 %w", track + 1)?;
+
+    let command = Command::new(query, |reply| {
+        let (frames, codec, length): (u16, u8, usize) =
+            scan!(reply, "1800 080046 f0030103 300000 1001 %w %b %d")?;
+        Ok((frames, codec, length))
+    });
+
+    Ok(command.accept_interim(true))
+}
+
+/// Read the reply once an uploaded track has been read, see [`save_track`]
+pub fn save_track_finished() -> Command<()> {
+    Command::reply(|reply| {
+        let () = scan!(reply, "1800 080046 f003010330 0000 1001 %?%? %?%?")?;
+        Ok(())
+    })
+}
+
+/// The format of an uploaded track from its codec, see [`save_track`]
+pub fn disc_format(codec: u8) -> Result<DiscFormat, InterfaceError> {
+    match codec & 0x06 {
+        0 => Ok(DiscFormat::LP4),
+        2 => Ok(DiscFormat::LP2),
+        4 => Ok(DiscFormat::SPMono),
+        6 => Ok(DiscFormat::SPStereo),
+        e => Err(InterfaceError::InvalidDiscFormat(e)),
+    }
+}
+
+/// Allow a track downloaded later to be uploaded again
+pub fn disable_new_track_protection(val: u16) -> Result<Command<()>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 2b ff %w", val)?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 2b 00 %?%?")?;
+        Ok(())
+    }))
+}
+
+/// Enter a secure session, which is needed to download tracks
+pub fn enter_secure_session() -> Result<Command<()>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 80 ff")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 80 00")?;
+        Ok(())
+    }))
+}
+
+/// Leave the secure session
+pub fn leave_secure_session() -> Result<Command<()>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 81 ff")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 81 00")?;
+        Ok(())
+    }))
+}
+
+/// Read the leaf ID of the device, which tells which keys it has
+pub fn leaf_id() -> Result<Command<[u8; 8]>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 11 ff")?;
+
+    Ok(Command::new(query, |reply| {
+        let leaf_id = scan!(reply, "1800 080046 f0030103 11 00 %*")?;
+        Ok(leaf_id)
+    }))
+}
+
+/// Send the parts of the EKB the device needs to decrypt the root key
+pub fn send_key_data(
+    ekbid: i32,
+    keychain: [[u8; 16]; 2],
+    depth: i32,
+    ekbsignature: [u8; 24],
+) -> Result<Command<()>, InterfaceError> {
+    let chainlen = keychain.len();
+    let databytes = 16 + 16 * chainlen + 24;
+
+    if !(1..=63).contains(&depth) {
+        return Err(EncryptionError::InvalidDepth(depth).into());
+    }
+
+    let keychains = keychain.concat();
+
+    let query = query!(
+        "1800 080046 f0030103 12 ff %w 0000 %w %d %d %d 00000000 %* %*",
+        databytes,
+        databytes,
+        chainlen,
+        depth,
+        ekbid,
+        keychains,
+        ekbsignature,
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 12 01 %?%? %?%?%?%?")?;
+        Ok(())
+    }))
+}
+
+/// Exchange nonces with the device, returning the device nonce
+pub fn session_key_exchange(host_nonce: &[u8]) -> Result<Command<Vec<u8>>, InterfaceError> {
+    if host_nonce.len() != 8 {
+        return Err(EncryptionError::InvalidLength("host nonce", host_nonce.len()).into());
+    }
+
+    let query = query!("1800 080046 f0030103 20 ff 000000 %*", host_nonce)?;
+
+    Ok(Command::new(query, |reply| {
+        let device_nonce = scan!(reply, "1800 080046 f0030103 20 %? 000000 %#")?;
+        Ok(device_nonce)
+    }))
+}
+
+/// Make the device forget the session key
+pub fn session_key_forget() -> Result<Command<()>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 21 ff 000000")?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 21 00 000000")?;
+        Ok(())
+    }))
+}
+
+/// Send the content ID and the key encryption key for the next track,
+/// encrypted with the session key
+pub fn setup_download(
+    contentid: &[u8],
+    keyenckey: &[u8],
+    hex_session_key: &[u8],
+) -> Result<Command<()>, InterfaceError> {
+    if contentid.len() != 20 {
+        return Err(EncryptionError::InvalidLength("content ID", contentid.len()).into());
+    }
+    if keyenckey.len() != 8 {
+        return Err(EncryptionError::InvalidLength("key encryption", keyenckey.len()).into());
+    }
+    check_session_key("session key", hex_session_key)?;
+
+    let mut message = [vec![1, 1, 1, 1], contentid.to_vec(), keyenckey.to_vec()].concat();
+    DesCbcEnc::new(hex_session_key.into(), &[0u8; 8].into())
+        .encrypt_padded_mut::<NoPadding>(message.as_mut_slice(), 32)
+        .unwrap();
+
+    let query = query!("1800 080046 f0030103 22 ff 0000 %*", message)?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 22 00 0000")?;
+        Ok(())
+    }))
+}
+
+/// Commit a downloaded track, signed with the session key
+pub fn commit_track(
+    track_number: u16,
+    hex_session_key: &[u8],
+) -> Result<Command<()>, InterfaceError> {
+    check_session_key("hex session key", hex_session_key)?;
+
+    let mut message = [0u8; 8];
+    DesEcbEnc::new(hex_session_key.into())
+        .encrypt_padded_mut::<NoPadding>(&mut message, 8)
+        .unwrap();
+
+    let query = query!(
+        "1800 080046 f0030103 48 ff 00 1001 %w %*",
+        track_number,
+        message
+    )?;
+
+    Ok(Command::new(query, |reply| {
+        let () = scan!(reply, "1800 080046 f0030103 48 00 00 1001 %?%?")?;
+        Ok(())
+    }))
+}
+
+/// Start downloading a track of `total_bytes`, including the header of the
+/// first packet. The packets are then written in bulk, see
+/// [`download_packet`], followed by [`track_sent`].
+pub fn send_track(
+    wireformat: u8,
+    discformat: u8,
+    frames: u32,
+    total_bytes: usize,
+) -> Result<Command<()>, InterfaceError> {
+    let query = query!(
+        "1800 080046 f0030103 28 ff 000100 1001 ffff 00 %b %b %d %d",
+        wireformat,
+        discformat,
+        frames,
+        total_bytes,
+    )?;
+
+    let command = Command::new(query, |reply| {
+        let _: Vec<u8> = scan!(reply, "1800 080046 f0030103 28 00 000100 1001 %?%? 00 %*")?;
+        Ok(())
+    });
+
+    Ok(command.accept_interim(true))
+}
+
+/// Frame an encrypted packet of a track for the bulk transfer. The first
+/// packet starts with the length of the track and its key and IV.
+pub fn download_packet(
+    index: usize,
+    packet_size: u32,
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    data: Vec<u8>,
+) -> Vec<u8> {
+    match index {
+        0 => [
+            vec![0, 0, 0, 0],
+            packet_size.to_be_bytes().to_vec(),
+            key,
+            iv,
+            data,
+        ]
+        .concat(),
+        _ => data,
+    }
+}
+
+/// The index of a newly written track, its UUID and its content ID
+type SentTrack = (u16, Vec<u8>, Vec<u8>);
+
+/// Read the reply once a track has been written, returning the index of the
+/// new track, its UUID and its content ID, decrypted with the session key
+pub fn track_sent(hex_session_key: &[u8]) -> Result<Command<SentTrack>, InterfaceError> {
+    check_session_key("hex session key", hex_session_key)?;
+    let hex_session_key = hex_session_key.to_vec();
+
+    Ok(Command::reply(move |reply| {
+        let (track, mut encrypted_data): (u16, Vec<u8>) = scan!(
+            reply,
+            "1800 080046 f0030103 28 00 000100 1001 %w 00 %?%? %?%?%?%? %?%?%?%? %*"
+        )?;
+
+        if encrypted_data.len() != 32 {
+            return Err(EncryptionError::InvalidLength("track reply", encrypted_data.len()).into());
+        }
+        DesCbcDec::new(hex_session_key.as_slice().into(), &[0u8; 8].into())
+            .decrypt_padded_mut::<NoPadding>(&mut encrypted_data)
+            .unwrap();
+
+        let part1 = encrypted_data[0..8].to_vec();
+        let part2 = encrypted_data[12..32].to_vec();
+
+        Ok((track, part1, part2))
+    }))
+}
+
+/// Read the UUID of a track
+pub fn track_uuid(track: u16) -> Result<Command<String>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 23 ff 1001 %w", track)?;
+
+    Ok(Command::new(query, |reply| {
+        let uuid: Vec<u8> = scan!(reply, "1800 080046 f0030103 23 00 1001 %?%? %*")?;
+        Ok(String::from_utf8_lossy(&uuid).to_string())
+    }))
+}
+
+/// Terminate the secure session
+pub fn terminate() -> Result<Command<()>, InterfaceError> {
+    let query = query!("1800 080046 f0030103 2a ff00")?;
+
+    Ok(Command::new(query, |_| Ok(())))
+}
+
+fn check_session_key(name: &'static str, key: &[u8]) -> Result<(), EncryptionError> {
+    match key.len() {
+        8 => Ok(()),
+        length => Err(EncryptionError::InvalidLength(name, length)),
+    }
+}
+
+type DesEcbEnc = ecb::Encryptor<des::Des>;
+type DesCbcEnc = cbc::Encryptor<des::Des>;
+type DesCbcDec = cbc::Decryptor<des::Des>;
+type TDesCbcEnc = cbc::Encryptor<des::TdesEde3>;
+
+/// Derive the session key from the nonces of the host and the device
+pub fn session_key(host_nonce: &[u8], device_nonce: &[u8]) -> Result<Vec<u8>, InterfaceError> {
+    if host_nonce.len() != 8 {
+        return Err(EncryptionError::InvalidLength("host nonce", host_nonce.len()).into());
+    }
+    if device_nonce.len() != 8 {
+        return Err(EncryptionError::InvalidLength("device nonce", device_nonce.len()).into());
+    }
+
+    let nonce = [host_nonce, device_nonce].concat();
+
+    Ok(retailmac(&EKBOpenSource.root_key(), &nonce, &[0u8; 8]))
+}
+
+pub(super) fn retailmac(key: &[u8], value: &[u8], iv: &[u8; 8]) -> Vec<u8> {
+    let mut subkey_a = [0u8; 8];
+    subkey_a.copy_from_slice(&key[0..8]);
+
+    let mut beginning = [0u8; 8];
+    beginning.copy_from_slice(&value[0..8]);
+
+    let mut end = [0u8; 8];
+    end.copy_from_slice(&value[8..]);
+
+    DesCbcEnc::new(&subkey_a.into(), iv.into())
+        .encrypt_padded_mut::<NoPadding>(&mut beginning, 8)
+        .unwrap();
+
+    let iv2 = &beginning[beginning.len() - 8..];
+
+    let mut wonky_key = [0u8; 24];
+    wonky_key[0..16].copy_from_slice(key);
+    wonky_key[16..].copy_from_slice(&key[0..8]);
+    TDesCbcEnc::new(&wonky_key.into(), iv2.into())
+        .encrypt_padded_mut::<NoPadding>(&mut end, 8)
+        .unwrap();
+
+    end[..8].to_vec()
+}
+
+pub(super) struct EKBData {
+    pub(super) chains: [[u8; 16]; 2],
+    pub(super) depth: i32,
+    pub(super) signature: [u8; 24],
+}
+
+pub(super) struct EKBOpenSource;
+
+impl EKBOpenSource {
+    pub fn root_key(&self) -> [u8; 16] {
+        [
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x0f, 0xed, 0xcb, 0xa9, 0x87, 0x65,
+            0x43, 0x21,
+        ]
+    }
+
+    pub fn ekb_id(&self) -> i32 {
+        0x26422642
+    }
+
+    pub fn ekb_data_for_leaf_id(&self) -> EKBData {
+        EKBData {
+            chains: [
+                [
+                    0x25, 0x45, 0x06, 0x4d, 0xea, 0xca, 0x14, 0xf9, 0x96, 0xbd, 0xc8, 0xa4, 0x06,
+                    0xc2, 0x2b, 0x81,
+                ],
+                [
+                    0xfb, 0x60, 0xbd, 0xdd, 0x0d, 0xbc, 0xab, 0x84, 0x8a, 0x00, 0x5e, 0x03, 0x19,
+                    0x4d, 0x3e, 0xda,
+                ],
+            ],
+            depth: 9,
+            signature: [
+                0x8f, 0x2b, 0xc3, 0x52, 0xe8, 0x6c, 0x5e, 0xd3, 0x06, 0xdc, 0xae, 0x18, 0xd2, 0xf3,
+                0x8c, 0x7f, 0x89, 0xb5, 0xe1, 0x85, 0x55, 0xa1, 0x05, 0xea,
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::interface::OperatingStatus;
+
+    /// A poll result with a reply of `length` bytes waiting
+    fn polled(length: u16) -> Response {
+        let [low, high] = length.to_le_bytes();
+        Response::Polled([0, 0, low, high])
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            max_total_delay: None,
+            max_interim_attempts: 2,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn read_reply() {
+        let mut read = ReadReply::new(policy());
+
+        assert_eq!(read.start(), Request::Poll);
+        assert_eq!(
+            read.advance(polled(2)).unwrap(),
+            Step::Request(Request::Read(2))
+        );
+        assert_eq!(
+            read.advance(Response::Read(vec![0x09, 0x18])).unwrap(),
+            Step::Done(vec![0x09, 0x18])
+        );
+    }
+
+    #[test]
+    fn read_reply_overridden_length() {
+        let mut read = ReadReply::factory(policy()).with_length(0x10);
+
+        read.start();
+        assert_eq!(
+            read.advance(polled(2)).unwrap(),
+            Step::Request(Request::ReadFactory(0x10))
+        );
+    }
+
+    #[test]
+    fn read_reply_times_out() {
+        let policy = policy();
+        let mut read = ReadReply::new(policy);
+
+        assert_eq!(read.start(), Request::Poll);
+        for attempt in 0..policy.max_attempts {
+            assert_eq!(
+                read.advance(polled(0)).unwrap(),
+                Step::Request(Request::Wait(policy.poll_delay(attempt)))
+            );
+
+            let next = read.advance(Response::Waited);
+            if attempt + 1 < policy.max_attempts {
+                assert_eq!(next.unwrap(), Step::Request(Request::Poll));
+            } else {
+                assert!(matches!(next, Err(NetMDError::Timeout)));
+            }
+        }
+    }
+
+    #[test]
+    fn read_reply_max_total_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            base_delay: Duration::from_millis(10),
+            max_total_delay: Some(Duration::from_millis(50)),
+            ..policy()
+        };
+        let mut read = ReadReply::new(policy);
+
+        // Waits 0, 10 and 30ms, then gives up rather than wait 70ms
+        read.start();
+        for _ in 0..3 {
+            assert!(matches!(
+                read.advance(polled(0)).unwrap(),
+                Step::Request(Request::Wait(_))
+            ));
+            assert_eq!(
+                read.advance(Response::Waited).unwrap(),
+                Step::Request(Request::Poll)
+            );
+        }
+        assert!(matches!(read.advance(polled(0)), Err(NetMDError::Timeout)));
+    }
+
+    #[test]
+    fn read_reply_unexpected_response() {
+        let mut read = ReadReply::new(policy());
+
+        read.start();
+        assert!(matches!(
+            read.advance(Response::Sent),
+            Err(NetMDError::InvalidResult)
+        ));
+    }
+
+    #[test]
+    fn send_command() {
+        let mut send = SendCommand::new(vec![0x00, 0x18, 0xc1]);
+
+        assert_eq!(send.start(), Request::Poll);
+        assert_eq!(
+            send.advance(polled(0)).unwrap(),
+            Step::Request(Request::Send(vec![0x00, 0x18, 0xc1]))
+        );
+        assert_eq!(send.advance(Response::Sent).unwrap(), Step::Done(()));
+    }
+
+    #[test]
+    fn send_command_not_ready() {
+        let mut send = SendCommand::new(vec![0x00, 0x18, 0xc1]);
+
+        send.start();
+        assert!(matches!(send.advance(polled(4)), Err(NetMDError::NotReady)));
+    }
+
+    #[test]
+    fn exchange() {
+        let mut exchange = Exchange::new(CommandType::Control, &[0x18, 0xc1], policy());
+
+        assert_eq!(exchange.start(), Request::Poll);
+        assert_eq!(
+            exchange.advance(polled(0)).unwrap(),
+            Step::Request(Request::Send(vec![0x00, 0x18, 0xc1]))
+        );
+        assert_eq!(
+            exchange.advance(Response::Sent).unwrap(),
+            Step::Request(Request::Poll)
+        );
+        assert_eq!(
+            exchange.advance(polled(3)).unwrap(),
+            Step::Request(Request::Read(3))
+        );
+
+        let Step::Done(reply) = exchange
+            .advance(Response::Read(vec![0x09, 0x18, 0xc1]))
+            .unwrap()
+        else {
+            panic!("the exchange should be done");
+        };
+        assert_eq!(reply.status, NetmdStatus::Accepted);
+        assert_eq!(reply.data, vec![0x09, 0x18, 0xc1]);
+    }
+
+    #[test]
+    fn exchange_retries_interim() {
+        let policy = policy();
+        let mut exchange = Exchange::reply(policy);
+
+        assert_eq!(exchange.start(), Request::Poll);
+        exchange.advance(polled(2)).unwrap();
+        assert_eq!(
+            exchange.advance(Response::Read(vec![0x0f, 0x18])).unwrap(),
+            Step::Request(Request::Wait(policy.interim_delay(0)))
+        );
+
+        // The reply is read again after the wait
+        assert_eq!(
+            exchange.advance(Response::Waited).unwrap(),
+            Step::Request(Request::Poll)
+        );
+        exchange.advance(polled(2)).unwrap();
+        let Step::Done(reply) = exchange.advance(Response::Read(vec![0x09, 0x18])).unwrap() else {
+            panic!("the exchange should be done");
+        };
+        assert_eq!(reply.status, NetmdStatus::Accepted);
+    }
+
+    #[test]
+    fn exchange_interim_max_retries() {
+        let policy = policy();
+        let mut exchange = Exchange::reply(policy);
+
+        exchange.start();
+        for attempt in 0..policy.max_interim_attempts {
+            exchange.advance(polled(2)).unwrap();
+
+            let next = exchange.advance(Response::Read(vec![0x0f, 0x18]));
+            if attempt + 1 < policy.max_interim_attempts {
+                assert_eq!(
+                    next.unwrap(),
+                    Step::Request(Request::Wait(policy.interim_delay(attempt)))
+                );
+                exchange.advance(Response::Waited).unwrap();
+            } else {
+                assert!(matches!(next, Err(InterfaceError::MaxRetries)));
+            }
+        }
+    }
+
+    #[test]
+    fn exchange_accepts_interim() {
+        let mut exchange = Exchange::reply(policy()).accept_interim(true);
+
+        exchange.start();
+        exchange.advance(polled(2)).unwrap();
+        let Step::Done(reply) = exchange.advance(Response::Read(vec![0x0f, 0x18])).unwrap() else {
+            panic!("the exchange should be done");
+        };
+        assert_eq!(reply.status, NetmdStatus::Interim);
+    }
+
+    type Driven<M> = Result<<M as Machine>::Output, <M as Machine>::Error>;
+
+    /// Drive a machine against canned replies, returning its output and the
+    /// commands it sent
+    fn drive<M: Machine>(mut machine: M, replies: &[&[u8]]) -> (Driven<M>, Vec<Vec<u8>>) {
+        let mut replies = replies.iter();
+        let mut waiting = None;
+        let mut sent = Vec::new();
+
+        let mut request = machine.start();
+        loop {
+            let response = match request {
+                Request::Poll => match waiting {
+                    Some(reply) => polled(<[u8]>::len(reply) as u16),
+                    None => polled(0),
+                },
+                Request::Send(command) | Request::SendFactory(command) => {
+                    sent.push(command);
+                    waiting = replies.next().copied();
+                    Response::Sent
+                }
+                Request::Read(_) | Request::ReadFactory(_) => {
+                    Response::Read(waiting.take().unwrap().to_vec())
+                }
+                Request::Wait(_) => Response::Waited,
+            };
+
+            match machine.advance(response) {
+                Ok(Step::Request(next)) => request = next,
+                Ok(Step::Done(output)) => return (Ok(output), sent),
+                Err(error) => return (Err(error), sent),
+            }
+        }
+    }
+
+    #[test]
+    fn command() {
+        let command = go_to_track(5).unwrap();
+        assert_eq!(
+            command.query(),
+            [0x18, 0x50, 0xff, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05]
+        );
+
+        let reply = [
+            0x09, 0x18, 0x50, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+        ];
+        let (track, sent) = drive(command, &[&reply]);
+
+        assert_eq!(track.unwrap(), 5);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0], NetmdStatus::Control as u8);
+    }
+
+    #[test]
+    fn command_rejected() {
+        let reply = [
+            0x0a, 0x18, 0x50, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+        ];
+        let (track, _) = drive(go_to_track(5).unwrap(), &[&reply]);
+
+        assert!(matches!(track, Err(InterfaceError::Rejected(_))));
+    }
+
+    #[test]
+    fn command_reply_mismatch() {
+        let reply = [
+            0x09, 0x18, 0x51, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+        ];
+        let (track, _) = drive(go_to_track(5).unwrap(), &[&reply]);
+
+        assert!(matches!(track, Err(InterfaceError::QueryError(_))));
+    }
+
+    #[test]
+    fn inquiry() {
+        let command = eject_disc().unwrap();
+        let query = command.query().to_vec();
+
+        let implemented = [&[0x0c][..], &query].concat();
+        let (supported, sent) = drive(command.inquiry(), &[&implemented]);
+        assert!(supported.unwrap());
+        assert_eq!(
+            sent[0],
+            [&[NetmdStatus::GeneralInquiry as u8][..], &query].concat()
+        );
+
+        let not_implemented = [&[0x08][..], &query].concat();
+        let (supported, _) = drive(eject_disc().unwrap().inquiry(), &[&not_implemented]);
+        assert!(!supported.unwrap());
+    }
+
+    #[test]
+    fn disc_title_chunks() {
+        let header = [
+            0x09, 0x18, 0x06, 0x02, 0x20, 0x18, 0x01, 0x00, 0x00, 0x30, 0x00, 0x0a, 0x00, 0x10,
+            0x00,
+        ];
+        let first = [
+            &header[..],
+            &[0x00, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x0b],
+            b"Hello",
+        ]
+        .concat();
+        let second = [&header[..], &[0x00, 0x06, 0x00, 0x00], b" world"].concat();
+
+        let (title, sent) = drive(raw_disc_title(false), &[&first, &second]);
+
+        assert_eq!(title.unwrap(), "Hello world");
+        assert_eq!(sent.len(), 2);
+        // The second chunk asks for the remaining 6 bytes after the first 5
+        assert_eq!(sent[1][sent[1].len() - 4..], [0x00, 0x06, 0x00, 0x05]);
+    }
+
+    #[test]
+    fn session_key_length() {
+        let key = session_key(&[1; 8], &[2; 8]).unwrap();
+
+        assert_eq!(key.len(), 8);
+        assert_eq!(key, session_key(&[1; 8], &[2; 8]).unwrap());
+        assert_ne!(key, session_key(&[1; 8], &[3; 8]).unwrap());
+    }
+
+    #[test]
+    fn session_key_short_nonce() {
+        assert!(matches!(
+            session_key(&[1; 8], &[2; 7]),
+            Err(InterfaceError::EncryptionError(_))
+        ));
+        assert!(matches!(
+            session_key(&[], &[2; 8]),
+            Err(InterfaceError::EncryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn session_key_is_checked() {
+        assert!(matches!(
+            setup_download(&[0; 20], &[0; 8], &[0; 4]),
+            Err(InterfaceError::EncryptionError(_))
+        ));
+        assert!(matches!(
+            session_key_exchange(&[0; 7]),
+            Err(InterfaceError::EncryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn playback_status() {
+        let status = PlaybackStatus::from_raw(&[0x88, 0x06, 0x00, 0x02, 0xc3, 0x75]).unwrap();
+
+        assert_eq!(status.field, 0x8806);
+        assert_eq!(status.data, [0xc3, 0x75]);
+        assert_eq!(status.operating_status(), Some(OperatingStatus::Playing));
+    }
+
+    #[test]
+    fn playback_status_short_field() {
+        // The length is wrong, but the status is still in bytes 4 and 5
+        let status = PlaybackStatus::from_raw(&[0x88, 0x06, 0x00, 0x08, 0xc3, 0x75]).unwrap();
+
+        assert_eq!(status.data, [0xc3, 0x75]);
+        assert_eq!(status.operating_status(), Some(OperatingStatus::Playing));
+        assert!(PlaybackStatus::from_raw(&[0x88, 0x06, 0x00]).is_err());
+    }
+
+    #[test]
+    fn groups() {
+        let groups = parse_groups(
+            "0;Disc//1-2;Side A//4;Bonus//",
+            "０；ディスク／／１－２；エー／／４；ボーナス／／",
+            5,
+        )
+        .unwrap();
+
+        assert_eq!(
+            groups,
+            vec![
+                (None, None, vec![2, 4]),
+                (
+                    Some("Side A".to_string()),
+                    Some("エー".to_string()),
+                    vec![0, 1]
+                ),
+                (
+                    Some("Bonus".to_string()),
+                    Some("ボーナス".to_string()),
+                    vec![3]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_groups() {
+        let groups = parse_groups("Disc", "", 3).unwrap();
+
+        assert_eq!(groups, vec![(None, None, vec![0, 1, 2])]);
+    }
+
+    #[test]
+    fn groups_are_clamped_to_track_count() {
+        let groups = parse_groups("0;Disc//1-9;All//", "０；／／１－９；全部／／", 2).unwrap();
+
+        assert_eq!(groups[0], (None, None, vec![]));
+        assert_eq!(groups[1].2, vec![0, 1]);
+    }
+
+    #[test]
+    fn overlapping_groups() {
+        let result = parse_groups(
+            "0;Disc//1-2;A//2-3;B//",
+            "０；／／１－２；Ａ／／２－３；Ｂ／／",
+            3,
+        );
+
+        assert!(matches!(result, Err(InterfaceError::GroupError(_))));
+    }
+
+    #[test]
+    fn invalid_group_range() {
+        let result = parse_groups("0;Disc//3-1;Backwards//", "０；／／３－１；Ｂ／／", 3);
+        assert!(matches!(result, Err(InterfaceError::InvalidGroup(_))));
+
+        let result = parse_groups("0;Disc//1-2;No full width//", "０；／／", 3);
+        assert!(matches!(result, Err(InterfaceError::InvalidGroup(_))));
+    }
+}
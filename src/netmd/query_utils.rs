@@ -1,60 +1,62 @@
+//! Formatting NetMD commands and scanning their replies.
+//!
+//! Commands and replies are described by a format string of hex bytes, with
+//! `%` followed by a character for each value:
+//!
+//! - `%b %w %d %q` - numbers of 1, 2, 4 or 8 bytes, big endian unless
+//!   preceded by `<` (little endian), f. ex. `%<d`
+//! - `%B %W` - BCD-encoded numbers of 1 or 2 bytes
+//! - `%x` - an array preceded by 2 bytes of length
+//! - `%s` - an array preceded by 2 bytes of length, with a trailing NUL
+//! - `%z` - an array preceded by 1 byte of length
+//! - `%*` - a raw array, which is the rest of the reply when scanning
+//! - `%#` - the rest of the reply (scanning only)
+//! - `%?` - a byte which is ignored (scanning only)
+//!
+//! A [`QueryTemplate`] parses a format string once so it can be used for
+//! many commands.
+//!
+//! ```
+//! use minidisc::netmd::query_utils::{QueryTemplate, QueryValue};
+//!
+//! let template = QueryTemplate::new("1850 ff010000 0000 %w").unwrap();
+//!
+//! let query = template.encode(&[QueryValue::Number(3)]).unwrap();
+//! println!("{:02x?}", query);
+//! ```
 use crate::netmd::utils;
+use log::trace;
 use thiserror::Error;
 
-/// %b, w, d, q - explained above (can have endiannes overriden by '>' and '<' operators, f. ex. %>d %<q)
-/// %s - Uint8Array preceded by 2 bytes of length
-/// %x - Uint8Array preceded by 2 bytes of length
-/// %z - Uint8Array preceded by 1 byte of length
-/// %* - raw Uint8Array
-/// %B - BCD-encoded 1-byte number
-/// %W - BCD-encoded 2-byte number
-static FORMAT_TYPE_LEN_DICT: phf::Map<char, i32> = phf::phf_map! {
+static FORMAT_TYPE_LEN_DICT: phf::Map<char, usize> = phf::phf_map! {
     'b' => 1, // byte
     'w' => 2, // word
     'd' => 4, // doubleword
     'q' => 8, // quadword
 };
 
-const DEBUG: bool = false;
-
-#[derive(Clone, Debug)]
-pub enum QueryValue {
+/// A value in a query, borrowing any array from the query or reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryValue<'a> {
     Number(i64),
-    Array(Vec<u8>),
+    Array(&'a [u8]),
 }
 
 #[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum ValueError {
     #[error("type mismatch: expected {expected}, got {actual}")]
     TypeMismatch { expected: String, actual: String },
-}
 
-impl QueryValue {
-    pub fn _from_array<const S: usize>(value: [u8; S]) -> Self {
-        Self::Array(value.to_vec())
-    }
-
-    pub fn _to_array<const S: usize>(&self) -> Result<[u8; S], ValueError> {
-        let mut array = [0u8; S];
-        match self {
-            QueryValue::Array(a) => {
-                for (i, byte) in a.iter().take(S).enumerate() {
-                    array[i] = *byte
-                }
-                Ok(array)
-            }
-            _ => Err(ValueError::TypeMismatch {
-                expected: String::from("Vec<u8>"),
-                actual: format!("{:?}", self),
-            }),
-        }
-    }
+    #[error("length mismatch: expected {expected} bytes, got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+}
 
-    pub fn to_vec(&self) -> Result<Vec<u8>, ValueError> {
+impl<'a> QueryValue<'a> {
+    pub fn to_slice(&self) -> Result<&'a [u8], ValueError> {
         match self {
-            QueryValue::Array(a) => Ok(a.to_vec()),
+            QueryValue::Array(a) => Ok(a),
             _ => Err(ValueError::TypeMismatch {
-                expected: String::from("Vec<u8>"),
+                expected: String::from("&[u8]"),
                 actual: format!("{:?}", self),
             }),
         }
@@ -71,20 +73,6 @@ impl QueryValue {
     }
 }
 
-impl TryInto<i64> for QueryValue {
-    type Error = ValueError;
-
-    fn try_into(self) -> Result<i64, Self::Error> {
-        match self {
-            QueryValue::Number(a) => Ok(a),
-            _ => Err(ValueError::TypeMismatch {
-                expected: String::from("i64"),
-                actual: format!("{:?}", self),
-            }),
-        }
-    }
-}
-
 #[derive(Error, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum QueryError {
     #[error("unrecognized format character: `{0}`")]
@@ -100,225 +88,919 @@ pub enum QueryError {
 
     #[error("input data is empty")]
     EmptyData,
+
+    #[error("reply ended at {index} while reading {field} (format {format_string})")]
+    Truncated {
+        index: usize,
+        field: &'static str,
+        format_string: String,
+    },
+
+    #[error("{length} unexpected bytes at the end of the reply (format {format_string})")]
+    TrailingData {
+        length: usize,
+        format_string: String,
+    },
+
+    #[error("expected {expected} values, got {actual}")]
+    CountMismatch { expected: usize, actual: usize },
+
+    #[error(transparent)]
+    Value(#[from] ValueError),
 }
 
-/// Formats a query using a standard input to send to the player
-pub fn format_query(format: String, args: Vec<QueryValue>) -> Result<Vec<u8>, QueryError> {
-    if DEBUG {
-        println!("SENT>>> F: {}", format);
-    }
+/// A single part of a [`QueryTemplate`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    /// A literal byte
+    Byte(u8),
+    /// A number of 1, 2, 4 or 8 bytes
+    Number { width: usize, little_endian: bool },
+    /// A BCD-encoded number of 1 or 2 bytes
+    Bcd { width: usize },
+    /// An array preceded by its length, one of `x`, `s` or `z`
+    Array(char),
+    /// A raw array, which is the rest of the reply when scanning
+    Raw,
+    /// A byte which is ignored when scanning
+    Skip,
+}
 
-    let mut result: Vec<u8> = Vec::new();
-    let mut half: Option<char> = None;
-    let mut arg_stack = args.into_iter();
-    let mut endianness_override: Option<char> = None;
+/// A format string which has been parsed ahead of time.
+///
+/// Parsing the format is the slowest part of building a query, so a template
+/// should be created once and then used for every query with the same
+/// format. Neither [`QueryTemplate::encode_into`] nor
+/// [`QueryTemplate::decode`] copy the values they are given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryTemplate {
+    format: String,
+    tokens: Vec<Token>,
+    values: usize,
+}
+
+impl QueryTemplate {
+    /// Parse a format string, see the [module documentation](self)
+    pub fn new(format: &str) -> Result<Self, QueryError> {
+        let mut tokens = Vec::new();
+        let mut half: Option<u32> = None;
+        let mut little_endian = None;
+        let mut escaped = false;
 
-    let mut escaped = false;
-    for character in format.chars() {
-        if escaped {
-            if endianness_override.is_none() && ['<', '>'].contains(&character) {
-                endianness_override = Some(character);
+        for character in format.chars() {
+            if escaped {
+                if little_endian.is_none() && ['<', '>'].contains(&character) {
+                    little_endian = Some(character == '<');
+                    continue;
+                }
+                escaped = false;
+
+                tokens.push(match character {
+                    'x' | 's' | 'z' => Token::Array(character),
+                    '*' | '#' => Token::Raw,
+                    'B' => Token::Bcd { width: 1 },
+                    'W' => Token::Bcd { width: 2 },
+                    '?' => Token::Skip,
+                    _ => match FORMAT_TYPE_LEN_DICT.get(&character) {
+                        Some(&width) => Token::Number {
+                            width,
+                            little_endian: little_endian.unwrap_or(false),
+                        },
+                        None => return Err(QueryError::UnrecognizedChar(character)),
+                    },
+                });
+                little_endian = None;
                 continue;
             }
-            escaped = false;
-
-            match character {
-                character if FORMAT_TYPE_LEN_DICT.contains_key(&character) => {
-                    let value = arg_stack.next().unwrap().to_i64().unwrap();
-                    match character {
-                        'b' => result.push(value as u8),
-                        'w' => {
-                            let mut value_bytes = (value as i16).to_be_bytes().to_vec();
-                            result.append(&mut value_bytes)
-                        }
-                        'd' => {
-                            let mut value_bytes = (value as i32).to_be_bytes().to_vec();
-                            result.append(&mut value_bytes)
-                        }
-                        'q' => {
-                            let mut value_bytes = value.to_be_bytes().to_vec();
-                            result.append(&mut value_bytes)
-                        }
-                        _ => (),
-                    };
-                    endianness_override = None;
-                }
-                character if character == 'x' || character == 's' || character == 'z' => {
-                    let mut array_value = arg_stack.next().unwrap().to_vec().unwrap();
+            if character == '%' && half.is_none() {
+                escaped = true;
+                continue;
+            }
+            if character == ' ' {
+                continue;
+            }
+
+            let Some(digit) = character.to_digit(16) else {
+                return Err(QueryError::UnrecognizedChar(character));
+            };
+            match half.take() {
+                None => half = Some(digit),
+                Some(high) => tokens.push(Token::Byte((high << 4 | digit) as u8)),
+            }
+        }
 
-                    let mut array_length = array_value.len();
+        if half.is_some() || escaped {
+            return Err(QueryError::UnrecognizedChar('%'));
+        }
+
+        let values = tokens
+            .iter()
+            .filter(|token| !matches!(token, Token::Byte(_) | Token::Skip))
+            .count();
+
+        Ok(Self {
+            format: format.to_string(),
+            tokens,
+            values,
+        })
+    }
+
+    /// The format string the template was parsed from
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    /// The number of values in a query or reply
+    pub fn value_count(&self) -> usize {
+        self.values
+    }
+
+    /// Format a query, appending it to `query`
+    pub fn encode_into(&self, args: &[QueryValue], query: &mut Vec<u8>) -> Result<(), QueryError> {
+        trace!("SENT>>> F: {}", self.format);
+
+        if args.len() != self.values {
+            return Err(QueryError::CountMismatch {
+                expected: self.values,
+                actual: args.len(),
+            });
+        }
+
+        let mut args = args.iter();
+        for token in &self.tokens {
+            match *token {
+                Token::Byte(byte) => query.push(byte),
+                Token::Number {
+                    width,
+                    little_endian,
+                } => {
+                    let value = args.next().unwrap().to_i64()?.to_be_bytes();
+                    let bytes = &value[value.len() - width..];
+                    match little_endian {
+                        false => query.extend_from_slice(bytes),
+                        true => query.extend(bytes.iter().rev()),
+                    }
+                }
+                Token::Bcd { width } => {
+                    let converted = utils::int_to_bcd(args.next().unwrap().to_i64()? as i32);
+                    if width == 2 {
+                        query.push(((converted >> 8) & 0xFF) as u8);
+                    }
+                    query.push((converted & 0xFF) as u8);
+                }
+                Token::Array(character) => {
+                    let array = args.next().unwrap().to_slice()?;
 
+                    let mut array_length = array.len();
                     if character == 's' {
                         array_length += 1;
                     }
 
                     if character != 'z' {
-                        result.push(((array_length >> 8) & 0xFF) as u8)
+                        query.push(((array_length >> 8) & 0xFF) as u8)
                     }
-                    result.push((array_length & 0xFF) as u8);
-                    result.append(&mut array_value);
+                    query.push((array_length & 0xFF) as u8);
+                    query.extend_from_slice(array);
                     if character == 's' {
-                        result.push(0);
+                        query.push(0);
                     }
                 }
-                '*' => {
-                    let mut array_value = arg_stack.next().unwrap().to_vec().unwrap();
-                    result.append(&mut array_value);
+                Token::Raw => query.extend_from_slice(args.next().unwrap().to_slice()?),
+                Token::Skip => return Err(QueryError::UnrecognizedChar('?')),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Format a query to send to the player
+    pub fn encode(&self, args: &[QueryValue]) -> Result<Vec<u8>, QueryError> {
+        let mut query = Vec::with_capacity(self.tokens.len());
+        self.encode_into(args, &mut query)?;
+
+        Ok(query)
+    }
+
+    /// Scan a reply from the player, returning values which borrow from it.
+    ///
+    /// Every field is checked against the length of the reply before it is
+    /// read, so a short or long reply is an error rather than a panic.
+    pub fn decode<'a>(&self, reply: &'a [u8]) -> Result<Vec<QueryValue<'a>>, QueryError> {
+        trace!("RECV<<< F: {}", self.format);
+
+        if reply.is_empty() {
+            return Err(QueryError::EmptyData);
+        }
+
+        let mut result = Vec::with_capacity(self.values);
+
+        // Remove an unknown byte at the beginning
+        // TODO: Find out what this is
+        let mut reader = Reader {
+            reply,
+            position: 1,
+            format: &self.format,
+        };
+
+        for token in &self.tokens {
+            match *token {
+                Token::Byte(expected) => {
+                    let actual = reader.take(1, "a fixed byte")?[0];
+                    if expected != actual {
+                        return Err(QueryError::InputMismatch {
+                            index: reader.position - 1,
+                            expected,
+                            actual,
+                            format_string: self.format.clone(),
+                        });
+                    }
                 }
-                character if character == 'B' || character == 'W' => {
-                    let value = arg_stack.next().unwrap().to_i64().unwrap();
-                    let converted = utils::int_to_bcd(value as i32);
-                    if character == 'W' {
-                        result.push(((converted >> 8) & 0xFF) as u8);
+                Token::Number {
+                    width,
+                    little_endian,
+                } => {
+                    let mut bytes = [0u8; 8];
+                    bytes[8 - width..].copy_from_slice(reader.take(width, "a number")?);
+                    if little_endian {
+                        bytes[8 - width..].reverse();
                     }
-                    result.push((converted & 0xFF) as u8);
+
+                    // Everything but bytes is signed
+                    let value = i64::from_be_bytes(bytes);
+                    result.push(QueryValue::Number(match width {
+                        2 => value as i16 as i64,
+                        4 => value as i32 as i64,
+                        _ => value,
+                    }));
+                }
+                Token::Bcd { width } => {
+                    let mut value = 0;
+                    for byte in reader.take(width, "a BCD number")? {
+                        value = value << 8 | *byte as i32;
+                    }
+
+                    result.push(QueryValue::Number(utils::bcd_to_int(value) as i64));
+                }
+                Token::Array(character) => {
+                    let length = match character {
+                        'z' => reader.take(1, "an array length")?[0] as usize,
+                        _ => {
+                            let length = reader.take(2, "an array length")?;
+                            u16::from_be_bytes([length[0], length[1]]) as usize
+                        }
+                    };
+
+                    result.push(QueryValue::Array(reader.take(length, "an array")?));
+                }
+                Token::Raw => {
+                    let length = reply.len() - reader.position;
+                    result.push(QueryValue::Array(reader.take(length, "an array")?));
+                }
+                Token::Skip => {
+                    reader.take(1, "a skipped byte")?;
                 }
-                _ => return Err(QueryError::UnrecognizedChar(character)),
             }
-            continue;
         }
-        if character == '%' {
-            escaped = true;
-            continue;
+
+        if reader.position != reply.len() {
+            return Err(QueryError::TrailingData {
+                length: reply.len() - reader.position,
+                format_string: self.format.clone(),
+            });
         }
-        if character == ' ' {
-            continue;
+
+        Ok(result)
+    }
+}
+
+/// The position in a reply which is being decoded
+struct Reader<'a, 'f> {
+    reply: &'a [u8],
+    position: usize,
+    format: &'f str,
+}
+
+impl<'a> Reader<'a, '_> {
+    /// Read the next `length` bytes, if the reply is long enough
+    fn take(&mut self, length: usize, field: &'static str) -> Result<&'a [u8], QueryError> {
+        let Some(bytes) = self.reply.get(self.position..self.position + length) else {
+            return Err(QueryError::Truncated {
+                index: self.position,
+                field,
+                format_string: self.format.to_string(),
+            });
+        };
+        self.position += length;
+
+        Ok(bytes)
+    }
+}
+
+/// A format string, checked against the types of its values at compile time.
+///
+/// This is implemented by the `query!` and `scan!` macros, and should not
+/// be implemented by hand.
+pub trait Template {
+    const FORMAT: &'static str;
+
+    /// The parsed format, which is only parsed the first time it is used
+    fn template() -> &'static QueryTemplate;
+}
+
+/// A value which can be passed to a `query!`
+pub trait QueryArg {
+    /// Whether this is an array (`%x %s %z %*`) rather than a number
+    const ARRAY: bool;
+
+    fn to_value(&self) -> QueryValue<'_>;
+}
+
+/// A value which can be returned by a `scan!`
+pub trait FromQueryValue: Sized {
+    /// Whether this is an array (`%x %s %z %* %#`) rather than a number
+    const ARRAY: bool;
+
+    fn from_value(value: QueryValue) -> Result<Self, ValueError>;
+}
+
+macro_rules! impl_number {
+    ($($number:ty)*) => {$(
+        impl QueryArg for $number {
+            const ARRAY: bool = false;
+
+            fn to_value(&self) -> QueryValue<'_> {
+                QueryValue::Number(*self as i64)
+            }
         }
-        if half.is_none() {
-            half = Some(character);
-        } else {
-            result.push(
-                u8::from_str_radix(&String::from_iter([half.unwrap(), character]), 16).unwrap(),
-            );
-            half = None;
+
+        impl FromQueryValue for $number {
+            const ARRAY: bool = false;
+
+            fn from_value(value: QueryValue) -> Result<Self, ValueError> {
+                Ok(value.to_i64()? as $number)
+            }
         }
+    )*};
+}
+
+impl_number!(u8 u16 u32 u64 usize i8 i16 i32 i64);
+
+impl QueryArg for Vec<u8> {
+    const ARRAY: bool = true;
+
+    fn to_value(&self) -> QueryValue<'_> {
+        QueryValue::Array(self)
+    }
+}
+
+impl QueryArg for &Vec<u8> {
+    const ARRAY: bool = true;
+
+    fn to_value(&self) -> QueryValue<'_> {
+        QueryValue::Array(self)
+    }
+}
+
+impl QueryArg for &[u8] {
+    const ARRAY: bool = true;
+
+    fn to_value(&self) -> QueryValue<'_> {
+        QueryValue::Array(self)
+    }
+}
+
+impl<const S: usize> QueryArg for [u8; S] {
+    const ARRAY: bool = true;
+
+    fn to_value(&self) -> QueryValue<'_> {
+        QueryValue::Array(self)
     }
+}
+
+impl<const S: usize> QueryArg for &[u8; S] {
+    const ARRAY: bool = true;
 
-    Ok(result)
+    fn to_value(&self) -> QueryValue<'_> {
+        QueryValue::Array(*self)
+    }
 }
 
-/// Scans a result using a standard input to recieve from the player
-pub fn scan_query(query_result: Vec<u8>, format: String) -> Result<Vec<QueryValue>, QueryError> {
-    if DEBUG {
-        println!("RECV<<< F: {}", format);
+impl FromQueryValue for Vec<u8> {
+    const ARRAY: bool = true;
+
+    fn from_value(value: QueryValue) -> Result<Self, ValueError> {
+        Ok(value.to_slice()?.to_vec())
     }
+}
+
+impl<const S: usize> FromQueryValue for [u8; S] {
+    const ARRAY: bool = true;
 
-    if query_result.is_empty() {
-        return Err(QueryError::EmptyData)
+    fn from_value(value: QueryValue) -> Result<Self, ValueError> {
+        let slice = value.to_slice()?;
+
+        slice.try_into().map_err(|_| ValueError::LengthMismatch {
+            expected: S,
+            actual: slice.len(),
+        })
     }
+}
 
-    let mut result: Vec<QueryValue> = Vec::new();
+/// Every value passed to a `query!`, as a tuple
+pub trait QueryArgs {
+    const KINDS: &'static [bool];
 
-    let initial_length = query_result.len();
-    let mut input_stack = query_result.into_iter();
-    let mut half: Option<char> = None;
-    let mut endianness_override: Option<char> = None;
-    let mut escaped = false;
+    fn encode_into(&self, template: &QueryTemplate, query: &mut Vec<u8>) -> Result<(), QueryError>;
+}
 
-    // Remove an unknown byte at the beginning
-    // TODO: Find out what this is
-    input_stack.next();
+/// Every value returned by a `scan!`, as a single value or a tuple
+pub trait QueryResults: Sized {
+    const KINDS: &'static [bool];
 
-    for character in format.chars() {
-        if escaped {
-            if endianness_override.is_none() && ['<', '>'].contains(&character) {
-                endianness_override = Some(character);
-                continue;
-            }
-            escaped = false;
+    fn from_values(values: &[QueryValue]) -> Result<Self, QueryError>;
+}
 
-            if character == '?' {
-                input_stack.next();
-                continue;
+impl<V: FromQueryValue> QueryResults for V {
+    const KINDS: &'static [bool] = &[V::ARRAY];
+
+    fn from_values(values: &[QueryValue]) -> Result<Self, QueryError> {
+        <(V,)>::from_values(values).map(|(value,)| value)
+    }
+}
+
+macro_rules! impl_tuples {
+    ($($name:ident)*) => {
+        impl<$($name: QueryArg),*> QueryArgs for ($($name,)*) {
+            const KINDS: &'static [bool] = &[$($name::ARRAY),*];
+
+            #[allow(non_snake_case)]
+            fn encode_into(
+                &self,
+                template: &QueryTemplate,
+                query: &mut Vec<u8>,
+            ) -> Result<(), QueryError> {
+                let ($($name,)*) = self;
+                template.encode_into(&[$($name.to_value()),*], query)
             }
+        }
 
-            match character {
-                character if FORMAT_TYPE_LEN_DICT.contains_key(&character) => {
-                    match character {
-                        'b' => {
-                            let new_value =
-                                u8::from_be_bytes(utils::get_bytes(&mut input_stack).unwrap());
-                            result.push(QueryValue::Number(new_value as i64));
-                        }
-                        'w' => {
-                            let new_value =
-                                i16::from_be_bytes(utils::get_bytes(&mut input_stack).unwrap());
-                            result.push(QueryValue::Number(new_value as i64));
-                        }
-                        'd' => {
-                            let new_value =
-                                i32::from_be_bytes(utils::get_bytes(&mut input_stack).unwrap());
-                            result.push(QueryValue::Number(new_value as i64));
-                        }
-                        'q' => {
-                            let new_value =
-                                i64::from_be_bytes(utils::get_bytes(&mut input_stack).unwrap());
-                            result.push(QueryValue::Number(new_value));
-                        }
-                        _ => unreachable!(),
-                    };
-                    endianness_override = None;
-                }
-                character if character == 'x' || character == 's' || character == 'z' => {
-                    let length = match character {
-                        'z' => input_stack.next().unwrap() as u16,
-                        _ => u16::from_be_bytes(utils::get_bytes(&mut input_stack).unwrap()),
-                    };
-                    let mut result_buffer: Vec<u8> = Vec::new();
-                    for _ in 0..length {
-                        result_buffer.push(input_stack.next().unwrap());
-                    }
-                    result.push(QueryValue::Array(result_buffer))
-                }
-                character if character == '*' || character == '#' => {
-                    let mut result_buffer: Vec<u8> = Vec::new();
-                    let temp_stack = input_stack.clone();
-                    for entry in temp_stack.take(initial_length) {
-                        result_buffer.push(entry);
-                        input_stack.next();
-                    }
-                    result.push(QueryValue::Array(result_buffer));
-                }
-                'B' => {
-                    let v = input_stack.next().unwrap();
-                    result.push(QueryValue::Number(utils::bcd_to_int(v as i32) as i64));
-                }
-                'W' => {
-                    let v = (input_stack.next().unwrap() as i32) << 8
-                        | input_stack.next().unwrap() as i32;
-                    result.push(QueryValue::Number(utils::bcd_to_int(v) as i64));
+        impl<$($name: FromQueryValue),*> QueryResults for ($($name,)*) {
+            const KINDS: &'static [bool] = &[$($name::ARRAY),*];
+
+            #[allow(unused_mut, unused_variables)]
+            fn from_values(values: &[QueryValue]) -> Result<Self, QueryError> {
+                if values.len() != <Self as QueryResults>::KINDS.len() {
+                    return Err(QueryError::CountMismatch {
+                        expected: <Self as QueryResults>::KINDS.len(),
+                        actual: values.len(),
+                    });
                 }
-                _ => return Err(QueryError::UnrecognizedChar(character)),
+
+                let mut values = values.iter();
+                Ok(($($name::from_value(*values.next().unwrap())?,)*))
             }
-            continue;
         }
-        if character == '%' {
-            assert_eq!(half, None);
-            escaped = true;
+    };
+}
+
+impl_tuples!();
+impl_tuples!(A);
+impl_tuples!(A B);
+impl_tuples!(A B C);
+impl_tuples!(A B C D);
+impl_tuples!(A B C D E);
+impl_tuples!(A B C D E F);
+impl_tuples!(A B C D E F G);
+impl_tuples!(A B C D E F G H);
+impl_tuples!(A B C D E F G H I);
+impl_tuples!(A B C D E F G H I J);
+impl_tuples!(A B C D E F G H I J K);
+impl_tuples!(A B C D E F G H I J K L);
+impl_tuples!(A B C D E F G H I J K L M);
+
+/// Check that a format string is valid, and that its values are `kinds`.
+///
+/// Scanning also allows `%#` for the rest of the reply and `%?` to skip a
+/// byte.
+pub const fn template_matches(format: &str, kinds: &[bool], scan: bool) -> bool {
+    let format = format.as_bytes();
+    let mut index = 0;
+    let mut value = 0;
+    let mut half = false;
+
+    while index < format.len() {
+        let character = format[index];
+        index += 1;
+
+        if character == b' ' {
             continue;
         }
-        if character == ' ' {
+        if character.is_ascii_hexdigit() {
+            half = !half;
             continue;
         }
-        if half.is_none() {
-            half = Some(character);
-        } else {
-            let input_value = input_stack.next().unwrap();
-            let format_value =
-                u8::from_str_radix(&String::from_iter([half.unwrap(), character]), 16).unwrap();
-            if format_value != input_value {
-                let i = initial_length - input_stack.len() - 1;
-                return Err(QueryError::InputMismatch {
-                    index: i,
-                    expected: format_value,
-                    actual: input_value,
-                    format_string: format,
-                });
+        if character != b'%' || half {
+            return false;
+        }
+
+        while index < format.len() && (format[index] == b'<' || format[index] == b'>') {
+            index += 1;
+        }
+        if index >= format.len() {
+            return false;
+        }
+
+        let array = match format[index] {
+            b'b' | b'w' | b'd' | b'q' | b'B' | b'W' => false,
+            b'x' | b's' | b'z' | b'*' => true,
+            b'#' if scan => true,
+            b'?' if scan => {
+                index += 1;
+                continue;
             }
-            half = None;
+            _ => return false,
+        };
+        index += 1;
+
+        if value >= kinds.len() || kinds[value] != array {
+            return false;
         }
+        value += 1;
     }
 
-    assert_eq!(input_stack.len(), 0);
-    Ok(result)
+    !half && value == kinds.len()
+}
+
+/// Count the values in a format string, see [`template_matches`]
+pub const fn template_len(format: &str) -> usize {
+    let format = format.as_bytes();
+    let mut index = 0;
+    let mut count = 0;
+
+    while index < format.len() {
+        if format[index] == b'%' {
+            while index + 1 < format.len() && matches!(format[index + 1], b'<' | b'>') {
+                index += 1;
+            }
+            if index + 1 < format.len() && format[index + 1] != b'?' {
+                count += 1;
+            }
+        }
+        index += 1;
+    }
+
+    count
+}
+
+#[doc(hidden)]
+pub fn format_template<F: Template, A: QueryArgs>(args: A) -> Result<Vec<u8>, QueryError> {
+    const {
+        assert!(
+            template_matches(F::FORMAT, A::KINDS, false),
+            "query values do not match the template"
+        )
+    };
+
+    let template = F::template();
+    let mut query = Vec::with_capacity(template.tokens.len());
+    args.encode_into(template, &mut query)?;
+
+    Ok(query)
+}
+
+#[doc(hidden)]
+pub fn scan_template<F: Template, R: QueryResults>(reply: &[u8]) -> Result<R, QueryError> {
+    const {
+        assert!(
+            template_matches(F::FORMAT, R::KINDS, true),
+            "scan results do not match the template"
+        )
+    };
+
+    R::from_values(&F::template().decode(reply)?)
+}
+
+#[doc(hidden)]
+macro_rules! count_values {
+    () => { 0usize };
+    ($head:expr $(, $tail:expr)*) => {
+        1usize + $crate::netmd::query_utils::count_values!($($tail),*)
+    };
+}
+
+/// Define a [`Template`] named `Format`, which is parsed on first use
+#[doc(hidden)]
+macro_rules! template {
+    ($format:literal) => {
+        struct Format;
+        impl $crate::netmd::query_utils::Template for Format {
+            const FORMAT: &'static str = $format;
+
+            fn template() -> &'static $crate::netmd::query_utils::QueryTemplate {
+                static TEMPLATE: once_cell::sync::Lazy<$crate::netmd::query_utils::QueryTemplate> =
+                    once_cell::sync::Lazy::new(|| {
+                        $crate::netmd::query_utils::QueryTemplate::new($format).unwrap()
+                    });
+
+                &TEMPLATE
+            }
+        }
+    };
+}
+
+/// Format a command from a template and its values.
+///
+/// The number of values is checked against the template when the macro is
+/// expanded, and their types (numbers for `%b %w %d %q %B %W`, byte arrays
+/// for `%x %s %z %*`) once the template is used. The template is parsed the
+/// first time the command is sent.
+///
+/// ```ignore
+/// let query = query!("1800 080046 f0030103 28 ff 000100 1001 ffff 00 %b %b %d %d",
+///     wireformat, discformat, frames, total_bytes)?;
+/// ```
+macro_rules! query {
+    ($format:literal $(, $value:expr)* $(,)?) => {{
+        const _: () = assert!(
+            $crate::netmd::query_utils::template_len($format)
+                == $crate::netmd::query_utils::count_values!($($value),*),
+            concat!("wrong number of values for the template `", $format, "`")
+        );
+
+        $crate::netmd::query_utils::template!($format);
+        $crate::netmd::query_utils::format_template::<Format, _>(($($value,)*))
+    }};
+}
+
+/// Scan a reply with a template, returning its values.
+///
+/// A template with a single value returns that value, and anything else
+/// returns a tuple. The types are checked against the template once it is
+/// used.
+///
+/// ```ignore
+/// let (frames, bytes): (u32, u32) = scan!(reply, "1800 080046 f0030103 28 00 000100 1001 %?%? 00 %d %d")?;
+/// ```
+macro_rules! scan {
+    ($reply:expr, $format:literal $(,)?) => {{
+        $crate::netmd::query_utils::template!($format);
+        $crate::netmd::query_utils::scan_template::<Format, _>(&$reply)
+    }};
+}
+
+pub(crate) use count_values;
+pub(crate) use query;
+pub(crate) use scan;
+pub(crate) use template;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_len_counts_values() {
+        assert_eq!(template_len("1809 8001 0330 8801"), 0);
+        assert_eq!(template_len("1850 ff010000 0000 %w"), 1);
+        assert_eq!(template_len("1800 %<d %>w %b %*"), 4);
+        assert_eq!(template_len("1807 022018%? %?%? 3000 %w %#"), 2);
+    }
+
+    #[test]
+    fn template_matches_kinds() {
+        assert!(template_matches("1850 ff010000 0000 %w", &[false], false));
+        assert!(template_matches("18 %b %<d %B %W", &[false; 4], false));
+        assert!(template_matches("18 %x %s %z %*", &[true; 4], false));
+        assert!(template_matches("18 %b %x", &[false, true], true));
+
+        // Numbers and arrays are not interchangeable
+        assert!(!template_matches("18 %w", &[true], false));
+        assert!(!template_matches("18 %x", &[false], false));
+
+        // Every value has a kind, and every kind a value
+        assert!(!template_matches("18 %w %w", &[false], false));
+        assert!(!template_matches("18 %w", &[false, false], false));
+    }
+
+    #[test]
+    fn template_matches_scan_only() {
+        assert!(template_matches("18%? %?%? %#", &[true], true));
+        assert!(!template_matches("18%? 00", &[], false));
+        assert!(!template_matches("18 %#", &[true], false));
+    }
+
+    #[test]
+    fn template_matches_rejects_invalid() {
+        // Odd number of hex digits
+        assert!(!template_matches("180", &[], false));
+        assert!(!template_matches("1%b 8", &[false], false));
+
+        // Unknown or missing format characters
+        assert!(!template_matches("18 %y", &[false], false));
+        assert!(!template_matches("18 %", &[], false));
+        assert!(!template_matches("18 %<", &[], false));
+        assert!(!template_matches("18 zz", &[], false));
+    }
+
+    fn encode(format: &str, args: &[QueryValue]) -> Vec<u8> {
+        QueryTemplate::new(format).unwrap().encode(args).unwrap()
+    }
+
+    fn decode<'a>(format: &str, reply: &'a [u8]) -> Result<Vec<QueryValue<'a>>, QueryError> {
+        QueryTemplate::new(format).unwrap().decode(reply)
+    }
+
+    #[test]
+    fn encode_numbers() {
+        let query = encode(
+            "1800 %b %w %d %q",
+            &[
+                QueryValue::Number(0x12),
+                QueryValue::Number(0x1234),
+                QueryValue::Number(0x12345678),
+                QueryValue::Number(1),
+            ],
+        );
+
+        assert_eq!(
+            query,
+            [0x18, 0x00, 0x12, 0x12, 0x34, 0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn encode_negative_numbers() {
+        // Negative numbers are truncated to their two's complement
+        let query = encode(
+            "18 %b %w %d",
+            &[
+                QueryValue::Number(-1),
+                QueryValue::Number(-2),
+                QueryValue::Number(-3),
+            ],
+        );
+
+        assert_eq!(query, [0x18, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xff, 0xfd]);
+    }
+
+    #[test]
+    fn encode_little_endian() {
+        let query = encode(
+            "18 %<w %>w %<d",
+            &[
+                QueryValue::Number(0x1234),
+                QueryValue::Number(0x1234),
+                QueryValue::Number(0x12345678),
+            ],
+        );
+
+        assert_eq!(
+            query,
+            [0x18, 0x34, 0x12, 0x12, 0x34, 0x78, 0x56, 0x34, 0x12]
+        );
+    }
+
+    #[test]
+    fn encode_bcd() {
+        let query = encode(
+            "18 %B %W",
+            &[QueryValue::Number(42), QueryValue::Number(1234)],
+        );
+
+        assert_eq!(query, [0x18, 0x42, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn encode_arrays() {
+        let array = QueryValue::Array(&[0xaa, 0xbb]);
+        let query = encode("18 %x %s %z %*", &[array; 4]);
+
+        assert_eq!(
+            query,
+            [
+                0x18, // header
+                0x00, 0x02, 0xaa, 0xbb, // %x
+                0x00, 0x03, 0xaa, 0xbb, 0x00, // %s
+                0x02, 0xaa, 0xbb, // %z
+                0xaa, 0xbb, // %*
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_checks_values() {
+        let template = QueryTemplate::new("18 %w").unwrap();
+
+        assert_eq!(
+            template.encode(&[]),
+            Err(QueryError::CountMismatch {
+                expected: 1,
+                actual: 0
+            })
+        );
+        assert!(matches!(
+            template.encode(&[QueryValue::Array(&[])]),
+            Err(QueryError::Value(ValueError::TypeMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn decode_signedness() {
+        // Only single bytes are unsigned
+        let reply = [
+            0x09, 0x18, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xff, 0xfd, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xfc,
+        ];
+        let values = decode("18 %b %w %d %q", &reply).unwrap();
+
+        assert_eq!(
+            values,
+            [
+                QueryValue::Number(0xff),
+                QueryValue::Number(-2),
+                QueryValue::Number(-3),
+                QueryValue::Number(-4),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_bcd() {
+        let values = decode("18 %B %W", &[0x09, 0x18, 0x42, 0x12, 0x34]).unwrap();
+
+        assert_eq!(values, [QueryValue::Number(42), QueryValue::Number(1234)]);
+    }
+
+    #[test]
+    fn decode_arrays() {
+        let reply = [0x09, 0x18, 0x00, 0x02, 0xaa, 0xbb, 0x01, 0xcc, 0xdd, 0xee];
+        let values = decode("18 %x %z %*", &reply).unwrap();
+
+        assert_eq!(
+            values,
+            [
+                QueryValue::Array(&[0xaa, 0xbb]),
+                QueryValue::Array(&[0xcc]),
+                QueryValue::Array(&[0xdd, 0xee]),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_rest_and_skipped_bytes() {
+        let reply = [0x09, 0x18, 0x01, 0x02, 0x03, 0x04];
+
+        assert_eq!(
+            decode("18 %? %#", &reply).unwrap(),
+            [QueryValue::Array(&[0x02, 0x03, 0x04])]
+        );
+        assert_eq!(
+            decode("18 %?%?%?%? %*", &reply).unwrap(),
+            [QueryValue::Array(&[])]
+        );
+    }
+
+    #[test]
+    fn decode_mismatch() {
+        assert_eq!(decode("18", &[]), Err(QueryError::EmptyData));
+        assert_eq!(
+            decode("18 00", &[0x09, 0x18, 0x01]),
+            Err(QueryError::InputMismatch {
+                index: 2,
+                expected: 0x00,
+                actual: 0x01,
+                format_string: String::from("18 00"),
+            })
+        );
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert_eq!(
+            decode("18 %w", &[0x09, 0x18, 0x01]),
+            Err(QueryError::Truncated {
+                index: 2,
+                field: "a number",
+                format_string: String::from("18 %w"),
+            })
+        );
+        assert_eq!(
+            decode("18 %x", &[0x09, 0x18, 0x00, 0x03, 0xaa]),
+            Err(QueryError::Truncated {
+                index: 4,
+                field: "an array",
+                format_string: String::from("18 %x"),
+            })
+        );
+    }
+
+    #[test]
+    fn decode_trailing_data() {
+        assert_eq!(
+            decode("18 %b", &[0x09, 0x18, 0x01, 0x02, 0x03]),
+            Err(QueryError::TrailingData {
+                length: 2,
+                format_string: String::from("18 %b"),
+            })
+        );
+    }
 }
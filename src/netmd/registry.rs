@@ -0,0 +1,267 @@
+//! Devices added at runtime, in addition to [`super::base::DEVICE_IDS`].
+//!
+//! Registered devices can be opened like any built-in device, and are
+//! included in [`super::base::device_filters`]. A registered device replaces
+//! the built-in entry with the same IDs, so this can also be used to change
+//! the quirks of a known device.
+//!
+//! Devices can also be loaded from a config file, with one device per line:
+//!
+//! ```text
+//! # vendor product capabilities name
+//! 054c 0999 deck,himd Sony MDS-EXAMPLE
+//! 04dd 9999 utoc1_disc_title,mode_scaled_capacity,download_delay=300 Sharp IM-EXAMPLE
+//! 04da 9999 capacity_flag=08 Panasonic SJ-EXAMPLE
+//! ```
+//!
+//! The capabilities are a comma separated list of `portable`, `deck`, `himd`,
+//! `upload`, `eject`, `utoc1_disc_title`, `mode_scaled_capacity`,
+//! `capacity_flag=<hex byte>` and `download_delay=<milliseconds>`, or `-`
+//! for a plain portable.
+use std::sync::RwLock;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use super::base::{Capabilities, DeviceId};
+
+static REGISTERED_DEVICES: RwLock<Vec<DeviceId>> = RwLock::new(Vec::new());
+
+/// An error when loading devices from a config
+#[derive(Error, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigError {
+    #[error("line {line}: {message}")]
+    Invalid { line: usize, message: String },
+
+    #[error("could not read config: {0}")]
+    Io(String),
+}
+
+/// Add a device, replacing any registered device with the same IDs
+pub fn register_device(device: DeviceId) {
+    let mut devices = REGISTERED_DEVICES
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+
+    devices
+        .retain(|d| d.vendor_id() != device.vendor_id() || d.product_id() != device.product_id());
+    devices.push(device);
+}
+
+/// Remove a registered device, returning whether it was registered
+pub fn unregister_device(vendor_id: u16, product_id: u16) -> bool {
+    let mut devices = REGISTERED_DEVICES
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+
+    let count = devices.len();
+    devices.retain(|d| d.vendor_id() != vendor_id || d.product_id() != product_id);
+    devices.len() != count
+}
+
+/// Get every device which was registered at runtime
+pub fn registered_devices() -> Vec<DeviceId> {
+    REGISTERED_DEVICES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+pub(super) fn find(vendor_id: u16, product_id: u16) -> Option<DeviceId> {
+    REGISTERED_DEVICES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|d| d.vendor_id() == vendor_id && d.product_id() == product_id)
+        .cloned()
+}
+
+/// Register every device in a config, returning the number of devices.
+///
+/// Nothing is registered if any line is invalid.
+pub fn load_config(config: &str) -> Result<usize, ConfigError> {
+    let mut devices = Vec::new();
+
+    for (index, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: String| ConfigError::Invalid {
+            line: index + 1,
+            message,
+        };
+
+        let mut fields = line.split_whitespace();
+        let name = fields.clone().skip(3).collect::<Vec<_>>().join(" ");
+        let (Some(vendor_id), Some(product_id), Some(capabilities)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid(
+                "expected a vendor ID, product ID, capabilities and name".into(),
+            ));
+        };
+        if name.is_empty() {
+            return Err(invalid("missing device name".into()));
+        }
+
+        let vendor_id = u16::from_str_radix(vendor_id, 16)
+            .map_err(|_| invalid(format!("invalid vendor ID `{}`", vendor_id)))?;
+        let product_id = u16::from_str_radix(product_id, 16)
+            .map_err(|_| invalid(format!("invalid product ID `{}`", product_id)))?;
+        let capabilities = parse_capabilities(capabilities).map_err(invalid)?;
+
+        devices.push(DeviceId::new(vendor_id, product_id, name, capabilities));
+    }
+
+    let count = devices.len();
+    for device in devices {
+        register_device(device);
+    }
+
+    Ok(count)
+}
+
+/// Register every device in a config file, see [`load_config`]
+#[cfg(all(feature = "fs", not(target_family = "wasm")))]
+pub fn load_config_file<P: AsRef<std::path::Path>>(path: P) -> Result<usize, ConfigError> {
+    let config =
+        std::fs::read_to_string(path).map_err(|error| ConfigError::Io(error.to_string()))?;
+
+    load_config(&config)
+}
+
+fn parse_capabilities(list: &str) -> Result<Capabilities, String> {
+    let mut capabilities = Capabilities::PORTABLE;
+
+    for flag in list.split(',').filter(|flag| *flag != "-") {
+        match flag.split_once('=') {
+            None => match flag {
+                "portable" => (),
+                "deck" => {
+                    capabilities.deck = true;
+                    capabilities.eject = true;
+                }
+                "himd" => capabilities.himd = true,
+                "upload" => capabilities.upload = true,
+                "eject" => capabilities.eject = true,
+                "utoc1_disc_title" => capabilities.utoc1_disc_title = true,
+                "mode_scaled_capacity" => capabilities.mode_scaled_capacity = true,
+                _ => return Err(format!("unknown capability `{}`", flag)),
+            },
+            Some(("capacity_flag", value)) => {
+                capabilities.capacity_flag = u8::from_str_radix(value, 16)
+                    .map_err(|_| format!("invalid capacity flag `{}`", value))?;
+            }
+            Some(("download_delay", value)) => {
+                let millis = value
+                    .parse()
+                    .map_err(|_| format!("invalid download delay `{}`", value))?;
+                capabilities.download_delay = Duration::from_millis(millis);
+            }
+            Some(_) => return Err(format!("unknown capability `{}`", flag)),
+        }
+    }
+
+    Ok(capabilities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::base::known_devices;
+
+    #[test]
+    fn capabilities() {
+        let capabilities =
+            parse_capabilities("deck,himd,capacity_flag=08,download_delay=300").unwrap();
+
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                capacity_flag: 0x08,
+                download_delay: Duration::from_millis(300),
+                ..Capabilities::HIMD_DECK
+            }
+        );
+        assert_eq!(parse_capabilities("-").unwrap(), Capabilities::PORTABLE);
+    }
+
+    #[test]
+    fn invalid_capabilities() {
+        assert!(parse_capabilities("portable,flying").is_err());
+        assert!(parse_capabilities("capacity_flag=zz").is_err());
+        assert!(parse_capabilities("download_delay=-1").is_err());
+        assert!(parse_capabilities("speed=2").is_err());
+    }
+
+    #[test]
+    fn load() {
+        // The registry is shared by every test, so these IDs aren't used
+        // anywhere else
+        let config = "
+            # vendor product capabilities name
+            fff0 0001 deck,himd Test Deck
+
+            fff0 0002 utoc1_disc_title,mode_scaled_capacity Test Portable
+        ";
+
+        assert_eq!(load_config(config), Ok(2));
+
+        let deck = DeviceId::lookup(0xfff0, 0x0001);
+        assert_eq!(deck.name(), Some("Test Deck"));
+        assert_eq!(deck.capabilities(), &Capabilities::HIMD_DECK);
+
+        let portable = DeviceId::lookup(0xfff0, 0x0002);
+        assert_eq!(portable.name(), Some("Test Portable"));
+        assert!(portable.capabilities().utoc1_disc_title);
+        assert!(portable.capabilities().mode_scaled_capacity);
+
+        let known = known_devices().len();
+        assert!(unregister_device(0xfff0, 0x0001));
+        assert!(unregister_device(0xfff0, 0x0002));
+        assert!(!unregister_device(0xfff0, 0x0002));
+        assert_eq!(known_devices().len(), known - 2);
+        assert_eq!(DeviceId::lookup(0xfff0, 0x0001).name(), None);
+    }
+
+    #[test]
+    fn replace_built_in() {
+        let kenwood = DeviceId::lookup(0x0b28, 0x1004);
+        register_device(DeviceId::new(0x0b28, 0x1004, "Renamed", Capabilities::DECK));
+
+        assert_eq!(DeviceId::lookup(0x0b28, 0x1004).name(), Some("Renamed"));
+
+        unregister_device(0x0b28, 0x1004);
+        assert_eq!(DeviceId::lookup(0x0b28, 0x1004), kenwood);
+    }
+
+    #[test]
+    fn malformed_config() {
+        let config = "
+            fff1 0001 deck Valid Deck
+            fff1 0002 deck
+            fff1 zzzz deck Invalid Product
+        ";
+
+        assert_eq!(
+            load_config(config),
+            Err(ConfigError::Invalid {
+                line: 3,
+                message: "missing device name".into(),
+            })
+        );
+        assert!(matches!(
+            load_config("fff1 zzzz deck Invalid Product"),
+            Err(ConfigError::Invalid { line: 1, .. })
+        ));
+        assert!(matches!(
+            load_config("fff1 0001 deck,flying Deck"),
+            Err(ConfigError::Invalid { line: 1, .. })
+        ));
+
+        // Nothing is registered when a line is invalid
+        assert_eq!(DeviceId::lookup(0xfff1, 0x0001).name(), None);
+    }
+}
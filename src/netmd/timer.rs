@@ -0,0 +1,311 @@
+//! Timers used while waiting on a device.
+//!
+//! Waiting for a device never blocks the thread it runs on. By default every
+//! sleep is woken by one timer thread shared by the whole crate, which works
+//! on any executor. On WASM, browser timers are used.
+//!
+//! Other runtimes can provide their own timer with [`set_sleep_provider`],
+//! for example to always use the tokio timer:
+//!
+//! ```no_run
+//! use minidisc::netmd::timer::set_sleep_provider;
+//!
+//! set_sleep_provider(|duration| Box::pin(tokio::time::sleep(duration)));
+//! ```
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::time::Duration;
+
+#[cfg(not(target_family = "wasm"))]
+use std::cmp::Ordering;
+#[cfg(not(target_family = "wasm"))]
+use std::collections::BinaryHeap;
+#[cfg(not(target_family = "wasm"))]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(not(target_family = "wasm"))]
+use std::task::{Context, Poll, Waker};
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
+
+#[cfg(not(target_family = "wasm"))]
+use once_cell::sync::Lazy;
+
+/// A future which completes once a sleep has finished
+#[cfg(not(target_family = "wasm"))]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A future which completes once a sleep has finished
+#[cfg(target_family = "wasm")]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+type SleepProvider = Box<dyn Fn(Duration) -> SleepFuture + Send + Sync>;
+
+static SLEEP_PROVIDER: RwLock<Option<SleepProvider>> = RwLock::new(None);
+
+/// Replace the timer used by the crate for every sleep.
+///
+/// The provider is called with the duration to sleep for, and must return a
+/// future which completes after that duration without blocking the thread.
+pub fn set_sleep_provider<F>(provider: F)
+where
+    F: Fn(Duration) -> SleepFuture + Send + Sync + 'static,
+{
+    *SLEEP_PROVIDER.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(provider));
+}
+
+/// Go back to the default timer for the platform.
+pub fn reset_sleep_provider() {
+    *SLEEP_PROVIDER.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Sleep for a [`Duration`] using the current sleep provider
+pub async fn sleep(duration: Duration) {
+    let future = match SLEEP_PROVIDER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        Some(provider) => provider(duration),
+        None => default_sleep(duration),
+    };
+
+    future.await
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn default_sleep(duration: Duration) -> SleepFuture {
+    Box::pin(ThreadSleep::new(duration))
+}
+
+#[cfg(target_family = "wasm")]
+fn default_sleep(duration: Duration) -> SleepFuture {
+    Box::pin(gloo::timers::future::TimeoutFuture::new(
+        duration.as_millis() as u32,
+    ))
+}
+
+/// The shared state between a [`ThreadSleep`] and the timer thread
+#[cfg(not(target_family = "wasm"))]
+#[derive(Default)]
+struct ThreadSleepState {
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+/// A sleep waiting in the [`TimerThread`]
+#[cfg(not(target_family = "wasm"))]
+struct Timer {
+    deadline: Instant,
+    state: Arc<Mutex<ThreadSleepState>>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Eq for Timer {}
+
+#[cfg(not(target_family = "wasm"))]
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Ord for Timer {
+    // Reversed, so the heap returns the earliest deadline first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// One thread which wakes every [`ThreadSleep`] once its deadline passes
+#[cfg(not(target_family = "wasm"))]
+struct TimerThread {
+    timers: Mutex<BinaryHeap<Timer>>,
+    changed: Condvar,
+}
+
+#[cfg(not(target_family = "wasm"))]
+static TIMER_THREAD: Lazy<Arc<TimerThread>> = Lazy::new(|| {
+    let timer_thread = Arc::new(TimerThread {
+        timers: Mutex::new(BinaryHeap::new()),
+        changed: Condvar::new(),
+    });
+
+    let thread = Arc::clone(&timer_thread);
+    std::thread::Builder::new()
+        .name("minidisc-timer".into())
+        .spawn(move || thread.run())
+        .expect("Failed to spawn the timer thread");
+
+    timer_thread
+});
+
+#[cfg(not(target_family = "wasm"))]
+impl TimerThread {
+    fn add(&self, timer: Timer) {
+        let mut timers = self.timers.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Only an earlier deadline changes how long the thread should wait
+        let earliest = !timers
+            .peek()
+            .is_some_and(|next| next.deadline <= timer.deadline);
+        timers.push(timer);
+        if earliest {
+            self.changed.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        let mut timers = self.timers.lock().unwrap_or_else(|e| e.into_inner());
+
+        loop {
+            let now = Instant::now();
+            while timers.peek().is_some_and(|next| next.deadline <= now) {
+                let Some(timer) = timers.pop() else { break };
+
+                let mut state = timer.state.lock().unwrap_or_else(|e| e.into_inner());
+                state.finished = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+
+            timers = match timers.peek() {
+                Some(next) => {
+                    let timeout = next.deadline.saturating_duration_since(now);
+                    self.changed
+                        .wait_timeout(timers, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.changed.wait(timers).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+/// A sleep which works on any executor, by waking the task from the timer
+/// thread shared by every sleep.
+#[cfg(not(target_family = "wasm"))]
+struct ThreadSleep {
+    duration: Duration,
+    state: Option<Arc<Mutex<ThreadSleepState>>>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ThreadSleep {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            state: None,
+        }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Future for ThreadSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.duration.is_zero() {
+            return Poll::Ready(());
+        }
+
+        let deadline = Instant::now() + self.duration;
+        let state = self.state.get_or_insert_with(|| {
+            let state = Arc::new(Mutex::new(ThreadSleepState::default()));
+            TIMER_THREAD.add(Timer {
+                deadline,
+                state: Arc::clone(&state),
+            });
+
+            state
+        });
+
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.finished {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_sleep_waits() {
+        let start = Instant::now();
+        tokio_test::block_on(ThreadSleep::new(Duration::from_millis(20)));
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn shorter_sleep_finishes_first() {
+        let mut long = tokio_test::task::spawn(ThreadSleep::new(Duration::from_secs(2)));
+        assert!(long.poll().is_pending());
+
+        let start = Instant::now();
+        tokio_test::block_on(ThreadSleep::new(Duration::from_millis(10)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(long.poll().is_pending());
+    }
+
+    /// Records the thread every wake comes from
+    #[derive(Default)]
+    struct ThreadWaker {
+        threads: Mutex<Vec<std::thread::ThreadId>>,
+    }
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.threads
+                .lock()
+                .unwrap()
+                .push(std::thread::current().id());
+        }
+    }
+
+    #[test]
+    fn sleeps_share_one_thread() {
+        let recorder = Arc::new(ThreadWaker::default());
+        let waker = Waker::from(Arc::clone(&recorder));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sleeps: Vec<_> = (0..50)
+            .map(|i| Box::pin(ThreadSleep::new(Duration::from_millis(i % 5 + 1))))
+            .collect();
+        for sleep in &mut sleeps {
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        }
+
+        let start = Instant::now();
+        while recorder.threads.lock().unwrap().len() < sleeps.len() {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        for sleep in &mut sleeps {
+            assert!(sleep.as_mut().poll(&mut cx).is_ready());
+        }
+
+        let threads = recorder.threads.lock().unwrap();
+        assert!(threads.iter().all(|thread| *thread == threads[0]));
+        assert_ne!(threads[0], std::thread::current().id());
+    }
+}
@@ -0,0 +1,185 @@
+//! Transports which carry the raw NetMD protocol to and from a device.
+//!
+//! A [`super::NetMD`] does not talk to USB directly, instead it goes through
+//! a [`Transport`]. `UsbTransport` is the default, and talks to a real
+//! device using [`cross_usb`] when the `native-usb` feature is enabled.
+
+// USB stuff
+#[cfg(feature = "native-usb")]
+use cross_usb::prelude::*;
+#[cfg(feature = "native-usb")]
+use cross_usb::usb::{ControlIn, ControlOut, ControlType, Error, Recipient};
+#[cfg(feature = "native-usb")]
+use cross_usb::{DeviceInfo, Interface};
+
+use super::base::NetMDError;
+
+#[cfg(feature = "native-usb")]
+const BULK_WRITE_ENDPOINT: u8 = 0x02;
+#[cfg(feature = "native-usb")]
+const BULK_READ_ENDPOINT: u8 = 0x81;
+
+/// The low-level operations needed to communicate with a NetMD device.
+///
+/// Implement this to run the higher level interfaces over something other
+/// than a physical player.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// The USB vendor ID of the device on the other end
+    fn vendor_id(&self) -> u16;
+
+    /// The USB product ID of the device on the other end
+    fn product_id(&self) -> u16;
+
+    /// Poll the device, returning the raw 4 byte poll result
+    async fn poll(&mut self) -> Result<[u8; 4], NetMDError>;
+
+    /// Send a control message to the device
+    async fn send_command(&mut self, command: &[u8]) -> Result<(), NetMDError>;
+
+    /// Send a factory control message to the device
+    async fn send_factory_command(&mut self, command: &[u8]) -> Result<(), NetMDError>;
+
+    /// Read a reply of `length` bytes from the device
+    async fn read_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError>;
+
+    /// Read a factory reply of `length` bytes from the device
+    async fn read_factory_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError>;
+
+    /// Read up to `length` bytes from the bulk endpoint
+    async fn read_bulk(&mut self, length: usize) -> Result<Vec<u8>, NetMDError>;
+
+    /// Write bytes to the bulk endpoint, returning the number of bytes written
+    async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError>;
+}
+
+/// A [`Transport`] to a physical device connected over USB.
+///
+/// When a transfer fails because the device was unplugged, the error is
+/// [`NetMDError::Disconnected`] rather than a USB error, so the context can
+/// be dropped. This is found by looking for a device with the same IDs, so
+/// it is not noticed while another device of the same model is connected.
+#[cfg(feature = "native-usb")]
+pub struct UsbTransport {
+    usb_interface: Interface,
+    vendor_id: u16,
+    product_id: u16,
+}
+
+#[cfg(feature = "native-usb")]
+impl UsbTransport {
+    /// Open a USB device for use as a transport
+    pub async fn new(usb_descriptor: DeviceInfo) -> Result<Self, NetMDError> {
+        let vendor_id = usb_descriptor.vendor_id().await;
+        let product_id = usb_descriptor.product_id().await;
+
+        let usb_device = usb_descriptor.open().await?;
+        let usb_interface = usb_device.open_interface(0).await?;
+
+        Ok(Self {
+            usb_interface,
+            vendor_id,
+            product_id,
+        })
+    }
+
+    /// Turn the error of a failed transfer into [`NetMDError::Disconnected`]
+    /// if the device is gone
+    async fn check<R>(&self, result: Result<R, Error>) -> Result<R, NetMDError> {
+        let error = match result {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+
+        let filter = cross_usb::device_filter! {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+        };
+        match cross_usb::get_device_list(vec![filter]).await {
+            Ok(devices) => match devices.into_iter().next() {
+                None => Err(NetMDError::Disconnected),
+                Some(_) => Err(error.into()),
+            },
+            Err(_) => Err(error.into()),
+        }
+    }
+
+    async fn control_out(&mut self, request: u8, command: &[u8]) -> Result<(), NetMDError> {
+        let result = self
+            .usb_interface
+            .control_out(ControlOut {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Interface,
+                request,
+                value: 0,
+                index: 0,
+                data: command,
+            })
+            .await;
+        self.check(result).await?;
+
+        Ok(())
+    }
+
+    async fn control_in(&mut self, request: u8, length: u16) -> Result<Vec<u8>, NetMDError> {
+        let result = self
+            .usb_interface
+            .control_in(ControlIn {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Interface,
+                request,
+                value: 0,
+                index: 0,
+                length,
+            })
+            .await;
+
+        self.check(result).await
+    }
+}
+
+#[cfg(feature = "native-usb")]
+impl Transport for UsbTransport {
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    async fn poll(&mut self) -> Result<[u8; 4], NetMDError> {
+        let poll_result = self.control_in(0x01, 4).await?;
+
+        match poll_result.try_into() {
+            Ok(val) => Ok(val),
+            Err(_) => Err(NetMDError::InvalidResult),
+        }
+    }
+
+    async fn send_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.control_out(0x80, command).await
+    }
+
+    async fn send_factory_command(&mut self, command: &[u8]) -> Result<(), NetMDError> {
+        self.control_out(0xff, command).await
+    }
+
+    async fn read_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        self.control_in(0x81, length).await
+    }
+
+    async fn read_factory_reply(&mut self, length: u16) -> Result<Vec<u8>, NetMDError> {
+        self.control_in(0xff, length).await
+    }
+
+    async fn read_bulk(&mut self, length: usize) -> Result<Vec<u8>, NetMDError> {
+        let result = self.usb_interface.bulk_in(BULK_READ_ENDPOINT, length).await;
+        self.check(result).await
+    }
+
+    async fn write_bulk(&mut self, data: &[u8]) -> Result<usize, NetMDError> {
+        let result = self.usb_interface.bulk_out(BULK_WRITE_ENDPOINT, data).await;
+        self.check(result).await
+    }
+}
@@ -3,7 +3,7 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use diacritics;
 use encoding_rs::SHIFT_JIS;
 use regex::Regex;
-use std::{error::Error, io::Write, time::Duration, vec::IntoIter};
+use std::{io::Write, time::Duration};
 use unicode_normalization::UnicodeNormalization;
 
 extern crate kana;
@@ -14,13 +14,9 @@ use super::{
     mappings::{HW_TO_FW_RANGE_MAP, MULTI_BYTE_CHARS},
 };
 
-/// Sleep for a specified [Duration] on any platform
+/// Sleep for a specified [Duration] on any platform, without blocking
 pub async fn cross_sleep(duration: Duration) {
-    #[cfg(not(target_family = "wasm"))]
-    std::thread::sleep(duration);
-
-    #[cfg(target_family = "wasm")]
-    gloo::timers::future::TimeoutFuture::new(duration.as_millis() as u32).await;
+    super::timer::sleep(duration).await;
 }
 
 pub fn bcd_to_int(mut bcd: i32) -> i32 {
@@ -58,13 +54,6 @@ pub fn half_width_to_full_width_range(range: &str) -> String {
         .collect()
 }
 
-pub fn get_bytes<const S: usize>(iterator: &mut IntoIter<u8>) -> Result<[u8; S], Box<dyn Error>> {
-    let byte_vec: Vec<u8> = iterator.take(S).collect();
-    let bytes: [u8; S] = byte_vec.try_into().unwrap();
-
-    Ok(bytes)
-}
-
 pub fn length_after_encoding_to_sjis(string: &str) -> usize {
     let new_string = SHIFT_JIS.encode(string);
 
@@ -286,6 +275,7 @@ pub fn create_wav_header(format: DiscFormat, bytes: u32) -> Vec<u8> {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RawTime {
     pub hours: u64,
     pub minutes: u64,
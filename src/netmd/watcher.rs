@@ -0,0 +1,272 @@
+//! Watching a device for changes in what it is doing.
+//!
+//! Notify commands are not supported the same way by every device, so a
+//! [`StatusWatcher`] polls [`NetMDContext::device_status`] instead, and turns
+//! the differences between polls into [`PlayerEvent`]s.
+//!
+//! ```no_run
+//! # use minidisc::netmd::NetMDContext;
+//! # async fn example(context: &mut NetMDContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::time::Duration;
+//! use minidisc::netmd::watcher::PlayerEvent;
+//!
+//! let mut watcher = context.watch_status(Duration::from_millis(500));
+//! loop {
+//!     match watcher.next().await? {
+//!         PlayerEvent::TrackChanged(track) => println!("now playing track {}", track),
+//!         PlayerEvent::DiscEjected => println!("disc ejected"),
+//!         event => println!("{:?}", event),
+//!     }
+//! }
+//! # }
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use super::commands::{ContextError, DeviceStatus, NetMDContext, OperatingStatus, Time};
+use super::transport::Transport;
+use super::utils::cross_sleep;
+
+/// A change in the state of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerEvent {
+    DiscInserted,
+    DiscEjected,
+    /// The current track changed, starting at 0
+    TrackChanged(u8),
+    StateChanged(OperatingStatus),
+    /// The playback position moved
+    Position {
+        track: u8,
+        time: Time,
+    },
+    /// The titles or tracks on the disc changed
+    ContentsChanged,
+}
+
+/// Watches a device for changes by polling its status, see
+/// [`NetMDContext::watch_status`].
+///
+/// The first poll reports the state of the device as it is, so a disc which
+/// is already inserted is reported as [`PlayerEvent::DiscInserted`]. The
+/// context can still be used through the watcher between events.
+pub struct StatusWatcher<'a, T: Transport> {
+    context: &'a mut NetMDContext<T>,
+    interval: Duration,
+    watch_contents: bool,
+    previous: Option<DeviceStatus>,
+    contents: Option<u64>,
+    events: VecDeque<PlayerEvent>,
+}
+
+impl<'a, T: Transport> StatusWatcher<'a, T> {
+    /// Create a watcher which polls the device every `interval`
+    pub fn new(context: &'a mut NetMDContext<T>, interval: Duration) -> Self {
+        Self {
+            context,
+            interval,
+            watch_contents: true,
+            previous: None,
+            contents: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Sets whether the disc title, track count and track titles are read on
+    /// every poll to report [`PlayerEvent::ContentsChanged`]
+    pub fn set_watch_contents(&mut self, watch_contents: bool) {
+        self.watch_contents = watch_contents;
+        self.contents = None;
+    }
+
+    /// The status of the device at the last poll
+    pub fn status(&self) -> Option<&DeviceStatus> {
+        self.previous.as_ref()
+    }
+
+    /// Wait for the next change in the state of the device
+    pub async fn next(&mut self) -> Result<PlayerEvent, ContextError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            if self.previous.is_some() {
+                cross_sleep(self.interval).await;
+            }
+            self.poll().await?;
+        }
+    }
+
+    async fn poll(&mut self) -> Result<(), ContextError> {
+        let status = self.context.device_status().await?;
+        let previous = self.previous.replace(status);
+
+        if previous.map(|p| p.disc_present) != Some(status.disc_present) {
+            match status.disc_present {
+                true => self.events.push_back(PlayerEvent::DiscInserted),
+                false if previous.is_some() => self.events.push_back(PlayerEvent::DiscEjected),
+                false => (),
+            }
+        }
+
+        if previous.map(|p| p.state) != Some(status.state) {
+            self.events
+                .push_back(PlayerEvent::StateChanged(status.state));
+        }
+
+        if status.disc_present {
+            if previous.map(|p| p.track) != Some(status.track) {
+                self.events
+                    .push_back(PlayerEvent::TrackChanged(status.track));
+            }
+
+            if previous.map(|p| p.time) != Some(status.time) {
+                self.events.push_back(PlayerEvent::Position {
+                    track: status.track,
+                    time: status.time,
+                });
+            }
+        }
+
+        if !status.disc_present || !self.watch_contents {
+            self.contents = None;
+            return Ok(());
+        }
+
+        // Only a disc which was already read can have changed
+        let contents = self.contents_hash().await?;
+        if self
+            .contents
+            .replace(contents)
+            .is_some_and(|old| old != contents)
+        {
+            self.events.push_back(PlayerEvent::ContentsChanged);
+        }
+
+        Ok(())
+    }
+
+    async fn contents_hash(&mut self) -> Result<u64, ContextError> {
+        let interface = self.context.interface_mut();
+
+        // The raw title holds the groups as well as the title of the disc
+        let mut hasher = DefaultHasher::new();
+        let track_count = interface.track_count().await?;
+        track_count.hash(&mut hasher);
+        interface.raw_disc_title(false).await?.hash(&mut hasher);
+        interface
+            .track_titles((0..track_count).collect(), false)
+            .await?
+            .hash(&mut hasher);
+
+        Ok(hasher.finish())
+    }
+}
+
+impl<T: Transport> std::ops::Deref for StatusWatcher<'_, T> {
+    type Target = NetMDContext<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.context
+    }
+}
+
+impl<T: Transport> std::ops::DerefMut for StatusWatcher<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netmd::emulator::{VirtualDisc, VirtualPlayer, VirtualTrack};
+    use crate::netmd::interface::Encoding;
+    use crate::netmd::{NetMD, NetMDInterface};
+
+    fn context() -> NetMDContext<VirtualPlayer> {
+        let disc = VirtualDisc {
+            title: String::from("Mix"),
+            tracks: vec![VirtualTrack::new(
+                "First",
+                Encoding::SP,
+                Duration::from_secs(90),
+            )],
+            ..Default::default()
+        };
+        let device = NetMD::new(VirtualPlayer::new(disc)).unwrap();
+        NetMDContext::from(NetMDInterface::from(device))
+    }
+
+    fn player<'a>(watcher: &'a mut StatusWatcher<'_, VirtualPlayer>) -> &'a mut VirtualPlayer {
+        watcher.interface_mut().device.transport_mut()
+    }
+
+    fn next(watcher: &mut StatusWatcher<'_, VirtualPlayer>) -> PlayerEvent {
+        tokio_test::block_on(watcher.next()).unwrap()
+    }
+
+    /// Create a watcher and read the events of the first poll
+    fn watcher(context: &mut NetMDContext<VirtualPlayer>) -> StatusWatcher<'_, VirtualPlayer> {
+        let mut watcher = context.watch_status(Duration::ZERO);
+
+        assert_eq!(next(&mut watcher), PlayerEvent::DiscInserted);
+        assert_eq!(
+            next(&mut watcher),
+            PlayerEvent::StateChanged(OperatingStatus::Ready)
+        );
+        assert_eq!(next(&mut watcher), PlayerEvent::TrackChanged(0));
+        assert!(matches!(
+            next(&mut watcher),
+            PlayerEvent::Position { track: 0, .. }
+        ));
+
+        watcher
+    }
+
+    #[test]
+    fn eject_and_insert() {
+        let mut context = context();
+        let mut watcher = watcher(&mut context);
+
+        let disc = player(&mut watcher).eject_disc().unwrap();
+        assert_eq!(next(&mut watcher), PlayerEvent::DiscEjected);
+        assert_eq!(
+            next(&mut watcher),
+            PlayerEvent::StateChanged(OperatingStatus::NoDisc)
+        );
+        assert!(!watcher.status().unwrap().disc_present);
+
+        player(&mut watcher).insert_disc(disc);
+        assert_eq!(next(&mut watcher), PlayerEvent::DiscInserted);
+        assert_eq!(
+            next(&mut watcher),
+            PlayerEvent::StateChanged(OperatingStatus::Ready)
+        );
+        assert!(watcher.status().unwrap().disc_present);
+    }
+
+    #[test]
+    fn track_renamed() {
+        let mut context = context();
+        let mut watcher = watcher(&mut context);
+
+        player(&mut watcher).disc_mut().unwrap().tracks[0].title = String::from("Renamed");
+
+        assert_eq!(next(&mut watcher), PlayerEvent::ContentsChanged);
+    }
+
+    #[test]
+    fn disc_renamed() {
+        let mut context = context();
+        let mut watcher = watcher(&mut context);
+
+        tokio_test::block_on(watcher.rename_disc("Renamed", None)).unwrap();
+
+        assert_eq!(next(&mut watcher), PlayerEvent::ContentsChanged);
+    }
+}